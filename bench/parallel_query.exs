@@ -0,0 +1,73 @@
+# Measures query throughput as the number of concurrent callers grows.
+#
+#     mix run bench/parallel_query.exs
+#
+# Options (environment variables):
+#   DOCS        records in the collection (default 10_000)
+#   DIM         embedding dimension (default 384)
+#   QUERIES     queries issued per concurrency level (default 2_000)
+#
+# Reads run on cloned Frontend handles, so throughput should scale with the
+# number of cores until the runtime's worker threads are saturated.
+
+docs = String.to_integer(System.get_env("DOCS", "10000"))
+dim = String.to_integer(System.get_env("DIM", "384"))
+queries = String.to_integer(System.get_env("QUERIES", "2000"))
+
+random_embeddings = fn count ->
+  Nx.Random.key(System.unique_integer([:positive]))
+  |> Nx.Random.uniform(shape: {count, dim}, type: :f32)
+  |> elem(0)
+end
+
+name = "bench_parallel_query_#{System.unique_integer([:positive])}"
+{:ok, collection} = ChromEx.Collection.create(name)
+
+try do
+  IO.puts("Loading #{docs} records of dimension #{dim}...")
+
+  0..(docs - 1)
+  |> Enum.chunk_every(1_000)
+  |> Enum.each(fn chunk ->
+    :ok =
+      ChromEx.Collection.add(collection,
+        ids: Enum.map(chunk, &"doc#{&1}"),
+        embeddings: random_embeddings.(length(chunk))
+      )
+  end)
+
+  query_embeddings = random_embeddings.(queries) |> Nx.to_batched(1) |> Enum.to_list()
+
+  levels =
+    Stream.iterate(1, &(&1 * 2))
+    |> Enum.take_while(&(&1 <= System.schedulers_online() * 2))
+
+  IO.puts("\nconcurrency  queries/s  speedup")
+
+  Enum.reduce(levels, nil, fn concurrency, baseline ->
+    {micros, _} =
+      :timer.tc(fn ->
+        query_embeddings
+        |> Task.async_stream(
+          fn embedding -> {:ok, _} = ChromEx.Collection.query(collection, embedding, n_results: 10) end,
+          max_concurrency: concurrency,
+          ordered: false,
+          timeout: :infinity
+        )
+        |> Stream.run()
+      end)
+
+    throughput = queries / (micros / 1_000_000)
+    baseline = baseline || throughput
+
+    IO.puts(
+      String.pad_leading("#{concurrency}", 11) <>
+        String.pad_leading(:erlang.float_to_binary(throughput, decimals: 1), 11) <>
+        String.pad_leading(:erlang.float_to_binary(throughput / baseline, decimals: 2) <> "x", 9)
+    )
+
+    baseline
+  end)
+after
+  ChromEx.Collection.delete(name)
+end
@@ -32,22 +32,72 @@ defmodule ChromEx do
   @doc """
   Returns maximum batch size for operations
   """
-  @spec max_batch_size() :: non_neg_integer()
-  def max_batch_size do
-    Client.max_batch_size()
+  @spec max_batch_size(GenServer.server()) :: non_neg_integer()
+  def max_batch_size(client \\ Client) do
+    Client.max_batch_size(client)
   end
 
   @doc """
   Resets all data in the database
+
+  Pass `client: name` to reset a client other than the default one.
+  """
+  @spec reset(keyword()) :: :ok | {:error, term()}
+  def reset(opts \\ []) do
+    resource = opts |> Keyword.get(:client, Client) |> Client.get_resource()
+
+    Native.call(&Native.reset(resource, &1), opts)
+  end
+
+  @doc """
+  Backs up a store into the directory `dest`, which must not exist yet or be
+  empty
+
+  `chroma.db` is copied with SQLite's online backup API and the HNSW segment
+  directories file by file, and a `manifest.json` lists every file with its
+  size and SHA-256. Calls already running on the store finish before the
+  copy starts, and calls made while it runs wait for it. Writes Chroma still
+  holds in its log are applied to the segments first, so the backup is
+  consistent even when taken under write load. The embedding cache is not
+  included. Returns `{:ok, manifest}`.
+
+  ## Options
+
+    * `:client` - the `ChromEx.Client` whose store to back up
+    * `:timeout` - defaults to `:infinity`
   """
-  @spec reset() :: :ok | {:error, term()}
-  def reset do
-    resource = Client.get_resource()
+  @spec backup(Path.t(), keyword()) :: {:ok, map()} | {:error, term()}
+  def backup(dest, opts \\ []) do
+    resource = opts |> Keyword.get(:client, Client) |> Client.get_resource()
+    opts = Keyword.put_new(opts, :timeout, :infinity)
+
+    Native.call(&Native.backup(resource, &1, Path.expand(dest)), opts)
+  end
+
+  @doc """
+  Replaces a client's store with the backup in `src`, written by `backup/2`
+
+  The backup is copied next to the client's `:persist_path` and checked
+  against its manifest before anything is changed; a backup with missing or
+  altered files is rejected with `{:error, {:validation, message}}` and the
+  store left as it was. Otherwise the client closes the store, waiting for
+  the calls running on it, swaps the directories and reopens the store,
+  keeping the embedding cache. Calls made meanwhile wait on the client and
+  fail if it takes longer than their timeout, or with `{:error, :closed}` if
+  they reach the old store, so restore during maintenance. Returns
+  `{:ok, manifest}`. If the directories cannot be swapped, the original store
+  is put back and reopened, and `{:error, reason}` returned.
 
-    case Native.reset(resource) do
-      "ok" -> :ok
-      {:error, reason} -> {:error, reason}
-    end
+  ## Options
+
+    * `:client` - the `ChromEx.Client` whose store to replace
+    * `:timeout` - milliseconds for the store to close, defaulting to the
+      client's `:shutdown_timeout`
+  """
+  @spec restore(Path.t(), keyword()) :: {:ok, map()} | {:error, term()}
+  def restore(src, opts \\ []) do
+    {client, opts} = Keyword.pop(opts, :client, Client)
+    Client.restore(client, Path.expand(src), opts)
   end
 
   @doc """
@@ -8,11 +8,35 @@ defmodule ChromEx.Application do
     # Get pool size from config, default to CPU cores
     pool_size = Application.get_env(:chromex, :embedding_pool_size, System.schedulers_online())
 
-    children = [
-      {ChromEx.Client, []},
-      {ChromEx.EmbeddingsPool, [pool_size: pool_size]}
+    client_opts =
+      :chromex
+      |> Application.get_all_env()
+      |> Keyword.take([
+        :allow_reset,
+        :persist_path,
+        :hnsw_cache_size,
+        :hnsw_cache_size_mb,
+        :embedding_cache
+      ])
+
+    # The native backend embeds inside the client, so it needs no pool
+    embeddings_children =
+      case ChromEx.Embeddings.backend() do
+        :ortex -> [{ChromEx.EmbeddingsPool, [pool_size: pool_size]}]
+        :native -> []
+      end
+
+    batcher_opts = [
+      max_batch_size: Application.get_env(:chromex, :embedding_batch_size, 32),
+      max_wait: Application.get_env(:chromex, :embedding_batch_wait, 5),
+      max_concurrency: pool_size
     ]
 
+    # The client loads embedding models under ChromEx.TaskSupervisor
+    children =
+      [{Task.Supervisor, name: ChromEx.TaskSupervisor}, {ChromEx.Client, client_opts}] ++
+        embeddings_children ++ [{ChromEx.EmbeddingsBatcher, batcher_opts}]
+
     opts = [strategy: :one_for_one, name: ChromEx.Supervisor]
     Supervisor.start_link(children, opts)
   end
@@ -0,0 +1,141 @@
+defmodule ChromEx.Chunking do
+  @moduledoc false
+  # Truncation detection and token-window chunking of documents for
+  # ChromEx.Collection, and collapsing chunked query results back to their
+  # parent documents.
+
+  @per_record ~w(documents metadatas distances embeddings uris)
+
+  @doc """
+  The ids, token counts and token limit of the documents longer than
+  `capacity` tokens.
+  """
+  def truncated(ids, offsets, capacity) do
+    ids
+    |> Enum.zip(offsets)
+    |> Enum.filter(fn {_id, offsets} -> length(offsets) > capacity end)
+    |> Enum.map(fn {id, offsets} -> %{id: id, tokens: length(offsets), max_tokens: capacity} end)
+  end
+
+  @doc """
+  Replaces each document longer than `capacity` tokens with overlapping
+  windows of at most `capacity` tokens, cut at the byte `offsets` of their
+  tokens. Chunk records get the id `"<id>#chunk-<index>"`, the text of their
+  window, and the parent's metadata plus `"parent_id"` and `"chunk_index"`;
+  their uris and sparse embeddings are the parent's. Other records are left
+  as they are.
+  """
+  def split(opts, offsets, capacity, overlap) do
+    if overlap >= capacity do
+      raise ArgumentError,
+            "chunk_overlap must be smaller than the model's #{capacity} token limit, got #{overlap}"
+    end
+
+    documents = Keyword.fetch!(opts, :documents)
+
+    # {parent index, id, document, extra metadata} per stored record
+    plan =
+      [Keyword.fetch!(opts, :ids), documents, offsets]
+      |> Enum.zip()
+      |> Enum.with_index()
+      |> Enum.flat_map(fn {{id, document, offsets}, index} ->
+        if length(offsets) > capacity do
+          offsets
+          |> windows(capacity, capacity - overlap)
+          |> Enum.with_index()
+          |> Enum.map(fn {window, chunk_index} ->
+            {start, _} = List.first(window)
+            {_, stop} = List.last(window)
+
+            {index, "#{id}#chunk-#{chunk_index}", binary_part(document, start, stop - start),
+             %{"parent_id" => id, "chunk_index" => chunk_index}}
+          end)
+        else
+          [{index, id, document, nil}]
+        end
+      end)
+
+    metadatas =
+      case Keyword.get(opts, :metadatas) do
+        nil ->
+          if Enum.any?(plan, &elem(&1, 3)), do: List.duplicate(nil, length(documents))
+
+        metadatas ->
+          metadatas
+      end
+
+    opts
+    |> Keyword.put(:ids, Enum.map(plan, &elem(&1, 1)))
+    |> Keyword.put(:documents, Enum.map(plan, &elem(&1, 2)))
+    |> expand(:metadatas, metadatas, plan, fn metadata, extra ->
+      if extra, do: Map.merge(Map.new(metadata || %{}), extra), else: metadata
+    end)
+    |> expand(:uris, Keyword.get(opts, :uris), plan, &keep/2)
+    |> expand(:sparse_embeddings, Keyword.get(opts, :sparse_embeddings), plan, &keep/2)
+  end
+
+  defp keep(value, _extra), do: value
+
+  defp windows(offsets, size, step) do
+    count = length(offsets)
+
+    0
+    |> Stream.iterate(&(&1 + step))
+    |> Enum.take_while(&(&1 == 0 or &1 + size - step < count))
+    |> Enum.map(&Enum.slice(offsets, &1, size))
+  end
+
+  defp expand(opts, _key, nil, _plan, _fun), do: opts
+
+  defp expand(opts, key, values, plan, fun) do
+    values = List.to_tuple(values)
+
+    Keyword.put(
+      opts,
+      key,
+      Enum.map(plan, fn {index, _id, _document, extra} -> fun.(elem(values, index), extra) end)
+    )
+  end
+
+  @doc """
+  Keeps only the best-ranked record of each parent document in every list of
+  query `results`, reporting it under the parent's id, and at most
+  `n_results` of them. Records that are not chunks are their own parent.
+  """
+  def collapse(results, n_results) do
+    # Per query, the {id, metadata} and position of each record kept
+    kept =
+      Enum.zip_with(results["ids"], results["metadatas"], fn ids, metadatas ->
+        ids
+        |> Enum.zip(metadatas)
+        |> Enum.with_index()
+        |> Enum.uniq_by(fn {{id, metadata}, _index} -> parent_id(id, metadata) end)
+        |> Enum.take(n_results)
+      end)
+
+    results
+    |> Map.new(fn
+      {key, lists} when key in @per_record and is_list(lists) ->
+        {key, Enum.zip_with(lists, kept, &pick/2)}
+
+      other ->
+        other
+    end)
+    |> Map.put(
+      "ids",
+      Enum.map(kept, fn kept ->
+        Enum.map(kept, fn {{id, metadata}, _index} -> parent_id(id, metadata) end)
+      end)
+    )
+  end
+
+  defp pick(list, kept) when is_list(list) do
+    values = List.to_tuple(list)
+    Enum.map(kept, fn {_record, index} -> elem(values, index) end)
+  end
+
+  defp pick(other, _kept), do: other
+
+  defp parent_id(_id, %{"parent_id" => parent_id}), do: parent_id
+  defp parent_id(id, _metadata), do: id
+end
@@ -1,25 +1,90 @@
 defmodule ChromEx.Client do
   @moduledoc """
   ChromEx client that manages the Chroma bindings resource lifecycle
+
+  ## Options
+
+    * `:name` - name to register the client under (default `ChromEx.Client`).
+      Start several clients with different names and `:persist_path`s to use
+      separate stores, then pass `client: name` to `ChromEx.Collection` and
+      `ChromEx.Database` functions.
+    * `:persist_path` - directory for the SQLite database and HNSW segments
+    * `:allow_reset` - whether `ChromEx.reset/1` is permitted (default `false`)
+    * `:hnsw_cache_size_mb` - memory budget for the HNSW indexes kept
+      loaded (default `1000`). The least recently used indexes beyond it are
+      evicted and reloaded from disk on next use. Index sizes are estimated
+      from their dimension and record count when the store is opened, see
+      `hnsw_cache_size/1`.
+    * `:hnsw_cache_size` - the most HNSW indexes kept loaded, however much
+      of the budget they leave (default `1000`)
+    * `:embedding_cache` - `true` or options to keep a persistent cache of
+      document embeddings in `:persist_path`, see `ChromEx.EmbeddingCache`
+      (default `false`)
+    * `:shutdown_timeout` - milliseconds the store gets to close when the
+      client stops (default `10_000`), see "Shutdown" below
+
+  With the `:native` embeddings backend (see `ChromEx.Embeddings`), each
+  client loads an embedding model the first time it needs it.
+
+  ## Shutdown
+
+  The client traps exits, so when its supervisor stops it the store is
+  closed before the client exits: new calls are refused, the calls in flight
+  finish, the local log is flushed into the segments and `chroma.db` is
+  closed. Calls made on the store's resource afterwards fail with
+  `{:error, :closed}`. The client's child spec gives it `:shutdown_timeout`
+  plus a margin to do so.
   """
 
   use GenServer
 
-  alias ChromEx.Native
+  alias ChromEx.{EmbeddingCache, Native}
+  alias ChromEx.Embeddings.Model
 
-  defstruct [:resource, :persist_path, :allow_reset, :hnsw_cache_size]
+  defstruct [
+    :resource,
+    :opts,
+    :persist_path,
+    :allow_reset,
+    :hnsw_cache_size_mb,
+    :hnsw_cache_size,
+    :embedding_cache,
+    :max_batch_size,
+    embedders: MapSet.new(),
+    loading: %{}
+  ]
 
   @type t :: %__MODULE__{
           resource: reference(),
+          opts: keyword(),
           persist_path: String.t() | nil,
           allow_reset: boolean(),
-          hnsw_cache_size: non_neg_integer()
+          hnsw_cache_size_mb: pos_integer(),
+          hnsw_cache_size: pos_integer(),
+          embedding_cache: EmbeddingCache.t() | nil,
+          max_batch_size: pos_integer(),
+          embedders: MapSet.t(String.t()),
+          loading: %{String.t() => %{ref: reference(), resource: reference(), waiters: list()}}
         }
 
   @default_cache_size_mb 1000
+  @default_cache_size 1000
+  # Where the native side keeps the store when no :persist_path is given
+  @default_persist_path "./chroma_data"
+  @default_shutdown_timeout 10_000
+
+  def child_spec(opts) do
+    %{
+      id: Keyword.get(opts, :name, __MODULE__),
+      start: {__MODULE__, :start_link, [opts]},
+      # Room for terminate/2 to close the store
+      shutdown: shutdown_timeout(opts) + 5_000
+    }
+  end
 
   def start_link(opts \\ []) do
-    GenServer.start_link(__MODULE__, opts, name: __MODULE__)
+    {name, opts} = Keyword.pop(opts, :name, __MODULE__)
+    GenServer.start_link(__MODULE__, opts, name: name)
   end
 
   @doc """
@@ -27,47 +92,321 @@ defmodule ChromEx.Client do
   """
   @spec init(keyword()) :: {:ok, t()} | {:error, term()}
   def init(opts) do
+    Process.flag(:trap_exit, true)
+
+    case open(opts) do
+      {:ok, state} -> {:ok, state}
+      {:error, reason} -> {:stop, reason}
+    end
+  end
+
+  defp open(opts) do
     allow_reset = Keyword.get(opts, :allow_reset, false)
     persist_path = Keyword.get(opts, :persist_path)
-    hnsw_cache_size = Keyword.get(opts, :hnsw_cache_size_mb, @default_cache_size_mb)
-
-    case Native.init(allow_reset, persist_path, hnsw_cache_size) do
-      resource when is_reference(resource) ->
-        {:ok,
-         %__MODULE__{
-           resource: resource,
-           persist_path: persist_path,
-           allow_reset: allow_reset,
-           hnsw_cache_size: hnsw_cache_size
-         }}
 
-      {:error, reason} ->
-        {:stop, reason}
+    hnsw_cache_size_mb = Keyword.get(opts, :hnsw_cache_size_mb, @default_cache_size_mb)
+    hnsw_cache_size = Keyword.get(opts, :hnsw_cache_size, @default_cache_size)
+
+    with resource when is_reference(resource) <-
+           Native.init(allow_reset, persist_path, hnsw_cache_size_mb, hnsw_cache_size),
+         {:ok, max_batch_size} <- max_batch_size(resource),
+         {:ok, embedding_cache} <-
+           open_embedding_cache(Keyword.get(opts, :embedding_cache, false), persist_path) do
+      {:ok,
+       %__MODULE__{
+         resource: resource,
+         opts: opts,
+         persist_path: persist_path,
+         allow_reset: allow_reset,
+         hnsw_cache_size_mb: hnsw_cache_size_mb,
+         hnsw_cache_size: hnsw_cache_size,
+         embedding_cache: embedding_cache,
+         max_batch_size: max_batch_size
+       }}
     end
   end
 
+  # Writes are split by this size, so an unusable one must stop the client
+  defp max_batch_size(resource) do
+    case Native.get_max_batch_size(resource) do
+      size when is_integer(size) and size > 0 -> {:ok, size}
+      {:error, reason} -> {:error, reason}
+      other -> {:error, {:internal, "invalid max batch size #{inspect(other)}"}}
+    end
+  end
+
+  defp shutdown_timeout(opts),
+    do: Keyword.get(opts, :shutdown_timeout, @default_shutdown_timeout)
+
+  defp open_embedding_cache(false, _persist_path), do: {:ok, nil}
+
+  defp open_embedding_cache(_opts, nil),
+    do: {:error, {:validation, "embedding_cache requires a persist_path"}}
+
+  defp open_embedding_cache(true, persist_path), do: open_embedding_cache([], persist_path)
+  defp open_embedding_cache(opts, persist_path), do: EmbeddingCache.open(persist_path, opts)
+
   @doc """
   Gets the client resource for direct NIF calls
   """
-  @spec get_resource() :: reference() | nil
-  def get_resource do
-    GenServer.call(__MODULE__, :get_resource)
+  @spec get_resource(GenServer.server()) :: reference() | nil
+  def get_resource(client \\ __MODULE__) do
+    GenServer.call(client, :get_resource)
+  end
+
+  @doc """
+  Gets the effective HNSW index cache size, in indexes: as many of the
+  store's largest indexes as fit in `:hnsw_cache_size_mb` together, capped
+  by `:hnsw_cache_size`. It is worked out when the store is opened, and again
+  by `restart_native/1` and `restore/3`.
+  """
+  @spec hnsw_cache_size(GenServer.server()) :: pos_integer()
+  def hnsw_cache_size(client \\ __MODULE__) do
+    GenServer.call(client, :hnsw_cache_size)
+  end
+
+  @doc """
+  Gets the client's `ChromEx.EmbeddingCache`, or `nil` if it has none
+  """
+  @spec embedding_cache(GenServer.server()) :: EmbeddingCache.t() | nil
+  def embedding_cache(client \\ __MODULE__) do
+    GenServer.call(client, :embedding_cache)
+  end
+
+  @doc """
+  Gets the most records Chroma accepts in one write. `ChromEx.Collection`
+  splits larger writes into batches of this size.
+  """
+  @spec max_batch_size(GenServer.server()) :: non_neg_integer()
+  def max_batch_size(client \\ __MODULE__) do
+    GenServer.call(client, :max_batch_size)
+  end
+
+  @doc """
+  Loads the named `ChromEx.Embeddings.Model` into the client's native
+  embedder, downloading it first if needed. Does nothing once the model is
+  loaded.
+  """
+  @spec ensure_embedder(GenServer.server(), String.t()) :: :ok | {:error, term()}
+  def ensure_embedder(client \\ __MODULE__, model \\ ChromEx.Embeddings.Model.default()) do
+    GenServer.call(client, {:ensure_embedder, model}, :infinity)
+  end
+
+  @doc """
+  Replaces the client's store with a backup, see `ChromEx.restore/2`
+  """
+  @spec restore(GenServer.server(), Path.t(), keyword()) :: {:ok, map()} | {:error, term()}
+  def restore(client, src, opts \\ []) do
+    GenServer.call(client, {:restore, src, opts}, :infinity)
   end
 
   @doc """
-  Gets maximum batch size for operations
+  Closes the client's store and opens it again with the client's options.
+
+  A panic in native code is returned as `{:error, {:panic, message}}` and
+  leaves the store usable, but if it left Chroma's state broken, later calls
+  keep failing; restarting the native side recovers without restarting the
+  client. Loaded embedding models are loaded again when next needed.
   """
-  @spec max_batch_size() :: non_neg_integer()
-  def max_batch_size do
-    GenServer.call(__MODULE__, :max_batch_size)
+  @spec restart_native(GenServer.server()) :: :ok | {:error, term()}
+  def restart_native(client \\ __MODULE__) do
+    GenServer.call(client, :restart_native, :infinity)
   end
 
   def handle_call(:get_resource, _from, %__MODULE__{resource: resource} = state) do
     {:reply, resource, state}
   end
 
-  def handle_call(:max_batch_size, _from, %__MODULE__{resource: resource} = state) do
-    result = Native.get_max_batch_size(resource)
-    {:reply, result, state}
+  def handle_call(:hnsw_cache_size, _from, %__MODULE__{resource: resource} = state) do
+    {:reply, Native.get_hnsw_cache_size(resource), state}
+  end
+
+  def handle_call(:embedding_cache, _from, %__MODULE__{embedding_cache: cache} = state) do
+    {:reply, cache, state}
+  end
+
+  # Downloading and loading a model runs in a task, so that the client keeps
+  # answering get_resource/1 meanwhile; callers asking for a model already
+  # loading wait for the same task
+  def handle_call({:ensure_embedder, name}, from, %__MODULE__{} = state) do
+    cond do
+      MapSet.member?(state.embedders, name) ->
+        {:reply, :ok, state}
+
+      Map.has_key?(state.loading, name) ->
+        {:noreply, update_in(state.loading[name].waiters, &[from | &1])}
+
+      true ->
+        {:noreply, load_embedder(state, name, [from])}
+    end
+  end
+
+  def handle_call(:max_batch_size, _from, %__MODULE__{max_batch_size: max_batch_size} = state) do
+    {:reply, max_batch_size, state}
+  end
+
+  # A store that does not close in time is closed regardless, and is the
+  # store being recovered, so reopening goes ahead either way
+  def handle_call(:restart_native, _from, %__MODULE__{} = state) do
+    _ = close(state, shutdown_timeout(state.opts))
+
+    case open(state.opts) do
+      {:ok, reopened} -> {:reply, :ok, %{reopened | loading: state.loading}}
+      {:error, reason} = error -> {:stop, reason, error, state}
+    end
+  end
+
+  def handle_call({:restore, src, opts}, _from, %__MODULE__{} = state) do
+    path = state.persist_path || @default_persist_path
+    staging = path <> ".restoring"
+
+    with {:ok, manifest} <- stage(src, staging) do
+      case close(state, Keyword.get(opts, :timeout, shutdown_timeout(state.opts))) do
+        :ok ->
+          swap(path, staging, manifest, state)
+
+        # The store is closed even when it did not close in time
+        {:error, reason} ->
+          File.rm_rf(staging)
+          reopen(reason, state)
+      end
+    else
+      {:error, reason} ->
+        File.rm_rf(staging)
+        {:reply, {:error, reason}, state}
+    end
+  end
+
+  def handle_info({:EXIT, _pid, _reason}, state), do: {:noreply, state}
+
+  def handle_info({ref, result}, %__MODULE__{} = state) when is_reference(ref) do
+    Process.demonitor(ref, [:flush])
+    {:noreply, embedder_loaded(state, ref, result)}
+  end
+
+  def handle_info({:DOWN, ref, :process, _pid, reason}, %__MODULE__{} = state) do
+    message = "loading the embedding model failed: " <> Exception.format_exit(reason)
+    {:noreply, embedder_loaded(state, ref, {:error, {:internal, message}})}
+  end
+
+  def terminate(_reason, %__MODULE__{} = state) do
+    close(state, shutdown_timeout(state.opts))
+  end
+
+  defp load_embedder(state, name, waiters) do
+    resource = state.resource
+
+    task =
+      Task.Supervisor.async_nolink(ChromEx.TaskSupervisor, fn ->
+        with {:ok, model} <- Model.get(name),
+             {:ok, files} <- Model.files(model) do
+          Native.load_embedder(resource, name, Model.to_native(model, files))
+        end
+      end)
+
+    put_in(state.loading[name], %{ref: task.ref, resource: resource, waiters: waiters})
+  end
+
+  defp embedder_loaded(state, ref, result) do
+    case Enum.find(state.loading, fn {_name, loading} -> loading.ref == ref end) do
+      nil ->
+        state
+
+      {name, %{resource: resource, waiters: waiters}} ->
+        state = %{state | loading: Map.delete(state.loading, name)}
+
+        cond do
+          # Loaded into a store that restart_native/1 or restore/3 has
+          # replaced since, so load it again into the current one
+          resource != state.resource ->
+            load_embedder(state, name, waiters)
+
+          result == :ok ->
+            Enum.each(waiters, &GenServer.reply(&1, :ok))
+            %{state | embedders: MapSet.put(state.embedders, name)}
+
+          true ->
+            Enum.each(waiters, &GenServer.reply(&1, result))
+            state
+        end
+    end
+  end
+
+  # Closes the store and the embedding cache. Closing the store again does
+  # nothing.
+  defp close(state, timeout) do
+    if state.embedding_cache, do: EmbeddingCache.close(state.embedding_cache)
+    Native.close(state.resource, timeout)
+  end
+
+  # Moves the staged backup into the closed store's place and opens it,
+  # putting the store back and reopening it if any step fails
+  defp swap(path, staging, manifest, state) do
+    previous = path <> ".previous"
+    File.rm_rf(previous)
+
+    with :ok <- rename(path, previous),
+         :ok <- put_back_on_error(rename(staging, path), previous, path) do
+      File.rm(Path.join(path, "manifest.json"))
+
+      # The embedding cache is keyed by content, so it stays valid; if it
+      # cannot be moved, the restored store starts with an empty one
+      cache = Path.join(previous, EmbeddingCache.file_name())
+      if File.exists?(cache), do: File.rename(cache, Path.join(path, EmbeddingCache.file_name()))
+
+      case open(state.opts) do
+        {:ok, restored} ->
+          File.rm_rf(previous)
+          {:reply, {:ok, manifest}, %{restored | loading: state.loading}}
+
+        {:error, reason} ->
+          File.rm_rf(path)
+          File.rename(previous, path)
+          reopen(reason, state)
+      end
+    else
+      {:error, reason} ->
+        File.rm_rf(staging)
+        reopen(reason, state)
+    end
+  end
+
+  defp put_back_on_error(:ok, _previous, _path), do: :ok
+
+  defp put_back_on_error({:error, reason}, previous, path) do
+    File.rename(previous, path)
+    {:error, reason}
+  end
+
+  defp rename(source, destination) do
+    case File.rename(source, destination) do
+      :ok ->
+        :ok
+
+      {:error, reason} ->
+        {:error,
+         {:internal, "could not move #{source} to #{destination}: #{:file.format_error(reason)}"}}
+    end
+  end
+
+  defp reopen(reason, state) do
+    case open(state.opts) do
+      {:ok, reopened} -> {:reply, {:error, reason}, %{reopened | loading: state.loading}}
+      {:error, _reason} = error -> {:stop, reason, error, state}
+    end
+  end
+
+  # Copies the backup next to the store, where it can be renamed into
+  # place, and verifies the copy
+  defp stage(src, staging) do
+    File.rm_rf(staging)
+
+    case File.cp_r(src, staging) do
+      {:ok, _files} ->
+        Native.verify_backup(staging)
+
+      {:error, reason, file} ->
+        {:error, {:internal, "could not copy #{file}: #{:file.format_error(reason)}"}}
+    end
   end
 end
@@ -1,13 +1,51 @@
 defmodule ChromEx.Collection do
   @moduledoc """
   ChromEx collection operations for document storage and retrieval
+
+  Every operation runs on the native runtime rather than on a BEAM scheduler
+  and accepts a `:timeout` option (in milliseconds) bounding how long the
+  caller waits for the result. It defaults to the `:timeout` application env,
+  or 60 seconds.
+
+  Functions that look collections up by name accept a `:client` option naming
+  the `ChromEx.Client` to use (defaults to `ChromEx.Client`). The returned
+  structs remember their client, so operations on a collection always go to
+  the store it came from.
+
+  Embeddings may be given as lists of floats, as a rank-2 `Nx.Tensor` (one
+  row per record), or already packed as `{binary, rows, dimension}` where
+  `binary` holds little-endian f32 values. Tensors are handed to the native
+  side as a single binary, without converting them to lists. Pass
+  `embeddings_as: :tensor` to `query/3` or `get_documents/2` to receive
+  included embeddings as tensors (`[]` for a block without records).
+
+  Documents and query texts given without embeddings are embedded with the
+  collection's `ChromEx.EmbeddingFunction`, which is persisted in its
+  configuration when the collection is created.
   """
 
-  alias ChromEx.{Client, Native}
+  alias ChromEx.{Chunking, Client, EmbeddingCache, EmbeddingFunction, Embeddings, Native, Search}
+  alias ChromEx.SparseVector
+
+  require Logger
 
-  defstruct [:id, :name, :tenant, :database, :metadata, :configuration]
+  # Nx is optional; tensors can only be passed in when it is installed.
+  @compile {:no_warn_undefined, Nx}
+
+  defstruct [
+    :id,
+    :name,
+    :tenant,
+    :database,
+    :metadata,
+    :configuration,
+    :embedding_function,
+    client: Client
+  ]
 
   @type t :: %__MODULE__{
+          client: GenServer.server(),
+          embedding_function: EmbeddingFunction.t() | nil,
           id: String.t(),
           name: String.t(),
           tenant: String.t(),
@@ -18,43 +56,67 @@ defmodule ChromEx.Collection do
 
   @default_tenant "default_tenant"
   @default_database "default_database"
+  @default_sparse_key "sparse_embedding"
+  @collapse_factor 4
+  @truncated_event [:chromex, :collection, :truncated]
+
+  # Options of add, upsert and update_documents holding one entry per record
+  @per_record_keys [:ids, :documents, :metadatas, :uris, :embeddings, :sparse_embeddings]
 
   @doc """
   Creates a new collection
+
+  ## Options
+
+    * `:metadata` - collection metadata
+    * `:configuration` - collection configuration, e.g. `%{"hnsw" => %{"space" => "cosine"}}`
+    * `:schema` - Chroma index schema, e.g. to configure indexes per metadata key
+    * `:sparse_vector_index` - metadata key to build a sparse vector index on,
+      so that `ChromEx.SparseVector` values stored under it can be searched
+    * `:embedding_function` - `ChromEx.EmbeddingFunction` module, or
+      `{module, config}`, used to embed documents and query texts (default
+      `ChromEx.EmbeddingFunction.Default`). It is persisted in the collection
+      configuration; an existing collection with a different function
+      returns `{:error, {:embedding_function_mismatch, persisted, given}}`.
+    * `:get_or_create` - return the existing collection instead of failing
+      (default `true`)
   """
   @spec create(String.t(), keyword()) :: {:ok, t()} | {:error, term()}
   def create(name, opts \\ []) do
-    resource = Client.get_resource()
+    client = Keyword.get(opts, :client, Client)
+    resource = Client.get_resource(client)
     config = Keyword.get(opts, :configuration)
     metadata = Keyword.get(opts, :metadata)
+    schema = schema(Keyword.get(opts, :schema), Keyword.get(opts, :sparse_vector_index))
+
+    embedding_function =
+      opts
+      |> Keyword.get(:embedding_function, EmbeddingFunction.Default)
+      |> EmbeddingFunction.new()
+
     get_or_create = Keyword.get(opts, :get_or_create, true)
     tenant = Keyword.get(opts, :tenant, @default_tenant)
     database = Keyword.get(opts, :database, @default_database)
 
-    config_json = if config, do: Jason.encode!(config), else: nil
-    metadata_json = if metadata, do: Jason.encode!(metadata), else: nil
-
-    case Native.create_collection(
-           resource,
-           name,
-           config_json,
-           metadata_json,
-           get_or_create,
-           tenant,
-           database
+    case Native.call(
+           &Native.create_collection(
+             resource,
+             &1,
+             name,
+             config,
+             EmbeddingFunction.to_configuration(embedding_function),
+             metadata && normalize_metadata(metadata),
+             schema,
+             get_or_create,
+             tenant,
+             database
+           ),
+           opts
          ) do
-      json when is_binary(json) ->
-        collection_data = Jason.decode!(json)
-
-        {:ok,
-         %__MODULE__{
-           id: collection_data["id"],
-           name: collection_data["name"],
-           tenant: Map.get(collection_data, "tenant", tenant),
-           database: Map.get(collection_data, "database", database),
-           metadata: collection_data["metadata"],
-           configuration: collection_data["configuration"]
-         }}
+      {:ok, collection_data} ->
+        collection_data
+        |> from_data(client, tenant, database)
+        |> put_embedding_function(embedding_function)
 
       {:error, reason} ->
         {:error, reason}
@@ -68,7 +130,7 @@ defmodule ChromEx.Collection do
   def create!(name, opts \\ []) do
     case create(name, opts) do
       {:ok, collection} -> collection
-      {:error, reason} -> raise "Failed to create collection: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "create collection"
     end
   end
 
@@ -79,32 +141,37 @@ defmodule ChromEx.Collection do
   def get!(name, opts \\ []) do
     case get(name, opts) do
       {:ok, collection} -> collection
-      {:error, reason} -> raise "Failed to get collection: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "get collection"
     end
   end
 
   @doc """
   Retrieves an existing collection by name
+
+  Documents and query texts are embedded with the embedding function
+  persisted when the collection was created.
+
+  ## Options
+
+    * `:embedding_function` - the `ChromEx.EmbeddingFunction` the caller
+      expects, as a module or `{module, config}`. Returns
+      `{:error, {:embedding_function_mismatch, persisted, given}}` if the
+      collection was created with a different one.
   """
   @spec get(String.t(), keyword()) :: {:ok, t()} | {:error, term()}
   def get(name, opts \\ []) do
-    resource = Client.get_resource()
+    client = Keyword.get(opts, :client, Client)
+    resource = Client.get_resource(client)
     tenant = Keyword.get(opts, :tenant, @default_tenant)
     database = Keyword.get(opts, :database, @default_database)
 
-    case Native.get_collection(resource, name, tenant, database) do
-      json when is_binary(json) ->
-        collection_data = Jason.decode!(json)
+    case Native.call(&Native.get_collection(resource, &1, name, tenant, database), opts) do
+      {:ok, collection_data} ->
+        embedding_function = Keyword.get(opts, :embedding_function)
 
-        {:ok,
-         %__MODULE__{
-           id: collection_data["id"],
-           name: collection_data["name"],
-           tenant: Map.get(collection_data, "tenant", tenant),
-           database: Map.get(collection_data, "database", database),
-           metadata: collection_data["metadata"],
-           configuration: collection_data["configuration"]
-         }}
+        collection_data
+        |> from_data(client, tenant, database)
+        |> put_embedding_function(embedding_function && EmbeddingFunction.new(embedding_function))
 
       {:error, reason} ->
         {:error, reason}
@@ -113,33 +180,40 @@ defmodule ChromEx.Collection do
 
   @doc """
   Updates an existing collection
+
+  `:configuration` accepts the settings Chroma allows changing after
+  creation, e.g. `%{"hnsw" => %{"ef_search" => 200, "num_threads" => 4}}`.
+  Anything else is rejected with `{:error, {:validation, message}}`. The
+  returned struct is reloaded, so it reflects what Chroma actually stored.
   """
   @spec update(t(), keyword()) :: {:ok, t()} | {:error, term()}
   def update(%__MODULE__{} = collection, opts) do
-    resource = Client.get_resource()
+    resource = Client.get_resource(collection.client)
     new_name = Keyword.get(opts, :name)
     new_metadata = Keyword.get(opts, :metadata)
     new_configuration = Keyword.get(opts, :configuration)
 
-    new_metadata_json = if new_metadata, do: Jason.encode!(new_metadata), else: nil
-    new_config_json = if new_configuration, do: Jason.encode!(new_configuration), else: nil
-
-    case Native.update_collection(
-           resource,
-           collection.id,
-           new_name,
-           new_metadata_json,
-           new_config_json
+    case Native.call(
+           &Native.update_collection(
+             resource,
+             &1,
+             collection.id,
+             new_name,
+             new_metadata && normalize_metadata(new_metadata),
+             new_configuration
+           ),
+           opts
          ) do
       :ok ->
-        updated = %__MODULE__{
-          collection
-          | name: new_name || collection.name,
-            metadata: new_metadata || collection.metadata,
-            configuration: new_configuration || collection.configuration
-        }
-
-        {:ok, updated}
+        get(
+          new_name || collection.name,
+          [
+            client: collection.client,
+            tenant: collection.tenant,
+            database: collection.database,
+            embedding_function: collection.embedding_function
+          ] ++ Keyword.take(opts, [:timeout])
+        )
 
       {:error, reason} ->
         {:error, reason}
@@ -153,7 +227,7 @@ defmodule ChromEx.Collection do
   def delete!(name, opts \\ []) do
     case delete(name, opts) do
       :ok -> :ok
-      {:error, reason} -> raise "Failed to delete collection: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "delete collection"
     end
   end
 
@@ -162,12 +236,13 @@ defmodule ChromEx.Collection do
   """
   @spec delete(String.t(), keyword()) :: :ok | {:error, term()}
   def delete(name, opts \\ []) do
-    resource = Client.get_resource()
+    client = Keyword.get(opts, :client, Client)
+    resource = Client.get_resource(client)
     tenant = Keyword.get(opts, :tenant, @default_tenant)
     database = Keyword.get(opts, :database, @default_database)
 
-    case Native.delete_collection(resource, name, tenant, database) do
-      "ok" -> :ok
+    case Native.call(&Native.delete_collection(resource, &1, name, tenant, database), opts) do
+      :ok -> :ok
       {:error, reason} -> {:error, reason}
     end
   end
@@ -179,7 +254,7 @@ defmodule ChromEx.Collection do
   def list!(opts \\ []) do
     case list(opts) do
       {:ok, collections} -> collections
-      {:error, reason} -> raise "Failed to list collections: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "list collections"
     end
   end
 
@@ -188,28 +263,19 @@ defmodule ChromEx.Collection do
   """
   @spec list(keyword()) :: {:ok, [t()]} | {:error, term()}
   def list(opts \\ []) do
-    resource = Client.get_resource()
+    client = Keyword.get(opts, :client, Client)
+    resource = Client.get_resource(client)
     limit = Keyword.get(opts, :limit)
     offset = Keyword.get(opts, :offset)
     tenant = Keyword.get(opts, :tenant, @default_tenant)
     database = Keyword.get(opts, :database, @default_database)
 
-    case Native.list_collections(resource, limit, offset, tenant, database) do
-      json when is_binary(json) ->
-        collections =
-          Jason.decode!(json)
-          |> Enum.map(fn collection_data ->
-            %__MODULE__{
-              id: collection_data["id"],
-              name: collection_data["name"],
-              tenant: Map.get(collection_data, "tenant", tenant),
-              database: Map.get(collection_data, "database", database),
-              metadata: collection_data["metadata"],
-              configuration: collection_data["configuration"]
-            }
-          end)
-
-        {:ok, collections}
+    case Native.call(
+           &Native.list_collections(resource, &1, limit, offset, tenant, database),
+           opts
+         ) do
+      {:ok, collections} ->
+        {:ok, Enum.map(collections, &from_data(&1, client, tenant, database))}
 
       {:error, reason} ->
         {:error, reason}
@@ -221,19 +287,61 @@ defmodule ChromEx.Collection do
   """
   @spec count_all(keyword()) :: {:ok, non_neg_integer()} | {:error, term()}
   def count_all(opts \\ []) do
-    resource = Client.get_resource()
+    client = Keyword.get(opts, :client, Client)
+    resource = Client.get_resource(client)
     tenant = Keyword.get(opts, :tenant, @default_tenant)
     database = Keyword.get(opts, :database, @default_database)
 
-    case Native.count_collections(resource, tenant, database) do
-      count when is_integer(count) -> {:ok, count}
-      {:error, reason} -> {:error, reason}
-    end
+    Native.call(&Native.count_collections(resource, &1, tenant, database), opts)
   end
 
   @doc """
   Adds documents to a collection
 
+  ## Truncation
+
+  Documents embedded by a model (see `ChromEx.EmbeddingFunction`) are cut
+  to the model's maximum input length. The `:truncation` option decides what
+  happens to documents longer than that:
+
+    * `:allow` (default) - embed their first tokens only, without a
+      warning. The other modes tokenize the documents an extra time to find
+      the long ones.
+    * `:warn` - embed their first tokens only and log a warning naming them
+    * `:error` - write nothing and return
+      `{:error, {:truncated, [%{id: id, tokens: tokens, max_tokens: max}]}}`
+    * `:chunk` - store each over-length document as overlapping windows of
+      at most `max_tokens` tokens instead. Chunk `i` of document `id` is
+      stored as `"id#chunk-i"` with the window's text and the document's
+      metadata plus `"parent_id"` and `"chunk_index"`. `:chunk_overlap` sets
+      how many tokens consecutive windows share (default 32). Query with
+      `collapse_chunks: true` to get parent documents back.
+
+  Documents embedded cut short in `:allow` or `:warn` mode are reported by a
+  `[:chromex, :collection, :truncated]` telemetry event, measuring `:count`,
+  with the collection's `:collection` name and `:collection_id` and the
+  `:documents` list of `%{id: id, tokens: tokens, max_tokens: max}` as
+  metadata. In `:allow` mode, documents are only tokenized to find them
+  while a handler is attached to that event.
+
+  ## Batching
+
+  Writes with more records than Chroma accepts at once (see
+  `ChromEx.max_batch_size/1`) are split into batches, written in order or
+  concurrently:
+
+    * `:batch_size` - records per batch (default `ChromEx.max_batch_size/1`)
+    * `:max_concurrency` - batches written at the same time (default 1)
+    * `:batch_mode` - `:atomic` (default) undoes the batches already written
+      when one fails, so that nothing is written. `:best_effort` keeps them
+      and writes the remaining batches too.
+
+  A failed split write returns
+  `{:error, {:batches_failed, [%{batch: index, ids: ids, reason: reason}]}}`,
+  listing each failed batch, or `{:error, {:rollback_failed, failures, reason}}`
+  if undoing an atomic write failed as well. Writes that fit in one batch
+  return Chroma's error unchanged.
+
   ## Examples
 
       # Positional IDs
@@ -249,6 +357,14 @@ defmodule ChromEx.Collection do
         embeddings: [[0.1, 0.2], [0.3, 0.4]],
         documents: ["doc1", "doc2"]
       )
+
+      # With sparse vectors, stored in metadata under `:sparse_key`
+      # (default "sparse_embedding")
+      ChromEx.Collection.add(collection,
+        ids: ["id1"],
+        documents: ["doc1"],
+        sparse_embeddings: [ChromEx.SparseVector.from_map(%{12 => 0.5, 97 => 1.3})]
+      )
   """
   @spec add(t(), [String.t()] | keyword(), keyword()) :: :ok | {:error, term()}
   def add(%__MODULE__{} = collection, ids_or_opts, opts \\ []) do
@@ -263,7 +379,13 @@ defmodule ChromEx.Collection do
   end
 
   defp add_impl(%__MODULE__{} = collection, opts) do
-    resource = Client.get_resource()
+    with {:ok, opts} <- check_truncation(collection, opts) do
+      write_batches(collection, :add, opts, &add_records(collection, &1))
+    end
+  end
+
+  defp add_records(%__MODULE__{} = collection, opts) do
+    resource = Client.get_resource(collection.client)
     ids = Keyword.get(opts, :ids) || raise ArgumentError, "ids are required"
     documents = Keyword.get(opts, :documents)
     metadatas = Keyword.get(opts, :metadatas)
@@ -273,37 +395,37 @@ defmodule ChromEx.Collection do
       case Keyword.get(opts, :embeddings) do
         nil ->
           if documents do
-            ChromEx.Embeddings.generate(documents)
+            embed_texts(collection, documents, :document)
           else
             raise ArgumentError, "Either embeddings or documents must be provided"
           end
 
         provided_embeddings ->
-          provided_embeddings
-      end
-
-    metadatas_json =
-      if metadatas do
-        Enum.map(metadatas, fn
-          nil -> nil
-          meta when is_map(meta) -> Jason.encode!(meta)
-          meta when is_list(meta) -> Jason.encode!(Map.new(meta))
-        end)
+          {:ok, pack_embeddings(provided_embeddings), nil}
       end
 
-    case Native.add(
-           resource,
-           ids,
-           collection.id,
-           embeddings,
-           metadatas_json,
-           documents,
-           uris,
-           collection.tenant,
-           collection.database
-         ) do
-      "ok" -> :ok
-      {:error, reason} -> {:error, reason}
+    metadatas =
+      metadatas
+      |> put_sparse_embeddings(Keyword.get(opts, :sparse_embeddings), opts)
+      |> normalize_metadatas()
+
+    with {:ok, embeddings, embedding_model} <- embeddings do
+      Native.call(
+        &Native.add(
+          resource,
+          &1,
+          ids,
+          collection.id,
+          embeddings,
+          embedding_model,
+          metadatas,
+          documents,
+          uris,
+          collection.tenant,
+          collection.database
+        ),
+        opts
+      )
     end
   end
 
@@ -314,7 +436,7 @@ defmodule ChromEx.Collection do
   def add!(collection, ids_or_opts, opts \\ []) do
     case add(collection, ids_or_opts, opts) do
       :ok -> :ok
-      {:error, reason} -> raise "Failed to add documents: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "add documents"
     end
   end
 
@@ -332,7 +454,7 @@ defmodule ChromEx.Collection do
   def query!(collection, query_embeddings_or_opts, opts \\ []) do
     case query(collection, query_embeddings_or_opts, opts) do
       {:ok, results} -> results
-      {:error, reason} -> raise "Failed to query collection: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "query collection"
     end
   end
 
@@ -352,6 +474,20 @@ defmodule ChromEx.Collection do
         n_results: 5
       )
 
+      # With an Nx tensor, returning embeddings as tensors
+      ChromEx.Collection.query(collection, Nx.tensor([[0.1, 0.2, ...]]),
+        include: ["embeddings", "distances"],
+        embeddings_as: :tensor
+      )
+
+      # Documents added with `truncation: :chunk`, one result per document
+      # (the best of up to 4 * n_results nearest chunks)
+      ChromEx.Collection.query(collection,
+        query_texts: ["search query"],
+        n_results: 5,
+        collapse_chunks: true
+      )
+
       # With metadata filter (single condition)
       ChromEx.Collection.query(collection,
         query_texts: ["search"],
@@ -386,53 +522,263 @@ defmodule ChromEx.Collection do
   - `$lte` - Less than or equal
   - `$in` - Value in list
   - `$nin` - Value not in list
+
+  ## Document Filtering
+
+  `:where_document` filters on document contents and is combined with
+  `:where`. It is also accepted by `get_documents/2` and `delete_documents/2`.
+  - `$contains` - Document contains the substring
+  - `$not_contains` - Document does not contain the substring
+  - `$regex` - Document matches the regular expression
+  - `$and` / `$or` - Combine document filters
   """
-  @spec query(t(), [[float()]] | keyword(), keyword()) :: {:ok, map()} | {:error, term()}
+  @spec query(t(), [[float()]] | Nx.Tensor.t() | keyword(), keyword()) ::
+          {:ok, map()} | {:error, term()}
   def query(collection, query_embeddings_or_opts, opts \\ [])
 
-  def query(%__MODULE__{} = collection, query_embeddings, opts) when is_list(query_embeddings) and is_list(hd(query_embeddings)) and is_number(hd(hd(query_embeddings))) do
-    query_impl(collection, query_embeddings, opts)
+  def query(%__MODULE__{} = collection, query_embeddings, opts)
+      when is_list(query_embeddings) and is_list(hd(query_embeddings)) and
+             is_number(hd(hd(query_embeddings))) do
+    query_impl(collection, query_embeddings, nil, opts)
+  end
+
+  def query(%__MODULE__{} = collection, query_embeddings, opts)
+      when is_struct(query_embeddings, Nx.Tensor) or is_tuple(query_embeddings) do
+    query_impl(collection, pack_embeddings(query_embeddings), nil, opts)
   end
 
   def query(%__MODULE__{} = collection, opts, _opts2) when is_list(opts) and is_tuple(hd(opts)) do
     query_texts = Keyword.get(opts, :query_texts)
 
     query_embeddings =
-      if query_texts do
-        ChromEx.Embeddings.generate(query_texts)
-      else
-        raise ArgumentError, "Either provide query_embeddings as second argument or query_texts in options"
+      cond do
+        embeddings = Keyword.get(opts, :query_embeddings) ->
+          {:ok, pack_embeddings(embeddings), nil}
+
+        query_texts ->
+          embed_texts(collection, query_texts, :query)
+
+        true ->
+          raise ArgumentError,
+                "Either provide query_embeddings as second argument or query_texts in options"
       end
 
-    query_impl(collection, query_embeddings, opts)
+    with {:ok, query_embeddings, embedding_model} <- query_embeddings do
+      query_impl(collection, query_embeddings, embedding_model, opts)
+    end
   end
 
-  defp query_impl(%__MODULE__{} = collection, query_embeddings, opts) do
-    resource = Client.get_resource()
+  defp query_impl(%__MODULE__{} = collection, query_embeddings, embedding_model, opts) do
+    resource = Client.get_resource(collection.client)
     n_results = Keyword.get(opts, :n_results, 10)
     where = Keyword.get(opts, :where)
     where_document = Keyword.get(opts, :where_document)
     include = Keyword.get(opts, :include, ["metadatas", "documents", "distances"])
+    as_tensor = Keyword.get(opts, :embeddings_as, :list) == :tensor
+    query_texts = if is_nil(query_embeddings), do: Keyword.get(opts, :query_texts)
+    collapse = Keyword.get(opts, :collapse_chunks, false)
 
-    where_json = if where, do: Jason.encode!(where), else: nil
-    where_document_json = if where_document, do: Jason.encode!(where_document), else: nil
-
-    case Native.query(
-           resource,
-           collection.id,
-           query_embeddings,
-           n_results,
-           where_json,
-           where_document_json,
-           include,
-           collection.tenant,
-           collection.database
-         ) do
-      json when is_binary(json) -> {:ok, Jason.decode!(json)}
-      {:error, reason} -> {:error, reason}
+    if collapse and as_tensor do
+      raise ArgumentError, "collapse_chunks can't be combined with embeddings_as: :tensor"
     end
+
+    # Collapsing needs the chunks' metadata, and fetches extra neighbours
+    # since several may belong to the same document
+    {fetch, fetch_include} =
+      if collapse,
+        do: {n_results * @collapse_factor, Enum.uniq(["metadatas" | include])},
+        else: {n_results, include}
+
+    Native.call(
+      &Native.query(
+        resource,
+        &1,
+        collection.id,
+        query_embeddings,
+        query_texts,
+        embedding_model,
+        fetch,
+        where,
+        where_document,
+        fetch_include,
+        as_tensor,
+        collection.tenant,
+        collection.database
+      ),
+      opts
+    )
+    |> maybe_collapse(collapse, n_results, "metadatas" in include)
+    |> maybe_tensor_embeddings(as_tensor)
+  end
+
+  defp maybe_collapse({:ok, results}, true, n_results, keep_metadatas) do
+    results = Chunking.collapse(results, n_results)
+    {:ok, if(keep_metadatas, do: results, else: Map.put(results, "metadatas", nil))}
   end
 
+  defp maybe_collapse(result, _collapse, _n_results, _keep_metadatas), do: result
+
+  @doc """
+  Runs one or more `ChromEx.Search` payloads against a collection
+
+  Returns a map with `"ids"`, `"documents"`, `"embeddings"`, `"metadatas"`,
+  `"scores"` and `"select"`, each holding one list per search, in the order
+  the searches were given. Keys that were not selected are `nil`.
+
+  ## Examples
+
+      alias ChromEx.Search
+
+      search =
+        Search.new()
+        |> Search.where(%{"year" => %{"$gte" => 2024}})
+        |> Search.rank(Search.knn(query_embedding, limit: 50))
+        |> Search.limit(5)
+        |> Search.select(["#document", "#score"])
+
+      {:ok, %{"ids" => [ids], "scores" => [scores]}} =
+        ChromEx.Collection.search(collection, search)
+  """
+  @spec search(t(), Search.t() | [Search.t()], keyword()) ::
+          {:ok, map()} | {:error, term()}
+  def search(%__MODULE__{} = collection, searches, opts \\ []) do
+    resource = Client.get_resource(collection.client)
+    payloads = searches |> List.wrap() |> Enum.map(&Search.to_payload/1)
+
+    Native.call(
+      &Native.search(
+        resource,
+        &1,
+        collection.id,
+        payloads,
+        collection.tenant,
+        collection.database
+      ),
+      opts
+    )
+  end
+
+  @doc """
+  Runs one or more searches against a collection, raising on error
+  """
+  @spec search!(t(), Search.t() | [Search.t()], keyword()) :: map()
+  def search!(%__MODULE__{} = collection, searches, opts \\ []) do
+    case search(collection, searches, opts) do
+      {:ok, results} -> results
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "search collection"
+    end
+  end
+
+  @doc """
+  Queries with a dense embedding and a sparse vector at once and fuses both
+  rankings into a single ranked list
+
+  The fusion runs inside Chroma as a `ChromEx.Search` rank expression.
+
+  ## Options
+
+    * `:query_text` or `:query_embedding` - the dense query
+    * `:sparse_query` - a `ChromEx.SparseVector`
+    * `:sparse_key` - metadata key holding the sparse vectors (default
+      `"sparse_embedding"`)
+    * `:fusion` - `:rrf` (default) for reciprocal rank fusion, or
+      `{:weighted, dense_weight, sparse_weight}` for a weighted sum of distances
+    * `:missing_score` - with weighted fusion, the distance used for a record
+      missing from one of the rankings (default 1.0)
+    * `:rrf_k` - RRF smoothing constant (default 60)
+    * `:candidates` - neighbours taken from each ranking before fusion
+      (default `max(n_results * 5, 50)`)
+    * `:n_results` - number of results (default 10)
+    * `:where`, `:where_document` - filters, as in `query/3`
+    * `:select` - keys to return (default `["#document", "#metadata", "#score"]`)
+
+  Returns `{:ok, %{"ids" => ids, "scores" => scores, ...}}` with one flat list
+  per key, best match first.
+
+  ## Examples
+
+      ChromEx.Collection.hybrid_query(collection,
+        query_text: "reset password",
+        sparse_query: MyBM25.encode("reset password"),
+        n_results: 5
+      )
+  """
+  @spec hybrid_query(t(), keyword()) :: {:ok, map()} | {:error, term()}
+  def hybrid_query(%__MODULE__{} = collection, opts) do
+    n_results = Keyword.get(opts, :n_results, 10)
+    candidates = Keyword.get(opts, :candidates, max(n_results * 5, 50))
+    sparse_key = Keyword.get(opts, :sparse_key, @default_sparse_key)
+
+    sparse_query =
+      Keyword.get(opts, :sparse_query) || raise ArgumentError, "sparse_query is required"
+
+    dense_query =
+      case Keyword.fetch(opts, :query_embedding) do
+        {:ok, embedding} ->
+          {:ok, embedding}
+
+        :error ->
+          text =
+            Keyword.get(opts, :query_text) ||
+              raise ArgumentError, "query_text or query_embedding is required"
+
+          with {:ok, embedding_function} <- embedding_function(collection) do
+            {:ok, hd(generate_embeddings(collection, embedding_function, [text], :query))}
+          end
+      end
+
+    with {:ok, dense_query} <- dense_query do
+      rank =
+        case Keyword.get(opts, :fusion, :rrf) do
+          :rrf ->
+            Search.rrf(
+              [
+                Search.knn(dense_query,
+                  limit: candidates,
+                  return_rank: true,
+                  default: candidates
+                ),
+                Search.knn(sparse_query,
+                  key: sparse_key,
+                  limit: candidates,
+                  return_rank: true,
+                  default: candidates
+                )
+              ],
+              k: Keyword.get(opts, :rrf_k, 60)
+            )
+
+          {:weighted, dense_weight, sparse_weight} ->
+            missing = Keyword.get(opts, :missing_score, 1.0)
+
+            Search.weighted_sum([
+              {Search.knn(dense_query, limit: candidates, default: missing), dense_weight},
+              {Search.knn(sparse_query, key: sparse_key, limit: candidates, default: missing),
+               sparse_weight}
+            ])
+        end
+
+      search =
+        Search.new()
+        |> Search.rank(rank)
+        |> Search.limit(n_results)
+        |> Search.select(Keyword.get(opts, :select, ["#document", "#metadata", "#score"]))
+        |> Search.where(Keyword.get(opts, :where))
+        |> Search.where_document(Keyword.get(opts, :where_document))
+
+      case search(collection, search, opts) do
+        {:ok, results} ->
+          {:ok, Map.new(results, fn {key, value} -> {key, first_search(value)} end)}
+
+        {:error, reason} ->
+          {:error, reason}
+      end
+    end
+  end
+
+  defp first_search([result | _]), do: result
+  defp first_search(other), do: other
+
   @doc """
   Retrieves documents from a collection, raising on error
   """
@@ -440,41 +786,191 @@ defmodule ChromEx.Collection do
   def get_documents!(%__MODULE__{} = collection, opts \\ []) do
     case get_documents(collection, opts) do
       {:ok, docs} -> docs
-      {:error, reason} -> raise "Failed to get documents: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "get documents"
     end
   end
 
   @doc """
   Retrieves documents from a collection
+
+  With `include: ["embeddings"]` and `embeddings_as: :tensor`, the
+  `"embeddings"` entry is a `{n, dimension}` `Nx.Tensor` instead of a list,
+  or `[]` when no record matched.
   """
   @spec get_documents(t(), keyword()) :: {:ok, map()} | {:error, term()}
   def get_documents(%__MODULE__{} = collection, opts \\ []) do
-    resource = Client.get_resource()
+    resource = Client.get_resource(collection.client)
     ids = Keyword.get(opts, :ids)
     where = Keyword.get(opts, :where)
     limit = Keyword.get(opts, :limit)
     offset = Keyword.get(opts, :offset)
     where_document = Keyword.get(opts, :where_document)
     include = Keyword.get(opts, :include, ["metadatas", "documents"])
+    as_tensor = Keyword.get(opts, :embeddings_as, :list) == :tensor
+
+    Native.call(
+      &Native.get(
+        resource,
+        &1,
+        collection.id,
+        ids,
+        where,
+        limit,
+        offset,
+        where_document,
+        include,
+        as_tensor,
+        collection.tenant,
+        collection.database
+      ),
+      opts
+    )
+    |> maybe_tensor_embeddings(as_tensor)
+  end
 
-    where_json = if where, do: Jason.encode!(where), else: nil
-    where_document_json = if where_document, do: Jason.encode!(where_document), else: nil
-
-    case Native.get(
-           resource,
-           collection.id,
-           ids,
-           where_json,
-           limit,
-           offset,
-           where_document_json,
-           include,
-           collection.tenant,
-           collection.database
-         ) do
-      json when is_binary(json) -> {:ok, Jason.decode!(json)}
-      {:error, reason} -> {:error, reason}
-    end
+  @doc """
+  Returns a lazy `Stream` of every record in a collection
+
+  Records are read a page at a time, in id order, so memory stays bounded by
+  `:page_size` however large the collection is. Each page starts after the
+  id of the last record of the previous one rather than at an offset, so
+  records written or deleted while the stream is consumed never cause
+  others to be skipped or repeated. Records added meanwhile are streamed if
+  their ids sort after the page being read, and not otherwise.
+
+  Each record is a map with an `:id` and, depending on `:include`, its
+  `:document`, `:metadata`, `:embedding` and `:uri` - the shape `ingest/3`
+  takes, so one collection can be copied into another with
+
+      records =
+        ChromEx.Collection.stream(collection, include: ["documents", "metadatas", "embeddings"])
+
+      ChromEx.Collection.ingest(copy, records)
+
+  Errors raise `ChromEx.Error` while the stream is consumed.
+
+  ## Options
+
+    * `:page_size` - records read per call (default 1000)
+    * `:where`, `:where_document` - filters, as in `get_documents/2`.
+      Filtered pages can hold fewer than `:page_size` records.
+    * `:include` - fields to return (default `["metadatas", "documents"]`)
+    * `:timeout` - per page
+  """
+  @spec stream(t(), keyword()) :: Enumerable.t()
+  def stream(%__MODULE__{} = collection, opts \\ []) do
+    page_size = Keyword.get(opts, :page_size, 1000)
+    include = Keyword.get(opts, :include, ["metadatas", "documents"])
+
+    Stream.resource(
+      fn -> {:after, nil} end,
+      fn
+        :done ->
+          {:halt, :done}
+
+        {:after, cursor} ->
+          resource = Client.get_resource(collection.client)
+
+          case Native.call(
+                 &Native.get_page(
+                   resource,
+                   &1,
+                   collection.id,
+                   cursor,
+                   page_size,
+                   Keyword.get(opts, :where),
+                   Keyword.get(opts, :where_document),
+                   include,
+                   false,
+                   collection.tenant,
+                   collection.database
+                 ),
+                 opts
+               ) do
+            {:ok, {nil, page}} ->
+              {page_records(page), :done}
+
+            {:ok, {next, page}} ->
+              {page_records(page), {:after, next}}
+
+            {:error, reason} ->
+              raise ChromEx.Error, reason: reason, action: "stream documents"
+          end
+      end,
+      fn _cursor -> :ok end
+    )
+  end
+
+  @doc """
+  Writes every record of a collection to a file
+
+  Ids, embeddings, documents, URIs and metadata are exported, preceded by a
+  header recording the collection's name, metadata, configuration and
+  embedding function, so `import/3` can recreate it in another store. The
+  format is picked with `:format` or from the file extension:
+
+    * `:jsonl` (`.jsonl`, and any unknown extension) - the header on the
+      first line, then one record per line as written by `stream/2`, with
+      string keys
+    * `:parquet` (`.parquet`) and `:arrow` (`.arrow`, `.ipc`, `.feather`) -
+      columns `id`, `embedding`, `document`, `uri` and `metadata` (as JSON
+      text), with the header as JSON under the `"chromex.export"` schema
+      metadata key. These need the native `arrow` feature, enabled with
+      `config :chromex, arrow_exports: true`.
+
+  Records are read with `stream/2` and written a page at a time. The file
+  only appears at `path` once complete. Returns `{:ok, count}`.
+
+  ## Options
+
+    * `:format` - `:jsonl`, `:parquet` or `:arrow`
+    * `:page_size` - records read and written at a time (default 1000)
+    * `:where`, `:where_document` - export only the matching records
+    * `:timeout` - per page
+  """
+  @spec export(t(), Path.t(), keyword()) :: {:ok, non_neg_integer()} | {:error, term()}
+  def export(%__MODULE__{} = collection, path, opts \\ []) do
+    ChromEx.Export.export(collection, path, opts)
+  end
+
+  @doc """
+  Creates the collection `name` from a file written by `export/3`
+
+  The collection gets the configuration, metadata and embedding function
+  recorded in the export's header, and records are written with `add/3` in
+  batches, so they are validated as any other write. Imports never replace
+  an existing collection. If any batch fails, the new collection is deleted
+  and `{:error, reason}` returned.
+
+  ## Options
+
+    * `:format` - as in `export/3`
+    * `:batch_size` - records per write (default 100)
+    * `:max_concurrency` - batches in flight, see `ingest/3`
+    * `:embedding_function` - use this function instead of the one in the
+      header, e.g. when that one is not registered here
+    * `:client`, `:tenant`, `:database` - where to create the collection
+    * `:timeout` - per call
+  """
+  @spec import(String.t(), Path.t(), keyword()) :: {:ok, t()} | {:error, term()}
+  def import(name, path, opts \\ []) do
+    ChromEx.Export.import(name, path, opts)
+  end
+
+  @record_fields [
+    document: "documents",
+    metadata: "metadatas",
+    embedding: "embeddings",
+    uri: "uris"
+  ]
+
+  defp page_records(page) do
+    included = for {field, key} <- @record_fields, page[key] != nil, do: {field, page[key]}
+    {fields, columns} = Enum.unzip([{:id, page["ids"]} | included])
+
+    columns
+    |> Enum.zip()
+    |> Enum.map(&(fields |> Enum.zip(Tuple.to_list(&1)) |> Map.new()))
   end
 
   @doc """
@@ -484,7 +980,7 @@ defmodule ChromEx.Collection do
   def update_documents!(%__MODULE__{} = collection, ids_or_opts, opts \\ []) do
     case update_documents(collection, ids_or_opts, opts) do
       :ok -> :ok
-      {:error, reason} -> raise "Failed to update documents: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "update documents"
     end
   end
 
@@ -503,6 +999,8 @@ defmodule ChromEx.Collection do
         ids: ["id1", "id2"],
         documents: ["Updated 1", "Updated 2"]
       )
+
+  Large updates are split into batches as described in `add/3`.
   """
   @spec update_documents(t(), [String.t()] | keyword(), keyword()) :: :ok | {:error, term()}
   def update_documents(%__MODULE__{} = collection, ids_or_opts, opts \\ []) do
@@ -517,35 +1015,95 @@ defmodule ChromEx.Collection do
   end
 
   defp update_documents_impl(%__MODULE__{} = collection, opts) do
-    resource = Client.get_resource()
-    ids = Keyword.get(opts, :ids) || raise ArgumentError, "ids are required"
-    embeddings = Keyword.get(opts, :embeddings)
+    write_batches(collection, :update, opts, &update_records(collection, &1))
+  end
+
+  defp update_records(%__MODULE__{} = collection, opts) do
+    resource = Client.get_resource(collection.client)
+    ids = Keyword.fetch!(opts, :ids)
+    embeddings = opts |> Keyword.get(:embeddings) |> pack_embeddings()
     metadatas = Keyword.get(opts, :metadatas)
     documents = Keyword.get(opts, :documents)
     uris = Keyword.get(opts, :uris)
 
-    metadatas_json =
-      if metadatas do
-        Enum.map(metadatas, fn
-          nil -> nil
-          meta when is_map(meta) -> Jason.encode!(meta)
-        end)
-      end
+    metadatas = normalize_metadatas(metadatas)
+
+    Native.call(
+      &Native.update(
+        resource,
+        &1,
+        collection.id,
+        ids,
+        embeddings,
+        metadatas,
+        documents,
+        uris,
+        collection.tenant,
+        collection.database
+      ),
+      opts
+    )
+  end
 
-    case Native.update(
-           resource,
-           collection.id,
-           ids,
-           embeddings,
-           metadatas_json,
-           documents,
-           uris,
-           collection.tenant,
-           collection.database
-         ) do
-      "ok" -> :ok
-      {:error, reason} -> {:error, reason}
-    end
+  @doc """
+  Embeds and writes a stream of records in concurrent batches
+
+  `records` is any `Enumerable` of maps or keyword lists with an `:id` and
+  any of `:document`, `:metadata`, `:embedding`, `:sparse_embedding` and
+  `:uri`. It is consumed lazily: at most `:max_concurrency` batches are
+  embedded and written at a time, so a `Stream` over millions of records is
+  never loaded at once. Within a batch, either every record has an
+  `:embedding` or none does.
+
+  Failed batches are reported and skipped. Returns `{:ok, summary}` with
+
+    * `:written`, `:failed` - records written and records in failed batches
+    * `:failures` - `%{ids: ids, reason: reason}` for each failed batch
+    * `:batches` - batches processed
+    * `:checkpoint` - id of the last record written with every record before
+      it written too; it stops before the first failed batch, so resuming
+      from it retries that batch
+
+  ## Options
+
+    * `:batch_size` - records per write (default 100)
+    * `:max_concurrency` - batches in flight (default
+      `System.schedulers_online/0`)
+    * `:mode` - `:upsert` (default), which makes re-ingesting idempotent,
+      or `:add`
+    * `:resume_from` - a checkpoint from an earlier run. Records up to and
+      including it are skipped; if no record has that id, nothing is
+      written and `{:error, {:validation, message}}` is returned.
+    * `:on_progress` - called after each batch, in order, with the summary
+      so far minus `:failures`
+    * `:halt_on_error` - stop at the first failed batch (default `false`)
+    * `:timeout`, `:truncation`, `:chunk_overlap`, `:batch_mode`,
+      `:sparse_key` - passed to each write
+
+  ## Telemetry
+
+    * `[:chromex, :ingest, :start]` and `[:chromex, :ingest, :stop]` (a
+      `:telemetry.span/3`), with the collection's `:collection` name and
+      `:collection_id` as metadata, plus the summary counters on stop
+    * `[:chromex, :ingest, :batch]` after each batch, measuring `:duration`
+      and `:count`, with `:first_id`, `:last_id` and the write's `:result`
+
+  ## Examples
+
+      records =
+        "corpus.jsonl"
+        |> File.stream!()
+        |> Stream.map(&Jason.decode!(&1, keys: :atoms))
+
+      {:ok, summary} =
+        ChromEx.Collection.ingest(collection, records,
+          resume_from: last_checkpoint,
+          on_progress: fn %{checkpoint: checkpoint} -> save_checkpoint(checkpoint) end
+        )
+  """
+  @spec ingest(t(), Enumerable.t(), keyword()) :: {:ok, map()} | {:error, term()}
+  def ingest(%__MODULE__{} = collection, records, opts \\ []) do
+    ChromEx.Ingest.run(collection, records, opts)
   end
 
   @doc """
@@ -555,7 +1113,7 @@ defmodule ChromEx.Collection do
   def upsert!(%__MODULE__{} = collection, ids_or_opts, opts \\ []) do
     case upsert(collection, ids_or_opts, opts) do
       :ok -> :ok
-      {:error, reason} -> raise "Failed to upsert documents: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "upsert documents"
     end
   end
 
@@ -574,6 +1132,11 @@ defmodule ChromEx.Collection do
         ids: ["id1", "id2"],
         documents: ["Doc 1", "Doc 2"]
       )
+
+  Accepts the `:truncation` and `:chunk_overlap` options of `add/3`. In
+  `:chunk` mode, an upserted document replaces the chunks it was stored as
+  before. Large upserts are split into batches as described in `add/3`;
+  undoing an `:atomic` upsert restores the records it overwrote.
   """
   @spec upsert(t(), [String.t()] | keyword(), keyword()) :: :ok | {:error, term()}
   def upsert(%__MODULE__{} = collection, ids_or_opts, opts \\ []) do
@@ -588,7 +1151,42 @@ defmodule ChromEx.Collection do
   end
 
   defp upsert_impl(%__MODULE__{} = collection, opts) do
-    resource = Client.get_resource()
+    with {:ok, records} <- check_truncation(collection, opts),
+         {:ok, replaced} <- replaced_records(collection, opts, records),
+         :ok <- write_batches(collection, :upsert, records, &upsert_records(collection, &1)) do
+      delete_replaced(collection, replaced, Keyword.take(opts, [:timeout]))
+    end
+  end
+
+  # In chunk mode an upserted document replaces however it was stored
+  # before: its earlier chunks, and its unchunked record if it is now split.
+  # They are looked up before the write and only deleted after it, so a
+  # failed upsert leaves the document as it was.
+  defp replaced_records(collection, opts, records) do
+    if Keyword.get(opts, :truncation) == :chunk and Keyword.get(opts, :documents) != nil and
+         Keyword.get(opts, :embeddings) == nil do
+      ids = Keyword.fetch!(opts, :ids)
+      written = Keyword.fetch!(records, :ids)
+
+      with {:ok, %{"ids" => chunks}} <-
+             get_documents(
+               collection,
+               [where: %{"parent_id" => %{"$in" => ids}}, include: []] ++
+                 Keyword.take(opts, [:timeout])
+             ) do
+        {:ok, (chunks ++ (ids -- written)) -- written}
+      end
+    else
+      {:ok, []}
+    end
+  end
+
+  defp delete_replaced(_collection, [], _opts), do: :ok
+  defp delete_replaced(collection, ids, opts),
+    do: delete_documents(collection, [ids: ids] ++ opts)
+
+  defp upsert_records(%__MODULE__{} = collection, opts) do
+    resource = Client.get_resource(collection.client)
     ids = Keyword.get(opts, :ids) || raise ArgumentError, "ids are required"
     documents = Keyword.get(opts, :documents)
     metadatas = Keyword.get(opts, :metadatas)
@@ -598,37 +1196,37 @@ defmodule ChromEx.Collection do
       case Keyword.get(opts, :embeddings) do
         nil ->
           if documents do
-            ChromEx.Embeddings.generate(documents)
+            embed_texts(collection, documents, :document)
           else
             raise ArgumentError, "Either embeddings or documents must be provided"
           end
 
         provided_embeddings ->
-          provided_embeddings
-      end
-
-    metadatas_json =
-      if metadatas do
-        Enum.map(metadatas, fn
-          nil -> nil
-          meta when is_map(meta) -> Jason.encode!(meta)
-          meta when is_list(meta) -> Jason.encode!(Map.new(meta))
-        end)
+          {:ok, pack_embeddings(provided_embeddings), nil}
       end
 
-    case Native.upsert(
-           resource,
-           collection.id,
-           ids,
-           embeddings,
-           metadatas_json,
-           documents,
-           uris,
-           collection.tenant,
-           collection.database
-         ) do
-      "ok" -> :ok
-      {:error, reason} -> {:error, reason}
+    metadatas =
+      metadatas
+      |> put_sparse_embeddings(Keyword.get(opts, :sparse_embeddings), opts)
+      |> normalize_metadatas()
+
+    with {:ok, embeddings, embedding_model} <- embeddings do
+      Native.call(
+        &Native.upsert(
+          resource,
+          &1,
+          collection.id,
+          ids,
+          embeddings,
+          embedding_model,
+          metadatas,
+          documents,
+          uris,
+          collection.tenant,
+          collection.database
+        ),
+        opts
+      )
     end
   end
 
@@ -639,7 +1237,7 @@ defmodule ChromEx.Collection do
   def delete_documents!(%__MODULE__{} = collection, opts \\ []) do
     case delete_documents(collection, opts) do
       :ok -> :ok
-      {:error, reason} -> raise "Failed to delete documents: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "delete documents"
     end
   end
 
@@ -648,49 +1246,433 @@ defmodule ChromEx.Collection do
   """
   @spec delete_documents(t(), keyword()) :: :ok | {:error, term()}
   def delete_documents(%__MODULE__{} = collection, opts \\ []) do
-    resource = Client.get_resource()
+    resource = Client.get_resource(collection.client)
     ids = Keyword.get(opts, :ids)
     where = Keyword.get(opts, :where)
     where_document = Keyword.get(opts, :where_document)
 
-    where_json = if where, do: Jason.encode!(where), else: nil
-    where_document_json = if where_document, do: Jason.encode!(where_document), else: nil
-
-    case Native.delete(
-           resource,
-           collection.id,
-           ids,
-           where_json,
-           where_document_json,
-           collection.tenant,
-           collection.database
-         ) do
-      "ok" -> :ok
-      {:error, reason} -> {:error, reason}
-    end
+    Native.call(
+      &Native.delete(
+        resource,
+        &1,
+        collection.id,
+        ids,
+        where,
+        where_document,
+        collection.tenant,
+        collection.database
+      ),
+      opts
+    )
   end
 
   @doc """
   Counts documents in a collection, raising on error
   """
-  @spec count!(t()) :: non_neg_integer()
-  def count!(%__MODULE__{} = collection) do
-    case count(collection) do
+  @spec count!(t(), keyword()) :: non_neg_integer()
+  def count!(%__MODULE__{} = collection, opts \\ []) do
+    case count(collection, opts) do
       {:ok, count} -> count
-      {:error, reason} -> raise "Failed to count documents: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "count documents"
     end
   end
 
   @doc """
   Counts documents in a collection
   """
-  @spec count(t()) :: {:ok, non_neg_integer()} | {:error, term()}
-  def count(%__MODULE__{} = collection) do
-    resource = Client.get_resource()
+  @spec count(t(), keyword()) :: {:ok, non_neg_integer()} | {:error, term()}
+  def count(%__MODULE__{} = collection, opts \\ []) do
+    resource = Client.get_resource(collection.client)
+
+    Native.call(
+      &Native.count(resource, &1, collection.id, collection.tenant, collection.database),
+      opts
+    )
+  end
+
+  defp from_data(collection_data, client, tenant, database) do
+    configuration = collection_data["configuration"]
+
+    # Left unset when the persisted function isn't registered; embedding
+    # then fails with `{:unknown_embedding_function, name}`.
+    embedding_function =
+      case EmbeddingFunction.from_configuration(persisted_embedding_function(configuration)) do
+        {:ok, embedding_function} -> embedding_function
+        {:error, _reason} -> nil
+      end
+
+    %__MODULE__{
+      client: client,
+      id: collection_data["id"],
+      name: collection_data["name"],
+      tenant: Map.get(collection_data, "tenant", tenant),
+      database: Map.get(collection_data, "database", database),
+      metadata: collection_data["metadata"],
+      configuration: configuration,
+      embedding_function: embedding_function
+    }
+  end
+
+  defp persisted_embedding_function(%{"embedding_function" => entry}), do: entry
+  defp persisted_embedding_function(_configuration), do: nil
+
+  defp put_embedding_function(collection, nil), do: {:ok, collection}
+
+  defp put_embedding_function(collection, embedding_function) do
+    given = EmbeddingFunction.to_configuration(embedding_function)
+
+    case persisted_embedding_function(collection.configuration) do
+      %{"type" => "known"} = persisted when persisted != given ->
+        {:error, {:embedding_function_mismatch, persisted, given}}
+
+      _persisted ->
+        {:ok, %{collection | embedding_function: embedding_function}}
+    end
+  end
+
+  defp embedding_function(%__MODULE__{embedding_function: nil, configuration: configuration}),
+    do: EmbeddingFunction.from_configuration(persisted_embedding_function(configuration))
+
+  defp embedding_function(%__MODULE__{embedding_function: embedding_function}),
+    do: {:ok, embedding_function}
+
+  # Runs `write` on batches of at most the client's max batch size, see
+  # "Batching" in add/3
+  defp write_batches(%__MODULE__{} = collection, op, opts, write) do
+    ids = Keyword.get(opts, :ids) || raise ArgumentError, "ids are required"
+    size = Keyword.get_lazy(opts, :batch_size, fn -> Client.max_batch_size(collection.client) end)
+
+    if length(ids) <= size do
+      write.(opts)
+    else
+      mode = Keyword.get(opts, :batch_mode, :atomic)
+      # Set once a batch fails, so that atomic writes start no further batches
+      failed = :atomics.new(1, [])
+
+      results =
+        opts
+        |> split_batches(size)
+        |> Task.async_stream(
+          fn batch ->
+            if mode == :atomic and :atomics.get(failed, 1) == 1 do
+              :skipped
+            else
+              with {:ok, snapshot} <- snapshot(collection, op, mode, batch),
+                   :ok <- write.(batch) do
+                {:ok, batch, snapshot}
+              else
+                {:error, reason} ->
+                  :atomics.put(failed, 1, 1)
+                  {:error, batch, reason}
+              end
+            end
+          end,
+          max_concurrency: Keyword.get(opts, :max_concurrency, 1),
+          timeout: :infinity
+        )
+        |> Enum.map(fn {:ok, result} -> result end)
+
+      failures =
+        for {{:error, batch, reason}, index} <- Enum.with_index(results),
+            do: %{batch: index, ids: Keyword.fetch!(batch, :ids), reason: reason}
+
+      cond do
+        failures == [] ->
+          :ok
+
+        mode == :best_effort ->
+          {:error, {:batches_failed, failures}}
+
+        true ->
+          case rollback(collection, op, results, Keyword.take(opts, [:timeout])) do
+            :ok -> {:error, {:batches_failed, failures}}
+            {:error, reason} -> {:error, {:rollback_failed, failures, reason}}
+          end
+      end
+    end
+  end
+
+  defp split_batches(opts, size) do
+    count = opts |> Keyword.fetch!(:ids) |> length()
+    starts = Enum.to_list(0..(count - 1)//size)
+
+    Enum.reduce(@per_record_keys, List.duplicate(opts, length(starts)), fn key, batches ->
+      case Keyword.get(opts, key) do
+        nil -> batches
+        values ->
+          Enum.zip_with(batches, chunk_records(values, starts, size), &Keyword.put(&1, key, &2))
+      end
+    end)
+  end
+
+  defp chunk_records(values, _starts, size) when is_list(values),
+    do: Enum.chunk_every(values, size)
+
+  defp chunk_records({binary, rows, dimension}, starts, size) do
+    Enum.map(starts, fn start ->
+      count = min(size, rows - start)
+      {binary_part(binary, start * dimension * 4, count * dimension * 4), count, dimension}
+    end)
+  end
+
+  defp chunk_records(tensor, starts, size) when is_struct(tensor, Nx.Tensor) do
+    rows = elem(Nx.shape(tensor), 0)
+    Enum.map(starts, &Nx.slice_along_axis(tensor, &1, min(size, rows - &1), axis: 0))
+  end
+
+  # Which of an atomic add's ids already exist: Chroma leaves those alone,
+  # so undoing the add must too
+  defp snapshot(collection, :add, :atomic, batch) do
+    get_documents(
+      collection,
+      [ids: Keyword.fetch!(batch, :ids), include: []] ++ Keyword.take(batch, [:timeout])
+    )
+  end
+
+  # What an atomic upsert or update overwrites, so it can be put back
+  defp snapshot(collection, op, :atomic, batch) when op in [:upsert, :update] do
+    get_documents(
+      collection,
+      [
+        ids: Keyword.fetch!(batch, :ids),
+        include: ["embeddings", "metadatas", "documents", "uris"]
+      ] ++ Keyword.take(batch, [:timeout])
+    )
+  end
+
+  defp snapshot(_collection, _op, _mode, _batch), do: {:ok, nil}
 
-    case Native.count(resource, collection.id, collection.tenant, collection.database) do
-      count when is_integer(count) -> {:ok, count}
+  defp rollback(collection, op, results, opts) do
+    Enum.reduce_while(results, :ok, fn
+      {:ok, batch, snapshot}, :ok ->
+        case undo(collection, op, Keyword.fetch!(batch, :ids), snapshot, opts) do
+          :ok -> {:cont, :ok}
+          {:error, reason} -> {:halt, {:error, reason}}
+        end
+
+      _result, :ok ->
+        {:cont, :ok}
+    end)
+  end
+
+  defp undo(collection, :add, ids, %{"ids" => existing}, opts),
+    do: delete_written(collection, ids -- existing, opts)
+
+  # Upserting the snapshot back would merge metadata and keep the new
+  # documents where the old ones were nil, so the written records are
+  # deleted and the overwritten ones added back as they were
+  defp undo(collection, _op, ids, %{"ids" => existing} = snapshot, opts) do
+    with :ok <- delete_written(collection, ids, opts) do
+      if existing == [] do
+        :ok
+      else
+        add_records(
+          collection,
+          [
+            ids: existing,
+            embeddings: snapshot["embeddings"],
+            metadatas: snapshot["metadatas"],
+            documents: snapshot["documents"],
+            uris: snapshot["uris"]
+          ] ++ opts
+        )
+      end
+    end
+  end
+
+  defp delete_written(_collection, [], _opts), do: :ok
+  defp delete_written(collection, ids, opts), do: delete_documents(collection, [ids: ids] ++ opts)
+
+  # Applies the `:truncation` option to documents the collection's model will
+  # embed, returning the records to write. In `:allow` mode documents are
+  # only tokenized when someone listens for @truncated_event.
+  defp check_truncation(%__MODULE__{} = collection, opts) do
+    mode = Keyword.get(opts, :truncation, :allow)
+    documents = Keyword.get(opts, :documents)
+    embedded? = is_list(documents) and is_nil(Keyword.get(opts, :embeddings))
+    checked? = mode != :allow or :telemetry.list_handlers(@truncated_event) != []
+
+    with true <- checked? and embedded?,
+         {:ok, embedding_function} <- embedding_function(collection),
+         model when is_binary(model) <- EmbeddingFunction.model(embedding_function),
+         {:ok, {capacity, offsets}} <-
+           Embeddings.token_offsets(documents, model, collection.client) do
+      truncated = Chunking.truncated(Keyword.fetch!(opts, :ids), offsets, capacity)
+
+      if mode in [:allow, :warn] and truncated != [] do
+        :telemetry.execute(
+          @truncated_event,
+          %{count: length(truncated)},
+          %{collection: collection.name, collection_id: collection.id, documents: truncated}
+        )
+      end
+
+      case {mode, truncated} do
+        {_mode, []} ->
+          {:ok, opts}
+
+        {:allow, _truncated} ->
+          {:ok, opts}
+
+        {:warn, truncated} ->
+          Logger.warning(
+            "Truncating #{length(truncated)} documents longer than #{capacity} tokens: " <>
+              Enum.map_join(truncated, ", ", &inspect(&1.id))
+          )
+
+          {:ok, opts}
+
+        {:error, truncated} ->
+          {:error, {:truncated, truncated}}
+
+        {:chunk, _truncated} ->
+          {:ok, Chunking.split(opts, offsets, capacity, Keyword.get(opts, :chunk_overlap, 32))}
+      end
+    else
       {:error, reason} -> {:error, reason}
+      _ -> {:ok, opts}
+    end
+  end
+
+  # Embeddings for `texts` when the caller gave none, as
+  # `{:ok, embeddings, model}`. Documents go through the client's
+  # EmbeddingCache if it has one. Otherwise, with the native backend,
+  # functions that run a registered model leave it to the NIF, which embeds
+  # the texts with `model` in the same call as the write or query; otherwise
+  # `model` is nil.
+  defp embed_texts(%__MODULE__{} = collection, texts, input) do
+    with {:ok, embedding_function} <- embedding_function(collection) do
+      cache = if input == :document, do: Client.embedding_cache(collection.client)
+
+      case {EmbeddingFunction.model(embedding_function), ChromEx.Embeddings.backend()} do
+        _ when cache != nil ->
+          generate = &generate_embeddings(collection, embedding_function, &1, input)
+          {:ok, EmbeddingCache.generate(cache, embedding_function, texts, generate), nil}
+
+        {model, :native} when is_binary(model) ->
+          with :ok <- Client.ensure_embedder(collection.client, model), do: {:ok, nil, model}
+
+        _ ->
+          {:ok, generate_embeddings(collection, embedding_function, texts, input), nil}
+      end
+    end
+  end
+
+  # Functions running a ChromEx.Embeddings.Model embed on the collection's
+  # client, which is where the :native backend loads the model
+  defp generate_embeddings(collection, embedding_function, texts, input) do
+    case EmbeddingFunction.model(embedding_function) do
+      model when is_binary(model) ->
+        ChromEx.Embeddings.generate(texts, model: model, input: input, client: collection.client)
+
+      nil ->
+        EmbeddingFunction.generate(embedding_function, texts, input)
+    end
+  end
+
+  # Tensors cross the NIF boundary as `{binary, rows, dimension}` with
+  # little-endian f32 values; `Nx.to_binary/1` and `Nx.from_binary/2` use the
+  # host byte order.
+  defp pack_embeddings(tensor) when is_struct(tensor, Nx.Tensor) do
+    {rows, dimension} =
+      case Nx.shape(tensor) do
+        {dimension} ->
+          {1, dimension}
+
+        {rows, dimension} ->
+          {rows, dimension}
+
+        shape ->
+          raise ArgumentError,
+                "expected a rank 1 or 2 embeddings tensor, got shape #{inspect(shape)}"
+      end
+
+    {tensor |> Nx.as_type({:f, 32}) |> Nx.to_binary() |> swap_to_little(), rows, dimension}
+  end
+
+  defp pack_embeddings(embeddings), do: embeddings
+
+  defp maybe_tensor_embeddings({:ok, results}, true) do
+    {:ok, Map.update(results, "embeddings", nil, &unpack_embeddings/1)}
+  end
+
+  defp maybe_tensor_embeddings(result, _as_tensor), do: result
+
+  # `get` returns one packed block; `query` returns one per query text. Nx
+  # has no empty tensors, so a block without records comes back as `[]`.
+  defp unpack_embeddings({_binary, rows, dimension}) when rows == 0 or dimension == 0, do: []
+
+  defp unpack_embeddings({binary, rows, dimension}) do
+    binary |> swap_to_little() |> Nx.from_binary({:f, 32}) |> Nx.reshape({rows, dimension})
+  end
+
+  defp unpack_embeddings(per_query) when is_list(per_query) do
+    Enum.map(per_query, fn
+      {_binary, _rows, _dimension} = packed -> unpack_embeddings(packed)
+      embeddings -> embeddings
+    end)
+  end
+
+  defp unpack_embeddings(nil), do: nil
+
+  # Converts 32-bit values between the host byte order and little-endian,
+  # either way.
+  if <<1::native-32>> == <<1::little-32>> do
+    defp swap_to_little(binary), do: binary
+  else
+    defp swap_to_little(binary) do
+      for <<value::native-32 <- binary>>, into: <<>>, do: <<value::little-32>>
     end
   end
+
+  defp schema(schema, nil), do: schema
+
+  defp schema(schema, sparse_key) do
+    index = %{
+      "sparse_vector" => %{"sparse_vector_index" => %{"enabled" => true, "config" => %{}}}
+    }
+
+    (schema || %{"defaults" => %{}, "keys" => %{}})
+    |> Map.put_new("defaults", %{})
+    |> Map.update("keys", %{sparse_key => index}, &Map.put(&1, sparse_key, index))
+  end
+
+  defp put_sparse_embeddings(metadatas, nil, _opts), do: metadatas
+
+  defp put_sparse_embeddings(metadatas, sparse_embeddings, opts) do
+    key = Keyword.get(opts, :sparse_key, @default_sparse_key)
+
+    (metadatas || List.duplicate(nil, length(sparse_embeddings)))
+    |> Enum.zip(sparse_embeddings)
+    |> Enum.map(fn
+      {metadata, nil} -> metadata
+      {metadata, sparse} -> Map.put(Map.new(metadata || %{}), key, sparse)
+    end)
+  end
+
+  defp normalize_metadatas(nil), do: nil
+
+  defp normalize_metadatas(metadatas) do
+    Enum.map(metadatas, fn
+      nil -> nil
+      meta -> normalize_metadata(meta)
+    end)
+  end
+
+  # Metadata is passed to the NIF as a map of scalars; keyword lists and
+  # date/time values are converted the same way Jason used to encode them.
+  defp normalize_metadata(meta) when is_list(meta), do: normalize_metadata(Map.new(meta))
+
+  defp normalize_metadata(meta) when is_map(meta) do
+    Map.new(meta, fn {key, value} -> {key, normalize_metadata_value(value)} end)
+  end
+
+  defp normalize_metadata_value(%SparseVector{} = value),
+    do: SparseVector.to_native(value)
+
+  defp normalize_metadata_value(%DateTime{} = value), do: DateTime.to_iso8601(value)
+  defp normalize_metadata_value(%NaiveDateTime{} = value), do: NaiveDateTime.to_iso8601(value)
+  defp normalize_metadata_value(%Date{} = value), do: Date.to_iso8601(value)
+  defp normalize_metadata_value(%Time{} = value), do: Time.to_iso8601(value)
+  defp normalize_metadata_value(value), do: value
 end
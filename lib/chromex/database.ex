@@ -1,13 +1,17 @@
 defmodule ChromEx.Database do
   @moduledoc """
   ChromEx database operations for managing databases within tenants
+
+  All functions accept a `:client` option naming the `ChromEx.Client` to use
+  (defaults to `ChromEx.Client`).
   """
 
   alias ChromEx.{Client, Native}
 
-  defstruct [:id, :name, :tenant]
+  defstruct [:id, :name, :tenant, client: Client]
 
   @type t :: %__MODULE__{
+          client: GenServer.server(),
           id: String.t(),
           name: String.t(),
           tenant: String.t()
@@ -20,15 +24,15 @@ defmodule ChromEx.Database do
   """
   @spec create(String.t(), keyword()) :: {:ok, t()} | {:error, term()}
   def create(name, opts \\ []) do
-    resource = Client.get_resource()
+    client = Keyword.get(opts, :client, Client)
+    resource = Client.get_resource(client)
     tenant = Keyword.get(opts, :tenant, @default_tenant)
 
-    case Native.create_database(resource, name, tenant) do
-      json when is_binary(json) ->
-        database_data = Jason.decode!(json)
-
+    case Native.call(&Native.create_database(resource, &1, name, tenant), opts) do
+      {:ok, database_data} ->
         {:ok,
          %__MODULE__{
+           client: client,
            id: database_data["id"],
            name: database_data["name"],
            tenant: Map.get(database_data, "tenant", tenant)
@@ -44,15 +48,15 @@ defmodule ChromEx.Database do
   """
   @spec get(String.t(), keyword()) :: {:ok, t()} | {:error, term()}
   def get(name, opts \\ []) do
-    resource = Client.get_resource()
+    client = Keyword.get(opts, :client, Client)
+    resource = Client.get_resource(client)
     tenant = Keyword.get(opts, :tenant, @default_tenant)
 
-    case Native.get_database(resource, name, tenant) do
-      json when is_binary(json) ->
-        database_data = Jason.decode!(json)
-
+    case Native.call(&Native.get_database(resource, &1, name, tenant), opts) do
+      {:ok, database_data} ->
         {:ok,
          %__MODULE__{
+           client: client,
            id: database_data["id"],
            name: database_data["name"],
            tenant: Map.get(database_data, "tenant", tenant)
@@ -68,11 +72,12 @@ defmodule ChromEx.Database do
   """
   @spec delete(String.t(), keyword()) :: :ok | {:error, term()}
   def delete(name, opts \\ []) do
-    resource = Client.get_resource()
+    client = Keyword.get(opts, :client, Client)
+    resource = Client.get_resource(client)
     tenant = Keyword.get(opts, :tenant, @default_tenant)
 
-    case Native.delete_database(resource, name, tenant) do
-      "ok" -> :ok
+    case Native.call(&Native.delete_database(resource, &1, name, tenant), opts) do
+      :ok -> :ok
       {:error, reason} -> {:error, reason}
     end
   end
@@ -82,17 +87,18 @@ defmodule ChromEx.Database do
   """
   @spec list(keyword()) :: {:ok, [t()]} | {:error, term()}
   def list(opts \\ []) do
-    resource = Client.get_resource()
+    client = Keyword.get(opts, :client, Client)
+    resource = Client.get_resource(client)
     limit = Keyword.get(opts, :limit)
     offset = Keyword.get(opts, :offset)
     tenant = Keyword.get(opts, :tenant, @default_tenant)
 
-    case Native.list_databases(resource, limit, offset, tenant) do
-      json when is_binary(json) ->
+    case Native.call(&Native.list_databases(resource, &1, limit, offset, tenant), opts) do
+      {:ok, databases} ->
         databases =
-          Jason.decode!(json)
-          |> Enum.map(fn database_data ->
+          Enum.map(databases, fn database_data ->
             %__MODULE__{
+              client: client,
               id: database_data["id"],
               name: database_data["name"],
               tenant: Map.get(database_data, "tenant", tenant)
@@ -113,7 +119,7 @@ defmodule ChromEx.Database do
   def create!(name, opts \\ []) do
     case create(name, opts) do
       {:ok, database} -> database
-      {:error, reason} -> raise "Failed to create database: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "create database"
     end
   end
 
@@ -124,7 +130,7 @@ defmodule ChromEx.Database do
   def get!(name, opts \\ []) do
     case get(name, opts) do
       {:ok, database} -> database
-      {:error, reason} -> raise "Failed to get database: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "get database"
     end
   end
 
@@ -135,7 +141,7 @@ defmodule ChromEx.Database do
   def delete!(name, opts \\ []) do
     case delete(name, opts) do
       :ok -> :ok
-      {:error, reason} -> raise "Failed to delete database: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "delete database"
     end
   end
 
@@ -146,7 +152,7 @@ defmodule ChromEx.Database do
   def list!(opts \\ []) do
     case list(opts) do
       {:ok, databases} -> databases
-      {:error, reason} -> raise "Failed to list databases: #{inspect(reason)}"
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "list databases"
     end
   end
 end
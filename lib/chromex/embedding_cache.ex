@@ -0,0 +1,299 @@
+defmodule ChromEx.EmbeddingCache do
+  @moduledoc """
+  Persistent cache of document embeddings, keyed by model and the SHA-256 of
+  the text.
+
+  Enable it per client with the `:embedding_cache` option, which requires a
+  `:persist_path`:
+
+      config :chromex, persist_path: "./chroma_data", embedding_cache: [max_entries: 500_000]
+
+  The cache is a DETS table, `embedding_cache.dets`, next to `chroma.db`.
+  `add` and `upsert` look documents up in it before running the collection's
+  embedding function, which then only sees the texts not cached yet, so
+  re-ingesting a mostly unchanged corpus skips most model runs. Query texts
+  are not cached.
+
+  Entries are keyed by the model the embedding function runs, or by its name
+  and configuration when it doesn't run a registered model, so collections
+  using the same model share entries. Embeddings are stored as 32-bit floats,
+  the precision Chroma keeps them at.
+
+  When entries were last used is kept in memory, ordered so that eviction
+  takes the oldest without scanning the table, and written to the file when
+  the client closes the cache, so hits cost no disk writes.
+
+  ## Options
+
+    * `:max_entries` - once the cache holds more entries than this, the least
+      recently used are evicted until it is back to 90% of it (default
+      `500_000`). A DETS file cannot grow past 2 GB, so for large embeddings
+      the limit is lowered to the entries that fit in 1.5 GB: about 190,000
+      of 1024 dimensions. `stats/1` reports the limit in effect.
+  """
+
+  alias ChromEx.{Client, EmbeddingFunction}
+
+  defstruct [:table, :used, :recency, :counters, :max_entries]
+
+  @type t :: %__MODULE__{
+          table: term(),
+          used: :ets.tid(),
+          recency: :ets.tid(),
+          counters: :counters.counters_ref(),
+          max_entries: pos_integer()
+        }
+
+  @type stats :: %{
+          hits: non_neg_integer(),
+          misses: non_neg_integer(),
+          hit_rate: float(),
+          evictions: non_neg_integer(),
+          size: non_neg_integer(),
+          max_entries: pos_integer()
+        }
+
+  @file_name "embedding_cache.dets"
+  @default_max_entries 500_000
+  # What the file may take up, leaving DETS's 2 GB limit room for
+  # fragmentation
+  @file_budget 1_536 * 1_024 * 1_024
+
+  @hits 1
+  @misses 2
+  @evictions 3
+  # The largest entry written so far, in bytes on disk
+  @entry_bytes 4
+
+  @doc """
+  Returns hit and eviction counters for the client's cache, counted since the
+  client started, along with its current size. Returns `nil` when the client
+  has no cache.
+  """
+  @spec stats(GenServer.server()) :: stats() | nil
+  def stats(client \\ Client) do
+    with %__MODULE__{} = cache <- Client.embedding_cache(client) do
+      hits = :counters.get(cache.counters, @hits)
+      misses = :counters.get(cache.counters, @misses)
+
+      %{
+        hits: hits,
+        misses: misses,
+        hit_rate: if(hits + misses > 0, do: hits / (hits + misses), else: 0.0),
+        evictions: :counters.get(cache.counters, @evictions),
+        size: :dets.info(cache.table, :size),
+        max_entries: max_entries(cache)
+      }
+    end
+  end
+
+  @doc """
+  Removes every entry from the client's cache
+  """
+  @spec clear(GenServer.server()) :: :ok
+  def clear(client \\ Client) do
+    case Client.embedding_cache(client) do
+      nil ->
+        :ok
+
+      cache ->
+        :ets.delete_all_objects(cache.used)
+        :ets.delete_all_objects(cache.recency)
+        :dets.delete_all_objects(cache.table)
+    end
+  end
+
+  @doc false
+  @spec open(String.t(), keyword()) :: {:ok, t()} | {:error, term()}
+  def open(persist_path, opts) do
+    table = {__MODULE__, Path.expand(persist_path)}
+    file = persist_path |> Path.join(@file_name) |> to_charlist()
+
+    with :ok <- File.mkdir_p(persist_path),
+         {:ok, ^table} <- :dets.open_file(table, file: file, type: :set) do
+      cache = %__MODULE__{
+        table: table,
+        # key => when it was last used
+        used: :ets.new(__MODULE__, [:set, :public, write_concurrency: true]),
+        # {last_used, key}, oldest first; rows whose time differs from `used`
+        # are left over from concurrent hits and skipped by evict/1
+        recency: :ets.new(__MODULE__, [:ordered_set, :public, write_concurrency: true]),
+        counters: :counters.new(4, [:write_concurrency]),
+        max_entries: Keyword.get(opts, :max_entries, @default_max_entries)
+      }
+
+      table
+      |> :dets.select([{{:"$1", :_, :"$2"}, [], [{{:"$1", :"$2"}}]}])
+      |> Enum.each(fn {key, used_at} -> touch(cache, key, used_at) end)
+
+      {:ok, cache}
+    end
+  end
+
+  @doc false
+  # The cache's file in a client's persist_path
+  @spec file_name() :: String.t()
+  def file_name, do: @file_name
+
+  @doc false
+  # Writes when entries were last used to the file before closing it
+  @spec close(t()) :: :ok | {:error, term()}
+  def close(%__MODULE__{table: table, used: used, recency: recency}) do
+    if :ets.info(used, :size) != :undefined do
+      entries =
+        for {key, used_at} <- :ets.tab2list(used),
+            [{^key, embedding, stored_at}] <- [:dets.lookup(table, key)],
+            stored_at != used_at,
+            do: {key, embedding, used_at}
+
+      :ok = :dets.insert(table, entries)
+      :ets.delete(used)
+      :ets.delete(recency)
+    end
+
+    :dets.close(table)
+  end
+
+  @doc false
+  # Embeds `texts`, calling `generate` only with the ones not cached yet
+  @spec generate(t(), EmbeddingFunction.t(), [String.t()], ([String.t()] -> [[float()]])) ::
+          [[float()]]
+  def generate(%__MODULE__{table: table} = cache, embedding_function, texts, generate) do
+    model = model_id(embedding_function)
+    keys = Enum.map(texts, &{model, :crypto.hash(:sha256, &1)})
+    now = System.system_time(:millisecond)
+
+    cached =
+      Enum.map(keys, fn key ->
+        case :dets.lookup(table, key) do
+          [{^key, embedding, _used_at}] -> decode(embedding)
+          [] -> nil
+        end
+      end)
+
+    misses =
+      keys
+      |> Enum.zip(texts)
+      |> Enum.zip(cached)
+      |> Enum.flat_map(fn
+        {miss, nil} -> [miss]
+        {_hit, _embedding} -> []
+      end)
+      |> Enum.uniq_by(fn {key, _text} -> key end)
+
+    generated =
+      case misses do
+        [] ->
+          %{}
+
+        misses ->
+          {miss_keys, miss_texts} = Enum.unzip(misses)
+          Map.new(Enum.zip(miss_keys, generate.(miss_texts)))
+      end
+
+    embeddings =
+      Enum.zip_with(keys, cached, fn key, embedding ->
+        embedding || Map.fetch!(generated, key)
+      end)
+
+    # Only new entries are written; hits just record when they were used
+    entries =
+      for {key, embedding} <- generated, do: {key, encode(embedding), now}
+
+    :ok = :dets.insert(table, entries)
+    Enum.each(Enum.uniq(keys), &touch(cache, &1, now))
+    record_entry_bytes(cache, entries)
+
+    :counters.add(cache.counters, @hits, length(keys) - map_size(generated))
+    :counters.add(cache.counters, @misses, map_size(generated))
+    evict(cache)
+
+    embeddings
+  end
+
+  defp model_id(embedding_function) do
+    case EmbeddingFunction.model(embedding_function) do
+      nil ->
+        %{"name" => name, "config" => config} =
+          EmbeddingFunction.to_configuration(embedding_function)
+
+        {name, :crypto.hash(:sha256, :erlang.term_to_binary(config, [:deterministic]))}
+
+      model ->
+        model
+    end
+  end
+
+  # DETS stores each object in a slot of the next power of two at or above
+  # its size
+  defp record_entry_bytes(_cache, []), do: :ok
+
+  defp record_entry_bytes(cache, entries) do
+    bytes =
+      entries
+      |> Enum.map(&:erlang.external_size/1)
+      |> Enum.max()
+      |> Kernel.+(8)
+      |> then(&(2 ** ceil(:math.log2(&1))))
+
+    if bytes > :counters.get(cache.counters, @entry_bytes),
+      do: :counters.put(cache.counters, @entry_bytes, bytes)
+
+    :ok
+  end
+
+  defp max_entries(%__MODULE__{counters: counters, max_entries: max_entries}) do
+    case :counters.get(counters, @entry_bytes) do
+      0 -> max_entries
+      bytes -> min(max_entries, div(@file_budget, bytes))
+    end
+  end
+
+  defp touch(%__MODULE__{used: used, recency: recency}, key, used_at) do
+    case :ets.lookup(used, key) do
+      [{^key, ^used_at}] ->
+        :ok
+
+      previous ->
+        for {^key, last_used} <- previous, do: :ets.delete(recency, {last_used, key})
+        :ets.insert(recency, {{used_at, key}})
+        :ets.insert(used, {key, used_at})
+    end
+  end
+
+  defp evict(%__MODULE__{table: table} = cache) do
+    size = :dets.info(table, :size)
+    max_entries = max_entries(cache)
+
+    if size > max_entries do
+      evicted = evict_oldest(cache, size - div(max_entries * 9, 10), 0)
+      :counters.add(cache.counters, @evictions, evicted)
+    end
+
+    :ok
+  end
+
+  defp evict_oldest(_cache, 0, evicted), do: evicted
+
+  defp evict_oldest(%__MODULE__{used: used, recency: recency} = cache, count, evicted) do
+    case :ets.first(recency) do
+      :"$end_of_table" ->
+        evicted
+
+      {used_at, key} = oldest ->
+        :ets.delete(recency, oldest)
+
+        # A row left behind by a concurrent hit is dropped without evicting
+        if :ets.lookup(used, key) == [{key, used_at}] do
+          :dets.delete(cache.table, key)
+          :ets.delete(used, key)
+          evict_oldest(cache, count - 1, evicted + 1)
+        else
+          evict_oldest(cache, count, evicted)
+        end
+    end
+  end
+
+  defp encode(embedding), do: for(value <- embedding, into: <<>>, do: <<value::float-32-little>>)
+  defp decode(binary), do: for(<<value::float-32-little <- binary>>, do: value)
+end
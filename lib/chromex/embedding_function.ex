@@ -0,0 +1,138 @@
+defmodule ChromEx.EmbeddingFunction do
+  @moduledoc """
+  Behaviour for the functions collections embed documents and query texts with
+
+  A collection is created with an embedding function, given as a module or as
+  `{module, config}`. Its name and config are stored in the collection
+  configuration under `"embedding_function"`, in the same form as Python
+  Chroma:
+
+      %{"type" => "known", "name" => "my_model", "config" => %{"size" => "small"}}
+
+  Opening the collection again uses the same function, found by name among
+  `ChromEx.EmbeddingFunction.Default`, `ChromEx.EmbeddingFunction.Onnx` and
+  the modules listed in the `:embedding_functions` application env. Passing
+  a different function to `ChromEx.Collection.get/2` or
+  `ChromEx.Collection.create/2` returns
+  `{:error, {:embedding_function_mismatch, persisted, given}}`.
+
+  ## Examples
+
+      defmodule MyApp.OpenAIEmbeddings do
+        @behaviour ChromEx.EmbeddingFunction
+
+        @impl true
+        def name, do: "openai"
+
+        @impl true
+        def default_config, do: %{"model_name" => "text-embedding-3-small"}
+
+        @impl true
+        def generate(texts, %{"model_name" => model}), do: MyApp.OpenAI.embed(texts, model)
+      end
+
+      # config/config.exs
+      config :chromex, embedding_functions: [MyApp.OpenAIEmbeddings]
+
+      ChromEx.Collection.create("docs", embedding_function: MyApp.OpenAIEmbeddings)
+  """
+
+  @typedoc "A module implementing this behaviour and its config"
+  @type t :: {module(), map()}
+
+  @doc """
+  Name the function is persisted and looked up under
+  """
+  @callback name() :: String.t()
+
+  @doc """
+  Embeds `texts`, returning one embedding per text
+  """
+  @callback generate(texts :: [String.t()], config :: map()) :: [[float()]]
+
+  @doc """
+  Embeds query texts, for models that embed queries differently from
+  documents. Defaults to `c:generate/2`.
+  """
+  @callback generate_queries(texts :: [String.t()], config :: map()) :: [[float()]]
+
+  @doc """
+  Config used when the function is given as a bare module
+  """
+  @callback default_config() :: map()
+
+  @doc """
+  Name of the `ChromEx.Embeddings.Model` the function runs, if any. With the
+  `:native` embeddings backend, such functions embed inside the same native
+  call as the write or query.
+  """
+  @callback model(config :: map()) :: String.t() | nil
+
+  @optional_callbacks generate_queries: 2, default_config: 0, model: 1
+
+  @doc """
+  Normalizes a module or `{module, config}` into `{module, config}`, with the
+  config in the JSON form it is persisted in
+  """
+  @spec new(module() | {module(), map() | keyword()}) :: t()
+  def new({module, config}) when is_atom(module) do
+    {module, config |> Map.new() |> Jason.encode!() |> Jason.decode!()}
+  end
+
+  def new(module) when is_atom(module) do
+    if exported?(module, :default_config, 0),
+      do: new({module, module.default_config()}),
+      else: {module, %{}}
+  end
+
+  @doc """
+  Embeds `texts`, which are documents or, with `:query`, query texts
+  """
+  @spec generate(t(), [String.t()], :document | :query) :: [[float()]]
+  def generate({module, config}, texts, input \\ :document) do
+    if input == :query and exported?(module, :generate_queries, 2),
+      do: module.generate_queries(texts, config),
+      else: module.generate(texts, config)
+  end
+
+  @doc """
+  Name of the `ChromEx.Embeddings.Model` `function` runs, or `nil`
+  """
+  @spec model(t()) :: String.t() | nil
+  def model({module, config}) do
+    if exported?(module, :model, 1), do: module.model(config)
+  end
+
+  @doc """
+  The collection configuration entry persisting `function`
+  """
+  @spec to_configuration(t()) :: map()
+  def to_configuration({module, config}) do
+    %{"type" => "known", "name" => module.name(), "config" => config}
+  end
+
+  @doc """
+  Finds the function persisted in a collection configuration entry.
+
+  Collections without one, or with a legacy entry, use
+  `ChromEx.EmbeddingFunction.Default`.
+  """
+  @spec from_configuration(map() | nil) :: {:ok, t()} | {:error, term()}
+  def from_configuration(%{"type" => "known", "name" => name} = entry) do
+    case Enum.find(registered(), &(&1.name() == name)) do
+      nil -> {:error, {:unknown_embedding_function, name}}
+      module -> {:ok, {module, entry["config"] || %{}}}
+    end
+  end
+
+  def from_configuration(_entry), do: {:ok, new(ChromEx.EmbeddingFunction.Default)}
+
+  defp registered do
+    [ChromEx.EmbeddingFunction.Default, ChromEx.EmbeddingFunction.Onnx] ++
+      Application.get_env(:chromex, :embedding_functions, [])
+  end
+
+  defp exported?(module, function, arity) do
+    Code.ensure_loaded?(module) and function_exported?(module, function, arity)
+  end
+end
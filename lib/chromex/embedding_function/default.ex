@@ -0,0 +1,22 @@
+defmodule ChromEx.EmbeddingFunction.Default do
+  @moduledoc """
+  all-MiniLM-L6-v2, the embedding function collections use unless given
+  another. Persisted as `"default"`, the name Python Chroma uses for it.
+
+  Embeddings come from `ChromEx.Embeddings.generate/2`. With the `:native`
+  backend, `add`, `upsert` and `query` instead embed inside the native call.
+  """
+
+  @behaviour ChromEx.EmbeddingFunction
+
+  alias ChromEx.Embeddings.Model
+
+  @impl true
+  def name, do: "default"
+
+  @impl true
+  def generate(texts, _config), do: ChromEx.Embeddings.generate(texts)
+
+  @impl true
+  def model(_config), do: Model.default()
+end
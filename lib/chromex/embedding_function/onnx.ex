@@ -0,0 +1,34 @@
+defmodule ChromEx.EmbeddingFunction.Onnx do
+  @moduledoc """
+  Embeds with a model from the `ChromEx.Embeddings.Model` registry, such as
+  a bge, e5 or gte export, applying its query and document prefixes.
+
+      ChromEx.Collection.create("docs",
+        embedding_function: {ChromEx.EmbeddingFunction.Onnx, %{"model" => "bge-small-en-v1.5"}}
+      )
+
+  Persisted as `"chromex_onnx"` with the model name as its config. The model
+  must be registered under the same name wherever the collection is opened.
+  """
+
+  @behaviour ChromEx.EmbeddingFunction
+
+  alias ChromEx.Embeddings.Model
+
+  @impl true
+  def name, do: "chromex_onnx"
+
+  @impl true
+  def default_config, do: %{"model" => Model.default()}
+
+  @impl true
+  def generate(texts, config),
+    do: ChromEx.Embeddings.generate(texts, model: model(config), input: :document)
+
+  @impl true
+  def generate_queries(texts, config),
+    do: ChromEx.Embeddings.generate(texts, model: model(config), input: :query)
+
+  @impl true
+  def model(%{"model" => model}), do: model
+end
@@ -2,38 +2,126 @@ defmodule ChromEx.Embeddings do
   @moduledoc """
   Auto-generates embeddings using the same ONNX model as Python ChromaDB.
 
-  This module provides a simple API for generating embeddings. Internally, it uses
-  a pool of workers for parallel processing, with pool size defaulting to the number
-  of CPU cores.
+  This module provides a simple API for generating embeddings. Two backends
+  are available, chosen at compile time:
+
+    * `:ortex` (default) - a pool of workers running the model through Ortex
+      and Nx, with pool size defaulting to the number of CPU cores
+    * `:native` - tokenization and inference inside the native crate. `add`,
+      `upsert` and `query` then embed documents and query texts in the same
+      native call as the write or search, and Ortex, Tokenizers and Nx are
+      not needed.
 
   ## Configuration
 
-  You can configure the pool size in your config.exs:
+  You can configure the backend, pool size and batching in your config.exs:
+
+      config :chromex, embeddings_backend: :native
 
       config :chromex, embedding_pool_size: 8
 
+      config :chromex, embedding_batch_size: 32, embedding_batch_wait: 5
+
+  Changing the backend requires recompiling chromex
+  (`mix deps.compile chromex --force`).
+
+  Models other than all-MiniLM-L6-v2, and models stored in a local
+  directory, are registered as described in `ChromEx.Embeddings.Model`.
+
   ## Examples
 
       ChromEx.Embeddings.generate(["Hello world", "Goodbye world"])
       #=> [[0.1, 0.2, ...], [0.3, 0.4, ...]]
   """
 
+  alias ChromEx.{Client, EmbeddingsBatcher, Native}
+  alias ChromEx.Embeddings.Model
+
+  @backend Application.compile_env(:chromex, :embeddings_backend, :ortex)
+
   @doc """
-  Generates embeddings for a list of texts using all-MiniLM-L6-v2 ONNX model.
+  Generates embeddings for a list of texts using an ONNX model, all-MiniLM-L6-v2
+  unless another is given.
+
+  Returns one embedding vector for each input text; all-MiniLM-L6-v2 embeddings
+  have 384 dimensions. Embeddings are L2-normalized (unless the model spec turns
+  it off) and suitable for semantic similarity search.
+
+  Concurrent calls for the same model are coalesced into shared batches by
+  `ChromEx.EmbeddingsBatcher`. With the `:ortex` backend, batches run on a
+  pool of workers, so several can be processed concurrently. With the
+  `:native` backend they run on the `:client`'s native embedder.
 
-  Returns a list of 384-dimensional embedding vectors, one for each input text.
-  Embeddings are L2-normalized and suitable for semantic similarity search.
+  ## Options
 
-  This function uses a pool of workers for parallel processing, allowing multiple
-  embedding requests to be processed concurrently.
+    * `:model` - name of a `ChromEx.Embeddings.Model` (default
+      `"all-MiniLM-L6-v2"`)
+    * `:input` - `:document` (default) or `:query`, selecting which of the
+      model's prefixes is prepended
+    * `:client` - the `ChromEx.Client` that embeds with the `:native`
+      backend (default `ChromEx.Client`)
+    * `:timeout` - milliseconds to wait for the embeddings (default
+      `:infinity`), see `ChromEx.EmbeddingsBatcher.generate/5`
 
   ## Examples
 
       ChromEx.Embeddings.generate(["Hello world", "Goodbye world"])
       #=> [[0.1, 0.2, ...], [0.3, 0.4, ...]]
+
+      ChromEx.Embeddings.generate(["how do I reset it?"], model: "bge-small-en-v1.5", input: :query)
   """
-  @spec generate([String.t()]) :: [[float()]]
-  def generate(texts) when is_list(texts) do
-    ChromEx.EmbeddingsPool.generate(texts)
+  @spec generate([String.t()], keyword()) :: [[float()]]
+  def generate(texts, opts \\ []) when is_list(texts) do
+    model = Keyword.get(opts, :model, Model.default())
+    input = Keyword.get(opts, :input, :document)
+    # Ortex workers serve every client, so their batches need not be split
+    client = if @backend == :native, do: Keyword.get(opts, :client, Client)
+    batcher_opts = [client: client] ++ Keyword.take(opts, [:timeout])
+
+    case EmbeddingsBatcher.generate(EmbeddingsBatcher, texts, model, input, batcher_opts) do
+      {:ok, embeddings} -> embeddings
+      {:error, reason} -> raise ChromEx.Error, reason: reason, action: "generate embeddings"
+    end
+  end
+
+  @doc """
+  Returns the embeddings backend chromex was compiled with, `:ortex` or `:native`
+  """
+  @spec backend() :: :ortex | :native
+  def backend, do: @backend
+
+  # Runs one batch on the configured backend; called by EmbeddingsBatcher
+  @doc false
+  @spec run([String.t()], String.t(), :document | :query, GenServer.server() | nil) ::
+          {:ok, [[float()]]} | {:error, term()}
+  def run(texts, model, input, client) do
+    case @backend do
+      :native -> generate_native(texts, model, input, client)
+      :ortex -> ChromEx.EmbeddingsPool.generate(texts, model, input)
+    end
+  end
+
+  # Token offsets of `texts` under `model` and how many tokens fit before
+  # truncation; used by ChromEx.Collection to detect and split long documents
+  @doc false
+  @spec token_offsets([String.t()], String.t(), GenServer.server()) ::
+          {:ok, {non_neg_integer(), [[{non_neg_integer(), non_neg_integer()}]]}}
+          | {:error, term()}
+  def token_offsets(texts, model, client \\ Client) do
+    case @backend do
+      :native ->
+        with :ok <- Client.ensure_embedder(client, model) do
+          Native.call(&Native.token_offsets(Client.get_resource(client), &1, model, texts))
+        end
+
+      :ortex ->
+        ChromEx.EmbeddingsPool.token_offsets(texts, model)
+    end
+  end
+
+  defp generate_native(texts, model, input, client) do
+    with :ok <- Client.ensure_embedder(client, model) do
+      Native.call(&Native.embed(Client.get_resource(client), &1, model, texts, input))
+    end
   end
 end
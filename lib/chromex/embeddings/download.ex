@@ -0,0 +1,189 @@
+defmodule ChromEx.Embeddings.Download do
+  @moduledoc false
+  # Fetches model archives over verified TLS, resuming partial downloads,
+  # checking their SHA-256 and extracting them atomically.
+
+  require Logger
+
+  @archive "onnx.tar.gz"
+  @idle_timeout 60_000
+
+  @doc """
+  Downloads the archive at `url` into `dir` and extracts it there, unless
+  every path in `expected` already exists. Concurrent calls for the same
+  directory wait for the first one instead of downloading twice.
+  """
+  @spec fetch(String.t(), String.t() | nil, String.t(), [String.t()]) :: :ok | {:error, term()}
+  def fetch(url, sha256, dir, expected) do
+    :global.trans({{__MODULE__, dir}, self()}, fn ->
+      if Enum.all?(expected, &File.exists?/1) do
+        :ok
+      else
+        archive = Path.join(dir, @archive)
+
+        with :ok <- File.mkdir_p(dir),
+             :ok <- ensure_archive(url, sha256, archive) do
+          extract(archive, dir)
+        end
+      end
+    end)
+  end
+
+  # A complete archive is only ever renamed into place after verification;
+  # an unverifiable one left behind by an older version is fetched again.
+  defp ensure_archive(url, sha256, archive) do
+    if File.exists?(archive) and verify(archive, sha256) == :ok do
+      :ok
+    else
+      File.rm(archive)
+      part = archive <> ".part"
+
+      with :ok <- download(url, part),
+           :ok <- verify_download(part, sha256) do
+        File.rename(part, archive)
+      end
+    end
+  end
+
+  defp verify_download(part, sha256) do
+    case verify(part, sha256) do
+      :ok ->
+        :ok
+
+      {:error, reason} ->
+        # Resuming a corrupt download would never succeed
+        File.rm(part)
+        {:error, reason}
+    end
+  end
+
+  defp verify(path, nil) do
+    Logger.warning("No sha256 configured for #{path}; skipping checksum verification")
+    :ok
+  end
+
+  defp verify(path, expected) do
+    actual =
+      path
+      |> File.stream!(1_048_576)
+      |> Enum.reduce(:crypto.hash_init(:sha256), &:crypto.hash_update(&2, &1))
+      |> :crypto.hash_final()
+      |> Base.encode16(case: :lower)
+
+    if actual == String.downcase(expected),
+      do: :ok,
+      else: {:error, {:checksum_mismatch, path, expected, actual}}
+  end
+
+  defp download(url, part) do
+    {:ok, _} = Application.ensure_all_started([:inets, :ssl])
+
+    offset =
+      case File.stat(part) do
+        {:ok, %File.Stat{size: size}} -> size
+        {:error, _} -> 0
+      end
+
+    headers = if offset > 0, do: [{~c"range", ~c"bytes=#{offset}-"}], else: []
+
+    request = {to_charlist(url), headers}
+
+    case :httpc.request(:get, request, http_options(), sync: false, stream: :self) do
+      {:ok, ref} -> receive_body(ref, url, part, offset)
+      {:error, reason} -> {:error, {:download_failed, url, reason}}
+    end
+  end
+
+  defp http_options do
+    [
+      connect_timeout: 30_000,
+      ssl: [
+        verify: :verify_peer,
+        cacerts: :public_key.cacerts_get(),
+        depth: 3,
+        customize_hostname_check: [
+          match_fun: :public_key.pkix_verify_hostname_match_fun(:https)
+        ]
+      ]
+    ]
+  end
+
+  defp receive_body(ref, url, part, offset) do
+    receive do
+      {:http, {^ref, :stream_start, headers}} ->
+        # A server that ignores the range sends the whole archive again
+        resumed? = offset > 0 and List.keymember?(headers, ~c"content-range", 0)
+        mode = if resumed?, do: [:append, :binary], else: [:write, :binary]
+
+        case File.open(part, mode) do
+          {:ok, file} ->
+            try do
+              stream_to(ref, url, file)
+            after
+              File.close(file)
+            end
+
+          {:error, reason} ->
+            :httpc.cancel_request(ref)
+            {:error, {:download_failed, url, reason}}
+        end
+
+      # The partial file already holds the whole archive; verification
+      # decides whether it is usable.
+      {:http, {^ref, {{_, 416, _}, _headers, _body}}} ->
+        :ok
+
+      {:http, {^ref, {{_, status, _}, _headers, _body}}} ->
+        {:error, {:download_failed, url, {:http_status, status}}}
+
+      {:http, {^ref, {:error, reason}}} ->
+        {:error, {:download_failed, url, reason}}
+    after
+      @idle_timeout ->
+        :httpc.cancel_request(ref)
+        {:error, {:download_failed, url, :timeout}}
+    end
+  end
+
+  defp stream_to(ref, url, file) do
+    receive do
+      {:http, {^ref, :stream, chunk}} ->
+        IO.binwrite(file, chunk)
+        stream_to(ref, url, file)
+
+      {:http, {^ref, :stream_end, _headers}} ->
+        :ok
+
+      {:http, {^ref, {:error, reason}}} ->
+        {:error, {:download_failed, url, reason}}
+    after
+      @idle_timeout ->
+        :httpc.cancel_request(ref)
+        {:error, {:download_failed, url, :timeout}}
+    end
+  end
+
+  # Extracts next to the final location and then renames each top-level
+  # entry into place, so readers never see a half-extracted model.
+  defp extract(archive, dir) do
+    staging = Path.join(dir, ".extract-#{System.unique_integer([:positive])}")
+    options = [:compressed, {:cwd, to_charlist(staging)}]
+
+    try do
+      with :ok <- File.mkdir_p(staging),
+           :ok <- :erl_tar.extract(to_charlist(archive), options) do
+        staging
+        |> File.ls!()
+        |> Enum.each(fn entry ->
+          destination = Path.join(dir, entry)
+          File.rm_rf!(destination)
+          File.rename!(Path.join(staging, entry), destination)
+        end)
+      else
+        {:error, reason} -> {:error, {:extract_failed, archive, reason}}
+      end
+    after
+      File.rm_rf(staging)
+    end
+  end
+end
@@ -0,0 +1,187 @@
+defmodule ChromEx.Embeddings.Model do
+  @moduledoc """
+  Specification of an ONNX embedding model and where its files live
+
+  `all-MiniLM-L6-v2` is built in. Further models, such as bge, e5 or gte
+  exports, are registered under the `:models` application env, by name:
+
+      config :chromex,
+        models: %{
+          "bge-small-en-v1.5" => [
+            dir: "/models/bge-small-en-v1.5",
+            onnx_path: "model.onnx",
+            tokenizer_path: "tokenizer.json",
+            max_length: 512,
+            pooling: :cls,
+            dimension: 384,
+            query_prefix: "Represent this sentence for searching relevant passages: "
+          ]
+        }
+
+  ## Options
+
+    * `:dir` - directory holding the model files. Models with a `:dir` are
+      loaded from it as is and never downloaded, so they work offline.
+    * `:url` - `.tar.gz` archive to download into the cache directory
+      (`<model_cache_dir>/<name>`) when there is no `:dir`
+    * `:sha256` - checksum the downloaded archive must match. Downloads
+      without one are used unverified, with a warning.
+    * `:onnx_path` - ONNX file, relative to the directory (default
+      `"onnx/model.onnx"`)
+    * `:tokenizer_path` - Hugging Face `tokenizer.json`, relative to the
+      directory (default `"onnx/tokenizer.json"`)
+    * `:max_length` - longest input in tokens; longer texts are truncated
+      (default 512)
+    * `:pooling` - `:mean` over unmasked tokens (default) or `:cls` for the
+      first token. Models that return pooled `{batch, dimension}` outputs
+      are used as is.
+    * `:normalize` - L2-normalize embeddings (default `true`)
+    * `:dimension` - expected embedding size, checked against the model output
+    * `:query_prefix`, `:document_prefix` - prepended to query texts and to
+      documents before embedding, e.g. `"query: "` and `"passage: "` for e5
+
+  ## Downloads
+
+  Archives are fetched over TLS with certificate verification, checked
+  against `:sha256` and extracted atomically. An interrupted download
+  resumes where it stopped. The cache directory defaults to
+  `~/.cache/chroma/onnx_models`, shared with Python Chroma, and is set with
+  the `:model_cache_dir` application env.
+
+  With `config :chromex, offline: true` (or `CHROMEX_OFFLINE=1`), nothing is
+  downloaded and a model missing from the cache returns
+  `{:error, {:model_offline, name, dir}}`. Run `mix chromex.models.fetch` at
+  build time to seed the cache for offline deployments.
+  """
+
+  alias ChromEx.Embeddings.Download
+
+  @default_model "all-MiniLM-L6-v2"
+
+  @builtin %{
+    @default_model => [
+      url: "https://chroma-onnx-models.s3.amazonaws.com/all-MiniLM-L6-v2/onnx.tar.gz",
+      sha256: "913d7300ceae3b2dbc2c50d1de4baacab4be7b9380491c27fab7418616a16ec3",
+      max_length: 256,
+      dimension: 384
+    ]
+  }
+
+  defstruct [
+    :name,
+    :dir,
+    :url,
+    :sha256,
+    :dimension,
+    onnx_path: "onnx/model.onnx",
+    tokenizer_path: "onnx/tokenizer.json",
+    max_length: 512,
+    pooling: :mean,
+    normalize: true,
+    query_prefix: "",
+    document_prefix: ""
+  ]
+
+  @type t :: %__MODULE__{
+          name: String.t(),
+          dir: String.t() | nil,
+          url: String.t() | nil,
+          sha256: String.t() | nil,
+          dimension: pos_integer() | nil,
+          onnx_path: String.t(),
+          tokenizer_path: String.t(),
+          max_length: pos_integer(),
+          pooling: :mean | :cls,
+          normalize: boolean(),
+          query_prefix: String.t(),
+          document_prefix: String.t()
+        }
+
+  @doc """
+  Name of the model used unless another is given
+  """
+  @spec default() :: String.t()
+  def default, do: @default_model
+
+  @doc """
+  Names of the built-in and configured models
+  """
+  @spec names() :: [String.t()]
+  def names, do: Map.keys(models())
+
+  @doc """
+  Looks up a model by name among the built-in and configured models
+  """
+  @spec get(String.t()) :: {:ok, t()} | {:error, {:unknown_model, String.t()}}
+  def get(name) do
+    case Map.fetch(models(), name) do
+      {:ok, opts} -> {:ok, struct!(__MODULE__, Keyword.put(opts, :name, name))}
+      :error -> {:error, {:unknown_model, name}}
+    end
+  end
+
+  @doc """
+  Returns the absolute paths of the model's ONNX and tokenizer files,
+  downloading them first if the model has a `:url` and no `:dir` and
+  downloads are allowed
+  """
+  @spec files(t()) :: {:ok, %{onnx: String.t(), tokenizer: String.t()}} | {:error, term()}
+  def files(%__MODULE__{} = model) do
+    dir = directory(model)
+    files = %{
+      onnx: Path.join(dir, model.onnx_path),
+      tokenizer: Path.join(dir, model.tokenizer_path)
+    }
+
+    cond do
+      File.exists?(files.onnx) and File.exists?(files.tokenizer) ->
+        {:ok, files}
+
+      model.dir || is_nil(model.url) ->
+        {:error, {:model_files_missing, model.name, dir}}
+
+      offline?() ->
+        {:error, {:model_offline, model.name, dir}}
+
+      true ->
+        with :ok <- Download.fetch(model.url, model.sha256, dir, [files.onnx, files.tokenizer]),
+             do: {:ok, files}
+    end
+  end
+
+  @doc """
+  Whether downloads are disabled, by the `:offline` application env or else
+  by the `CHROMEX_OFFLINE` environment variable
+  """
+  @spec offline?() :: boolean()
+  def offline? do
+    Application.get_env(:chromex, :offline, System.get_env("CHROMEX_OFFLINE") in ["1", "true"])
+  end
+
+  @doc false
+  # The form the native crate takes model specs in.
+  @spec to_native(t(), %{onnx: String.t(), tokenizer: String.t()}) :: map()
+  def to_native(%__MODULE__{} = model, files) do
+    %{
+      onnx_path: files.onnx,
+      tokenizer_path: files.tokenizer,
+      max_length: model.max_length,
+      pooling: model.pooling,
+      normalize: model.normalize,
+      dimension: model.dimension,
+      query_prefix: model.query_prefix,
+      document_prefix: model.document_prefix
+    }
+  end
+
+  defp models, do: Map.merge(@builtin, Application.get_env(:chromex, :models, %{}))
+
+  defp directory(%__MODULE__{dir: nil, name: name}) do
+    :chromex
+    |> Application.get_env(:model_cache_dir, "~/.cache/chroma/onnx_models")
+    |> Path.join(name)
+    |> Path.expand()
+  end
+
+  defp directory(%__MODULE__{dir: dir}), do: Path.expand(dir)
+end
@@ -0,0 +1,235 @@
+defmodule ChromEx.EmbeddingsBatcher do
+  @moduledoc """
+  Coalesces concurrent embedding requests into shared model runs.
+
+  Callers of `ChromEx.Embeddings.generate/2` are queued per model, input
+  type and, with the `:native` backend, client. A queue is run as one batch
+  once it holds `:max_batch_size` texts or its oldest request has waited
+  `:max_wait` milliseconds, whichever comes first, and each caller gets back
+  the embeddings for its own texts. Many
+  concurrent single-text queries thus share a few model runs instead of each
+  running a batch of one. A request is never split, so one larger than
+  `:max_batch_size` runs as a batch of its own.
+
+  Up to `:max_concurrency` batches run at once. A queue that is due while
+  that many are running waits, still taking requests, until one finishes.
+
+  ## Configuration
+
+      config :chromex,
+        embedding_batch_size: 32,
+        embedding_batch_wait: 5
+
+  `:max_concurrency` follows `:embedding_pool_size`.
+  """
+
+  use GenServer
+
+  @type stats :: %{
+          requests: non_neg_integer(),
+          texts: non_neg_integer(),
+          batches: non_neg_integer(),
+          full_batches: non_neg_integer(),
+          largest_batch: non_neg_integer(),
+          mean_batch_size: float(),
+          pending: non_neg_integer(),
+          running: non_neg_integer()
+        }
+
+  @doc """
+  Starts a batcher.
+
+  ## Options
+
+    * `:name` - registered name (default `ChromEx.EmbeddingsBatcher`)
+    * `:max_batch_size` - texts per batch before it runs without waiting
+      (default 32)
+    * `:max_wait` - milliseconds a request waits for others to join its
+      batch (default 5)
+    * `:max_concurrency` - batches run at once (default
+      `System.schedulers_online/0`)
+  """
+  def start_link(opts \\ []) do
+    {name, opts} = Keyword.pop(opts, :name, __MODULE__)
+    GenServer.start_link(__MODULE__, opts, name: name)
+  end
+
+  @doc """
+  Embeds `texts` with `model` as part of the next batch for that model and
+  input type. Returns `{:ok, embeddings}` or the batch's `{:error, reason}`.
+
+  ## Options
+
+    * `:client` - the `ChromEx.Client` whose native embedder runs the batch
+      with the `:native` backend (default `ChromEx.Client`)
+    * `:timeout` - milliseconds to wait for the batch (default `:infinity`).
+      A batch may first wait for others to finish and for its model to be
+      downloaded and loaded.
+  """
+  @spec generate(GenServer.server(), [String.t()], String.t(), :document | :query, keyword()) ::
+          {:ok, [[float()]]} | {:error, term()}
+  def generate(server \\ __MODULE__, texts, model, input, opts \\ [])
+
+  def generate(_server, [], _model, _input, _opts), do: {:ok, []}
+
+  def generate(server, texts, model, input, opts) when is_list(texts) do
+    client = Keyword.get(opts, :client, ChromEx.Client)
+    timeout = Keyword.get(opts, :timeout, :infinity)
+    GenServer.call(server, {:generate, texts, model, input, client}, timeout)
+  end
+
+  @doc """
+  Returns counters describing how well requests are being coalesced.
+
+    * `:requests`, `:texts` - totals received
+    * `:batches` - batches run, and `:full_batches` of those that reached
+      `:max_batch_size` rather than waiting out `:max_wait`
+    * `:largest_batch`, `:mean_batch_size` - texts per batch
+    * `:pending` - texts waiting for a batch, `:running` - batches in flight
+  """
+  @spec stats(GenServer.server()) :: stats()
+  def stats(server \\ __MODULE__), do: GenServer.call(server, :stats)
+
+  @impl true
+  def init(opts) do
+    state = %{
+      max_batch_size: Keyword.get(opts, :max_batch_size, 32),
+      max_wait: Keyword.get(opts, :max_wait, 5),
+      max_concurrency: Keyword.get(opts, :max_concurrency, System.schedulers_online()),
+      # {client, model, input} => %{requests: [{from, texts}] (newest first), size, timer, token,
+      # due}, where due is the trigger of a queue waiting for a running batch
+      queues: %{},
+      # keys of the due queues, oldest first
+      waiting: :queue.new(),
+      # task ref => [{from, count}] in batch order
+      running: %{},
+      stats: %{requests: 0, texts: 0, batches: 0, full_batches: 0, largest_batch: 0}
+    }
+
+    {:ok, state}
+  end
+
+  @impl true
+  def handle_call({:generate, texts, model, input, client}, from, state) do
+    key = {client, model, input}
+    count = length(texts)
+
+    queue =
+      case Map.fetch(state.queues, key) do
+        {:ok, queue} ->
+          queue
+
+        :error ->
+          token = make_ref()
+          timer = Process.send_after(self(), {:flush, key, token}, state.max_wait)
+          %{requests: [], size: 0, timer: timer, token: token, due: nil}
+      end
+
+    queue = %{queue | requests: [{from, texts} | queue.requests], size: queue.size + count}
+
+    state =
+      state
+      |> put_in([:queues, key], queue)
+      |> update_in([:stats], fn stats ->
+        %{stats | requests: stats.requests + 1, texts: stats.texts + count}
+      end)
+
+    if queue.size >= state.max_batch_size and queue.due == nil do
+      Process.cancel_timer(queue.timer)
+      {:noreply, schedule(state, key, :full)}
+    else
+      {:noreply, state}
+    end
+  end
+
+  def handle_call(:stats, _from, state) do
+    %{batches: batches, texts: texts} = state.stats
+    pending = state.queues |> Map.values() |> Enum.map(& &1.size) |> Enum.sum()
+    batched = texts - pending
+
+    stats =
+      Map.merge(state.stats, %{
+        mean_batch_size: if(batches > 0, do: batched / batches, else: 0.0),
+        pending: pending,
+        running: map_size(state.running)
+      })
+
+    {:reply, stats, state}
+  end
+
+  @impl true
+  def handle_info({:flush, key, token}, state) do
+    # The token tells a queue's own timer apart from one left over by an
+    # earlier queue for the same key that filled up before it fired
+    case state.queues do
+      %{^key => %{token: ^token, due: nil}} -> {:noreply, schedule(state, key, :timeout)}
+      _ -> {:noreply, state}
+    end
+  end
+
+  def handle_info({ref, result}, state) when is_map_key(state.running, ref) do
+    Process.demonitor(ref, [:flush])
+    {callers, running} = Map.pop(state.running, ref)
+    reply_all(callers, result)
+    {:noreply, run_due(%{state | running: running})}
+  end
+
+  def handle_info({:DOWN, ref, :process, _pid, reason}, state)
+      when is_map_key(state.running, ref) do
+    {callers, running} = Map.pop(state.running, ref)
+    Enum.each(callers, fn {from, _count} -> GenServer.reply(from, {:error, {:exit, reason}}) end)
+    {:noreply, run_due(%{state | running: running})}
+  end
+
+  def handle_info(_message, state), do: {:noreply, state}
+
+  defp schedule(state, key, trigger) do
+    if map_size(state.running) < state.max_concurrency do
+      run(state, key, trigger)
+    else
+      state
+      |> put_in([:queues, key, :due], trigger)
+      |> Map.update!(:waiting, &:queue.in(key, &1))
+    end
+  end
+
+  defp run_due(state) do
+    case :queue.out(state.waiting) do
+      {{:value, key}, waiting} -> run(%{state | waiting: waiting}, key, state.queues[key].due)
+      {:empty, _waiting} -> state
+    end
+  end
+
+  defp run(state, {client, model, input} = key, trigger) do
+    {queue, queues} = Map.pop(state.queues, key)
+    requests = Enum.reverse(queue.requests)
+    texts = Enum.flat_map(requests, fn {_from, texts} -> texts end)
+    callers = Enum.map(requests, fn {from, texts} -> {from, length(texts)} end)
+
+    task =
+      Task.Supervisor.async_nolink(ChromEx.TaskSupervisor, fn ->
+        ChromEx.Embeddings.run(texts, model, input, client)
+      end)
+
+    stats = %{
+      state.stats
+      | batches: state.stats.batches + 1,
+        full_batches: state.stats.full_batches + if(trigger == :full, do: 1, else: 0),
+        largest_batch: max(state.stats.largest_batch, queue.size)
+    }
+
+    %{state | queues: queues, running: Map.put(state.running, task.ref, callers), stats: stats}
+  end
+
+  defp reply_all(callers, {:ok, embeddings}) do
+    Enum.reduce(callers, embeddings, fn {from, count}, rest ->
+      {own, rest} = Enum.split(rest, count)
+      GenServer.reply(from, {:ok, own})
+      rest
+    end)
+  end
+
+  defp reply_all(callers, {:error, _reason} = error) do
+    Enum.each(callers, fn {from, _count} -> GenServer.reply(from, error) end)
+  end
+end
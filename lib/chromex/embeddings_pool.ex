@@ -7,6 +7,8 @@ defmodule ChromEx.EmbeddingsPool do
 
   @behaviour NimblePool
 
+  alias ChromEx.Embeddings.Model
+
   @doc """
   Starts the embeddings pool with the given options.
 
@@ -35,17 +37,44 @@ defmodule ChromEx.EmbeddingsPool do
   @doc """
   Generates embeddings using a worker from the pool.
 
-  Returns a list of 384-dimensional embedding vectors, one for each input text.
+  Returns `{:ok, embeddings}` with one embedding vector for each input text,
+  or `{:error, reason}` if the model can't be loaded.
+  """
+  @spec generate([String.t()], String.t(), :document | :query) ::
+          {:ok, [[float()]]} | {:error, term()}
+  def generate(texts, model, input) when is_list(texts) do
+    with :ok <- fetch(model) do
+      checkout(&ChromEx.EmbeddingsWorker.generate(&1, texts, model, input))
+    end
+  end
+
+  @doc """
+  Tokenizes texts without truncation using a worker from the pool, see
+  `ChromEx.EmbeddingsWorker.token_offsets/3`.
   """
-  @spec generate([String.t()]) :: [[float()]]
-  def generate(texts) when is_list(texts) do
+  @spec token_offsets([String.t()], String.t()) ::
+          {:ok, {non_neg_integer(), [[{non_neg_integer(), non_neg_integer()}]]}}
+          | {:error, term()}
+  def token_offsets(texts, model) when is_list(texts) do
+    with :ok <- fetch(model) do
+      checkout(&ChromEx.EmbeddingsWorker.token_offsets(&1, texts, model))
+    end
+  end
+
+  # Downloads the model's files, if they are missing, in the caller and
+  # before checking out a worker: a first download on a slow link takes far
+  # longer than a worker call or a checkout may. Workers then find them.
+  defp fetch(name) do
+    with {:ok, model} <- Model.get(name),
+         {:ok, _files} <- Model.files(model),
+         do: :ok
+  end
+
+  defp checkout(fun) do
     NimblePool.checkout!(
       __MODULE__,
       :checkout,
-      fn _from, worker ->
-        result = ChromEx.EmbeddingsWorker.generate(worker, texts)
-        {result, worker}
-      end,
+      fn _from, worker -> {fun.(worker), worker} end,
       60_000
     )
   end
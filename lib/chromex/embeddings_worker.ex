@@ -1,46 +1,133 @@
 defmodule ChromEx.EmbeddingsWorker do
   @moduledoc """
-  Worker GenServer for generating embeddings. Each worker has its own model instance.
-  Used by EmbeddingsPool for parallel embedding generation.
+  Worker GenServer for generating embeddings. Each worker has its own model instances,
+  loaded the first time a model is used.
+  Used by EmbeddingsPool for parallel embedding generation, which downloads
+  models before calling a worker; models missing at that point are
+  downloaded within the call and its timeout.
   """
 
   use GenServer
 
-  @model_url "https://chroma-onnx-models.s3.amazonaws.com/all-MiniLM-L6-v2/onnx.tar.gz"
-  @cache_dir Path.expand("~/.cache/chroma/onnx_models/all-MiniLM-L6-v2")
+  alias ChromEx.Embeddings.Model
+
+  # Ortex, Tokenizers and Nx are optional and only needed by this backend.
+  @compile {:no_warn_undefined,
+            [Ortex, Ortex.Native, Tokenizers.Tokenizer, Tokenizers.Encoding, Nx]}
 
   def start_link(_opts) do
     GenServer.start_link(__MODULE__, [])
   end
 
   @doc """
-  Generates embeddings for a list of texts using this worker's model instance
+  Generates embeddings for a list of texts using this worker's instance of `model`
+  """
+  def generate(worker, texts, model \\ Model.default(), input \\ :document) when is_list(texts) do
+    GenServer.call(worker, {:generate, texts, model, input}, 60_000)
+  end
+
+  @doc """
+  Tokenizes `texts` with `model`'s tokenizer without truncating them. Returns
+  how many tokens of a document fit in one model input and the byte offsets
+  of each text's tokens.
   """
-  def generate(worker, texts) when is_list(texts) do
-    GenServer.call(worker, {:generate, texts}, 60_000)
+  def token_offsets(worker, texts, model) when is_list(texts) do
+    GenServer.call(worker, {:token_offsets, texts, model}, 60_000)
   end
 
   @impl true
   def init(_opts) do
-    # Download model if needed (safe to call multiple times)
-    download_model_if_needed()
+    {:ok, %{models: %{}}}
+  end
 
-    # Load model and tokenizer for this worker
-    model = Ortex.load("#{@cache_dir}/onnx/model.onnx")
-    {:ok, tokenizer} = Tokenizers.Tokenizer.from_file("#{@cache_dir}/onnx/tokenizer.json")
-    Tokenizers.Tokenizer.set_truncation(tokenizer, max_length: 256)
+  @impl true
+  def handle_call({:generate, texts, name, input}, _from, state) do
+    case loaded_model(state, name) do
+      {:ok, loaded, state} ->
+        {:reply, {:ok, generate_embeddings(loaded, texts, input)}, state}
 
-    {:ok, %{model: model, tokenizer: tokenizer}}
+      {:error, reason} ->
+        {:reply, {:error, reason}, state}
+    end
   end
 
-  @impl true
-  def handle_call({:generate, texts}, _from, %{model: model, tokenizer: tokenizer} = state) do
-    embeddings = generate_embeddings(model, tokenizer, texts)
-    {:reply, embeddings, state}
+  def handle_call({:token_offsets, texts, name}, _from, state) do
+    case loaded_model(state, name) do
+      {:ok, %{counter: counter, reserved: reserved, spec: spec}, state} ->
+        offsets =
+          Enum.map(texts, fn text ->
+            {:ok, encoding} =
+              Tokenizers.Tokenizer.encode(counter, text, add_special_tokens: false)
+
+            byte_offsets(text, Tokenizers.Encoding.get_offsets(encoding))
+          end)
+
+        {:reply, {:ok, {max(spec.max_length - reserved, 0), offsets}}, state}
+
+      {:error, reason} ->
+        {:reply, {:error, reason}, state}
+    end
+  end
+
+  # The Elixir binding encodes with char offsets, counting codepoints, where
+  # the native backend reports the bytes the tokenizers crate works in.
+  # ChromEx.Chunking cuts documents with binary_part/3, so convert to bytes.
+  defp byte_offsets(text, offsets) do
+    bytes =
+      text
+      |> String.codepoints()
+      |> Enum.scan(0, &(byte_size(&1) + &2))
+      |> then(&List.to_tuple([0 | &1]))
+
+    Enum.map(offsets, fn {start, stop} -> {elem(bytes, start), elem(bytes, stop)} end)
+  end
+
+  defp loaded_model(%{models: models} = state, name) do
+    case Map.fetch(models, name) do
+      {:ok, loaded} ->
+        {:ok, loaded, state}
+
+      :error ->
+        with {:ok, spec} <- Model.get(name),
+             {:ok, files} <- Model.files(spec),
+             {:ok, tokenizer} <- Tokenizers.Tokenizer.from_file(files.tokenizer) do
+          counter =
+            tokenizer
+            |> Tokenizers.Tokenizer.disable_truncation()
+            |> Tokenizers.Tokenizer.disable_padding()
+
+          # Tokens taken up by special tokens and the document prefix
+          {:ok, prefix} = Tokenizers.Tokenizer.encode(counter, spec.document_prefix)
+          tokenizer = Tokenizers.Tokenizer.set_truncation(tokenizer, max_length: spec.max_length)
+          model = Ortex.load(files.onnx)
+
+          loaded = %{
+            spec: spec,
+            model: model,
+            inputs: input_names(model),
+            tokenizer: tokenizer,
+            counter: counter,
+            reserved: Tokenizers.Encoding.get_length(prefix)
+          }
+
+          {:ok, loaded, %{state | models: Map.put(models, name, loaded)}}
+        end
+    end
   end
 
-  defp generate_embeddings(model, tokenizer, texts) do
+  # Not every BERT export takes token_type_ids, and ONNX matches inputs by
+  # position, so they are passed in the order the model declares
+  defp input_names(model) do
+    {inputs, _outputs} = Ortex.Native.show_session(model.reference)
+    Enum.map(inputs, fn {name, _type, _dims} -> name end)
+  end
+
+  defp generate_embeddings(loaded, texts, input) do
+    %{spec: spec, model: model, inputs: inputs, tokenizer: tokenizer} = loaded
+    prefix = if input == :query, do: spec.query_prefix, else: spec.document_prefix
+
     texts
+    |> Enum.map(&(prefix <> &1))
     |> Enum.chunk_every(32)
     |> Enum.flat_map(fn batch ->
       encoded = Enum.map(batch, fn text ->
@@ -48,11 +135,14 @@ defmodule ChromEx.EmbeddingsWorker do
         encoding
       end)
 
+      # Pad only to the longest text in the batch
+      seq_length = encoded |> Enum.map(&Tokenizers.Encoding.get_length/1) |> Enum.max()
+
       input_ids =
         encoded
         |> Enum.map(fn enc ->
           ids = Tokenizers.Encoding.get_ids(enc)
-          pad_to_length(ids, 256, 0)
+          pad_to_length(ids, seq_length, 0)
         end)
         |> Nx.tensor(type: :s64)
 
@@ -60,40 +150,51 @@ defmodule ChromEx.EmbeddingsWorker do
         encoded
         |> Enum.map(fn enc ->
           mask = Tokenizers.Encoding.get_attention_mask(enc)
-          pad_to_length(mask, 256, 0)
+          pad_to_length(mask, seq_length, 0)
         end)
         |> Nx.tensor(type: :s64)
 
-      token_type_ids =
-        input_ids
-        |> Nx.shape()
-        |> then(fn {batch, seq} -> Nx.broadcast(0, {batch, seq}) end)
-        |> Nx.as_type(:s64)
+      tensors = %{
+        "input_ids" => input_ids,
+        "attention_mask" => attention_mask,
+        "token_type_ids" => Nx.broadcast(Nx.tensor(0, type: :s64), Nx.shape(input_ids))
+      }
 
-      {output} =
-        Ortex.run(model, {input_ids, attention_mask, token_type_ids})
+      output =
+        model
+        |> Ortex.run(inputs |> Enum.map(&Map.fetch!(tensors, &1)) |> List.to_tuple())
+        |> elem(0)
+        |> Nx.backend_transfer()
 
-      output = Nx.backend_transfer(output)
       attention_mask = Nx.backend_transfer(attention_mask)
 
-      attention_mask_expanded =
-        attention_mask
-        |> Nx.new_axis(-1)
-        |> Nx.broadcast(Nx.shape(output))
-
       embeddings =
-        Nx.sum(Nx.multiply(output, attention_mask_expanded), axes: [1])
-        |> Nx.divide(
-          Nx.sum(attention_mask_expanded, axes: [1])
-          |> Nx.max(1.0e-9)
-        )
-        |> normalize()
+        output
+        |> pool(attention_mask, spec.pooling)
+        |> then(&if(spec.normalize, do: normalize(&1), else: &1))
 
       Nx.to_batched(embeddings, 1)
       |> Enum.map(&Nx.to_flat_list/1)
     end)
   end
 
+  # Models exported with pooling built in return {batch, dimension}
+  defp pool(%{shape: {_batch, _dimension}} = output, _attention_mask, _pooling), do: output
+  defp pool(output, _attention_mask, :cls), do: output[[.., 0, ..]]
+
+  defp pool(output, attention_mask, :mean) do
+    attention_mask_expanded =
+      attention_mask
+      |> Nx.new_axis(-1)
+      |> Nx.broadcast(Nx.shape(output))
+
+    Nx.sum(Nx.multiply(output, attention_mask_expanded), axes: [1])
+    |> Nx.divide(
+      Nx.sum(attention_mask_expanded, axes: [1])
+      |> Nx.max(1.0e-9)
+    )
+  end
+
   defp normalize(tensor) do
     norm =
       tensor
@@ -119,27 +220,4 @@ defmodule ChromEx.EmbeddingsWorker do
         list ++ List.duplicate(pad_value, target_length - current_length)
     end
   end
-
-  defp download_model_if_needed do
-    model_path = "#{@cache_dir}/onnx/model.onnx"
-
-    unless File.exists?(model_path) do
-      File.mkdir_p!(@cache_dir)
-      archive_path = "#{@cache_dir}/onnx.tar.gz"
-
-      unless File.exists?(archive_path) do
-        {:ok, _} = :httpc.request(:get, {@model_url |> to_charlist(), []}, [], [])
-        |> case do
-          {:ok, {{_, 200, _}, _headers, body}} ->
-            File.write!(archive_path, body)
-            {:ok, :downloaded}
-
-          error ->
-            raise "Failed to download model: #{inspect(error)}"
-        end
-      end
-
-      System.cmd("tar", ["-xzf", archive_path, "-C", @cache_dir])
-    end
-  end
 end
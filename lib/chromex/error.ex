@@ -0,0 +1,122 @@
+defmodule ChromEx.Error do
+  @moduledoc """
+  Exception raised by the bang (`!`) variants of ChromEx functions.
+
+  `reason` is the same term the non-bang variant returns in `{:error, reason}`:
+
+    * `{:not_found, resource, name}` - `resource` is `:tenant`, `:database` or `:collection`
+    * `{:already_exists, resource, name}`
+    * `{:validation, message}` - the request was rejected before or by Chroma
+    * `{:internal, message}` - Chroma failed while executing the request
+    * `{:panic, message}` - native code panicked while executing the request;
+      see `ChromEx.Client.restart_native/1` if later calls keep failing
+    * `:timeout` - the native call did not reply within the `:timeout` option
+    * `:closed` - the store was closed, see `ChromEx.Client`
+    * `{:embedding_function_mismatch, persisted, given}` - the collection was
+      created with a different `ChromEx.EmbeddingFunction`
+    * `{:unknown_embedding_function, name}` - the collection's embedding
+      function is not registered
+    * `{:model_offline, name, dir}` - the model is not in the cache and
+      downloads are disabled
+    * `{:checksum_mismatch, path, expected, actual}` - a downloaded model
+      archive failed SHA-256 verification
+    * `{:batches_failed, [%{batch: index, ids: ids, reason: reason}]}` - some
+      batches of a write split by `ChromEx.Collection` failed
+    * `{:truncated, [%{id: id, tokens: tokens, max_tokens: max}]}` - documents
+      longer than the model's input were rejected by `truncation: :error`
+    * `{:invalid_export, path, message}` - `ChromEx.Collection.import/3` was
+      given a file that is not a ChromEx export
+
+  ## Examples
+
+      try do
+        ChromEx.Collection.get!("missing")
+      rescue
+        e in ChromEx.Error ->
+          {:not_found, :collection, "missing"} = e.reason
+      end
+  """
+
+  defexception [:reason, :action]
+
+  @type reason ::
+          {:not_found, :tenant | :database | :collection, String.t()}
+          | {:already_exists, :tenant | :database | :collection, String.t()}
+          | {:validation, String.t()}
+          | {:internal, String.t()}
+          | {:panic, String.t()}
+          | :timeout
+          | :closed
+          | term()
+
+  @type t :: %__MODULE__{reason: reason(), action: String.t() | nil}
+
+  @impl true
+  def message(%__MODULE__{reason: reason, action: nil}), do: describe(reason)
+
+  def message(%__MODULE__{reason: reason, action: action}),
+    do: "Failed to #{action}: #{describe(reason)}"
+
+  @doc """
+  Returns the tag of the error reason, e.g. `:not_found` or `:validation`
+  """
+  @spec type(t()) :: atom()
+  def type(%__MODULE__{reason: reason}) when is_tuple(reason), do: elem(reason, 0)
+  def type(%__MODULE__{reason: reason}) when is_atom(reason), do: reason
+  def type(%__MODULE__{}), do: :unknown
+
+  defp describe({:not_found, resource, name}), do: "#{resource} #{inspect(name)} not found"
+
+  defp describe({:already_exists, resource, name}),
+    do: "#{resource} #{inspect(name)} already exists"
+
+  defp describe({:validation, message}), do: "invalid request: #{message}"
+  defp describe({:internal, message}), do: "internal error: #{message}"
+  defp describe({:panic, message}), do: "native code panicked: #{message}"
+  defp describe(:timeout), do: "timed out waiting for the native call"
+  defp describe(:closed), do: "the store is closed"
+
+  defp describe({:embedding_function_mismatch, persisted, given}),
+    do: "collection uses embedding function #{function(persisted)}, got #{function(given)}"
+
+  defp describe({:unknown_embedding_function, name}),
+    do: "embedding function #{inspect(name)} is not registered in :embedding_functions"
+
+  defp describe({:model_offline, name, dir}),
+    do:
+      "model #{inspect(name)} is not in #{dir} and downloads are disabled (offline mode); " <>
+        "run `mix chromex.models.fetch #{name}` to fetch it"
+
+  defp describe({:checksum_mismatch, path, expected, actual}),
+    do: "#{path} has SHA-256 #{actual}, expected #{expected}"
+
+  defp describe({:download_failed, url, reason}),
+    do: "downloading #{url} failed: #{inspect(reason)}"
+
+  defp describe({:extract_failed, archive, reason}),
+    do: "extracting #{archive} failed: #{inspect(reason)}"
+
+  defp describe({:truncated, [%{max_tokens: max_tokens} | _] = truncated}),
+    do:
+      "#{length(truncated)} documents exceed the model's #{max_tokens} token limit: " <>
+        Enum.map_join(truncated, ", ", &inspect(&1.id))
+
+  defp describe({:batches_failed, failures}),
+    do: "#{length(failures)} batches failed: " <> Enum.map_join(failures, "; ", &batch_failure/1)
+
+  defp describe({:rollback_failed, failures, reason}),
+    do:
+      describe({:batches_failed, failures}) <>
+        "; undoing the other batches failed: #{describe(reason)}"
+
+  defp describe({:invalid_export, path, message}),
+    do: "#{path} is not a valid ChromEx export: #{message}"
+
+  defp describe(reason), do: inspect(reason)
+
+  defp batch_failure(%{batch: index, ids: ids, reason: reason}),
+    do: "batch #{index} (#{length(ids)} records from #{inspect(hd(ids))}): #{describe(reason)}"
+
+  defp function(%{"name" => name, "config" => config}),
+    do: "#{inspect(name)} (#{inspect(config)})"
+end
@@ -0,0 +1,312 @@
+defmodule ChromEx.Export do
+  @moduledoc false
+  # Implementation of ChromEx.Collection.export/3 and import/3. JSONL files
+  # are read and written here, Parquet and Arrow IPC files by the native side
+  # when it is built with the `arrow` feature. Either way records move a page
+  # at a time, so neither direction loads a whole collection.
+
+  alias ChromEx.{Collection, EmbeddingFunction, Native, SparseVector}
+
+  @version 1
+  @include ["documents", "metadatas", "embeddings", "uris"]
+
+  @extensions %{
+    ".jsonl" => :jsonl,
+    ".ndjson" => :jsonl,
+    ".parquet" => :parquet,
+    ".arrow" => :arrow,
+    ".arrows" => :arrow,
+    ".ipc" => :arrow,
+    ".feather" => :arrow
+  }
+
+  @fields %{
+    "id" => :id,
+    "embedding" => :embedding,
+    "document" => :document,
+    "uri" => :uri,
+    "metadata" => :metadata
+  }
+
+  @columns [
+    ids: :id,
+    embeddings: :embedding,
+    documents: :document,
+    uris: :uri,
+    metadatas: :metadata
+  ]
+
+  def export(%Collection{} = collection, path, opts) do
+    format = format(path, opts)
+    page_size = Keyword.get(opts, :page_size, 1000)
+    # Written next to the target and renamed once complete, so a failed
+    # export never leaves a truncated file behind under `path`
+    part = path <> ".part"
+
+    pages =
+      collection
+      |> Collection.stream(
+        [include: @include, page_size: page_size] ++
+          Keyword.take(opts, [:where, :where_document, :timeout])
+      )
+      |> Stream.chunk_every(page_size)
+
+    result =
+      with {:ok, writer} <- open_writer(format, part, header(collection)) do
+        written =
+          try do
+            Enum.reduce_while(pages, {:ok, 0}, fn page, {:ok, count} ->
+              case write_page(writer, page) do
+                :ok -> {:cont, {:ok, count + length(page)}}
+                {:error, reason} -> {:halt, {:error, reason}}
+              end
+            end)
+          rescue
+            error in ChromEx.Error -> {:error, error.reason}
+          end
+
+        with :ok <- close_writer(writer), do: written
+      end
+
+    case result do
+      {:ok, count} ->
+        File.rename!(part, path)
+        {:ok, count}
+
+      {:error, reason} ->
+        File.rm(part)
+        {:error, reason}
+    end
+  end
+
+  def import(name, path, opts) do
+    format = format(path, opts)
+    batch_size = Keyword.get(opts, :batch_size, 100)
+    collection_opts = Keyword.take(opts, [:client, :tenant, :database, :timeout])
+
+    with {:ok, reader, header} <- open_reader(format, path, batch_size) do
+      try do
+        with {:ok, header} <- check_header(path, header),
+             {:ok, embedding_function} <- embedding_function(header, opts),
+             # Keyword.get/2 takes the first entry, so nothing in
+             # `collection_opts` can turn this back into get-or-create and
+             # write the file into a collection that already exists
+             {:ok, collection} <-
+               Collection.create(
+                 name,
+                 [
+                   get_or_create: false,
+                   configuration: header["configuration"],
+                   metadata: header["metadata"],
+                   embedding_function: embedding_function
+                 ] ++ collection_opts
+               ) do
+          ingest_opts =
+            [mode: :add, halt_on_error: true, batch_size: batch_size] ++
+              Keyword.take(opts, [:max_concurrency, :timeout])
+
+          result =
+            try do
+              Collection.ingest(collection, records(reader, path), ingest_opts)
+            catch
+              {__MODULE__, reason} -> {:error, reason}
+            end
+
+          case result do
+            {:ok, %{failures: []}} ->
+              {:ok, collection}
+
+            # `create` fails on an existing name, so the collection was made
+            # by this import and dropping it loses nothing but the records
+            # imported so far
+            {:ok, %{failures: [%{reason: reason} | _]}} ->
+              Collection.delete(name, collection_opts)
+              {:error, reason}
+
+            {:error, reason} ->
+              Collection.delete(name, collection_opts)
+              {:error, reason}
+          end
+        end
+      after
+        close_reader(reader)
+      end
+    end
+  end
+
+  defp format(path, opts) do
+    Keyword.get_lazy(opts, :format, fn ->
+      Map.get(@extensions, path |> Path.extname() |> String.downcase(), :jsonl)
+    end)
+  end
+
+  defp header(collection) do
+    %{
+      "chromex_export" => @version,
+      "name" => collection.name,
+      "metadata" => json_metadata(collection.metadata),
+      "configuration" => collection.configuration,
+      "embedding_function" => embedding_function_entry(collection),
+      "exported_at" => DateTime.to_iso8601(DateTime.utc_now())
+    }
+  end
+
+  # The function persisted with the collection, even when it is not
+  # registered here
+  defp embedding_function_entry(%Collection{embedding_function: nil} = collection),
+    do: collection.configuration && collection.configuration["embedding_function"]
+
+  defp embedding_function_entry(%Collection{embedding_function: embedding_function}),
+    do: EmbeddingFunction.to_configuration(embedding_function)
+
+  defp check_header(_path, %{"chromex_export" => @version} = header), do: {:ok, header}
+
+  defp check_header(path, %{"chromex_export" => version}),
+    do: {:error, {:invalid_export, path, "unsupported export version #{inspect(version)}"}}
+
+  defp check_header(path, _header),
+    do: {:error, {:invalid_export, path, "missing ChromEx export header"}}
+
+  defp embedding_function(header, opts) do
+    case Keyword.fetch(opts, :embedding_function) do
+      {:ok, embedding_function} -> {:ok, embedding_function}
+      :error -> EmbeddingFunction.from_configuration(header["embedding_function"])
+    end
+  end
+
+  ## Writing
+
+  defp open_writer(:jsonl, path, header) do
+    with {:ok, file} <- File.open(path, [:write, :binary]) do
+      IO.binwrite(file, [Jason.encode_to_iodata!(header), ?\n])
+      {:ok, {:jsonl, file}}
+    end
+  end
+
+  defp open_writer(format, path, header) do
+    with {:ok, writer} <- Native.open_export(path, format, Jason.encode!(header)) do
+      {:ok, {:native, writer}}
+    end
+  end
+
+  defp write_page({:jsonl, file}, records) do
+    IO.binwrite(
+      file,
+      Enum.map(records, fn record ->
+        json =
+          Map.new(record, fn
+            {:metadata, metadata} -> {"metadata", json_metadata(metadata)}
+            {key, value} -> {Atom.to_string(key), value}
+          end)
+
+        [Jason.encode_to_iodata!(json), ?\n]
+      end)
+    )
+  end
+
+  defp write_page({:native, writer}, records) do
+    columns =
+      Map.new(@columns, fn {column, field} ->
+        values =
+          Enum.map(records, fn record ->
+            case {field, Map.get(record, field)} do
+              {:metadata, metadata} when is_map(metadata) ->
+                Jason.encode!(json_metadata(metadata))
+
+              {_field, value} ->
+                value
+            end
+          end)
+
+        {column, if(Enum.all?(values, &is_nil/1), do: nil, else: values)}
+      end)
+
+    Native.write_export(writer, columns)
+  end
+
+  defp close_writer({:jsonl, file}), do: File.close(file)
+  defp close_writer({:native, writer}), do: Native.finish_export(writer)
+
+  defp close_reader({:jsonl, file}), do: File.close(file)
+  defp close_reader({:native, _reader}), do: :ok
+
+  defp json_metadata(nil), do: nil
+
+  defp json_metadata(metadata) do
+    Map.new(metadata, fn
+      {key, %SparseVector{} = value} -> {key, SparseVector.to_native(value)}
+      {key, value} -> {key, value}
+    end)
+  end
+
+  ## Reading
+
+  defp open_reader(:jsonl, path, _batch_size) do
+    with {:ok, file} <- File.open(path, [:read, :binary]) do
+      case IO.binread(file, :line) do
+        line when is_binary(line) ->
+          case Jason.decode(line) do
+            {:ok, header} when is_map(header) -> {:ok, {:jsonl, file}, header}
+            _ -> {:ok, {:jsonl, file}, nil}
+          end
+
+        _eof ->
+          {:ok, {:jsonl, file}, nil}
+      end
+    end
+  end
+
+  defp open_reader(format, path, batch_size) do
+    with {:ok, reader, header} <- Native.open_import(path, format, batch_size) do
+      {:ok, {:native, reader}, Jason.decode!(header)}
+    end
+  end
+
+  # Read errors are thrown out of the stream and returned by import/3
+  defp records({:jsonl, file}, path) do
+    file
+    |> IO.binstream(:line)
+    |> Stream.with_index(2)
+    |> Stream.reject(fn {line, _number} -> String.trim(line) == "" end)
+    |> Stream.map(fn {line, number} ->
+      case Jason.decode(line) do
+        {:ok, %{"id" => id} = record} when is_binary(id) ->
+          for {key, field} <- @fields, Map.has_key?(record, key), into: %{} do
+            {field, record[key]}
+          end
+
+        _ ->
+          throw({__MODULE__, {:invalid_export, path, "line #{number} is not a record"}})
+      end
+    end)
+  end
+
+  defp records({:native, reader}, _path) do
+    Stream.resource(
+      fn -> reader end,
+      fn reader ->
+        case Native.read_import(reader) do
+          {:ok, nil} -> {:halt, reader}
+          {:ok, columns} -> {rows(columns), reader}
+          {:error, reason} -> throw({__MODULE__, reason})
+        end
+      end,
+      fn _reader -> :ok end
+    )
+  end
+
+  defp rows(columns) do
+    {fields, values} =
+      @columns
+      |> Enum.reject(fn {column, _field} -> is_nil(columns[column]) end)
+      |> Enum.map(fn
+        {:metadatas, field} -> {field, Enum.map(columns.metadatas, &(&1 && Jason.decode!(&1)))}
+        {column, field} -> {field, columns[column]}
+      end)
+      |> Enum.unzip()
+
+    values
+    |> Enum.zip()
+    |> Enum.map(&(fields |> Enum.zip(Tuple.to_list(&1)) |> Map.new()))
+  end
+end
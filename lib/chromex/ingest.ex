@@ -0,0 +1,142 @@
+defmodule ChromEx.Ingest do
+  @moduledoc false
+  # Implementation of ChromEx.Collection.ingest/3: batches an enumerable of
+  # records and writes the batches concurrently. Results are taken in source
+  # order and the checkpoint stops at the first failed batch, so it always
+  # covers a prefix of the source that was written.
+
+  alias ChromEx.Collection
+
+  @fields [
+    id: :ids,
+    document: :documents,
+    metadata: :metadatas,
+    embedding: :embeddings,
+    sparse_embedding: :sparse_embeddings,
+    uri: :uris
+  ]
+
+  @write_opts [:timeout, :truncation, :chunk_overlap, :batch_mode, :sparse_key]
+
+  def run(%Collection{} = collection, records, opts) do
+    batch_size = Keyword.get(opts, :batch_size, 100)
+    on_progress = Keyword.get(opts, :on_progress, fn _progress -> :ok end)
+    write = write_fun(Keyword.get(opts, :mode, :upsert))
+    write_opts = Keyword.take(opts, @write_opts)
+    metadata = %{collection: collection.name, collection_id: collection.id}
+    # Set once the checkpoint to resume from is found in the source
+    resumed = :atomics.new(1, [])
+
+    :telemetry.span([:chromex, :ingest], metadata, fn ->
+      initial = %{written: 0, failed: 0, failures: [], batches: 0, checkpoint: opts[:resume_from]}
+
+      summary =
+        records
+        |> Stream.map(&normalize_record/1)
+        |> resume(opts[:resume_from], resumed)
+        |> Stream.chunk_every(batch_size)
+        |> Task.async_stream(&write_batch(collection, write, &1, write_opts, metadata),
+          max_concurrency: Keyword.get(opts, :max_concurrency, System.schedulers_online()),
+          timeout: :infinity
+        )
+        |> Enum.reduce_while(initial, fn {:ok, {ids, result}}, summary ->
+          summary = record_batch(summary, ids, result)
+          on_progress.(Map.delete(summary, :failures))
+
+          if match?({:error, _}, result) and Keyword.get(opts, :halt_on_error, false),
+            do: {:halt, summary},
+            else: {:cont, summary}
+        end)
+        |> Map.update!(:failures, &Enum.reverse/1)
+
+      result =
+        if opts[:resume_from] && :atomics.get(resumed, 1) == 0,
+          do: {:error, {:validation, "resume_from #{inspect(opts[:resume_from])} not found"}},
+          else: {:ok, summary}
+
+      {result, Map.merge(metadata, Map.delete(summary, :failures))}
+    end)
+  end
+
+  defp write_fun(:upsert), do: &Collection.upsert/2
+  defp write_fun(:add), do: &Collection.add/2
+
+  defp normalize_record(record) when is_list(record), do: normalize_record(Map.new(record))
+
+  defp normalize_record(%{id: id} = record) when is_binary(id), do: record
+
+  defp normalize_record(record),
+    do: raise(ArgumentError, "expected a record with a string :id, got: #{inspect(record)}")
+
+  defp resume(records, nil, _resumed), do: records
+
+  # Drops records up to and including the checkpoint
+  defp resume(records, checkpoint, resumed) do
+    Stream.transform(records, false, fn
+      record, true ->
+        {[record], true}
+
+      %{id: ^checkpoint}, false ->
+        :atomics.put(resumed, 1, 1)
+        {[], true}
+
+      _record, false ->
+        {[], false}
+    end)
+  end
+
+  defp write_batch(collection, write, records, write_opts, metadata) do
+    ids = Enum.map(records, & &1.id)
+    start = System.monotonic_time()
+
+    result =
+      case batch_opts(records) do
+        {:ok, batch} -> write.(collection, batch ++ write_opts)
+        {:error, reason} -> {:error, reason}
+      end
+
+    :telemetry.execute(
+      [:chromex, :ingest, :batch],
+      %{duration: System.monotonic_time() - start, count: length(ids)},
+      Map.merge(metadata, %{first_id: hd(ids), last_id: List.last(ids), result: result})
+    )
+
+    {ids, result}
+  end
+
+  # One list per field, omitted when no record in the batch has it
+  defp batch_opts(records) do
+    Enum.reduce_while(@fields, {:ok, []}, fn {field, key}, {:ok, batch} ->
+      values = Enum.map(records, &Map.get(&1, field))
+
+      cond do
+        Enum.all?(values, &is_nil/1) ->
+          {:cont, {:ok, batch}}
+
+        field == :embedding and Enum.any?(values, &is_nil/1) ->
+          {:halt, {:error, {:validation, "records in a batch must all have embeddings or none"}}}
+
+        true ->
+          {:cont, {:ok, [{key, values} | batch]}}
+      end
+    end)
+  end
+
+  defp record_batch(summary, ids, :ok) do
+    %{
+      summary
+      | written: summary.written + length(ids),
+        batches: summary.batches + 1,
+        checkpoint: if(summary.failed == 0, do: List.last(ids), else: summary.checkpoint)
+    }
+  end
+
+  defp record_batch(summary, ids, {:error, reason}) do
+    %{
+      summary
+      | failed: summary.failed + length(ids),
+        failures: [%{ids: ids, reason: reason} | summary.failures],
+        batches: summary.batches + 1
+    }
+  end
+end
@@ -1,48 +1,151 @@
 defmodule ChromEx.Native do
   @moduledoc false
 
+  # The native embeddings backend needs the crate's `embeddings` feature,
+  # Parquet and Arrow IPC exports its `arrow` feature; the test suite needs
+  # `test_hooks`.
+  @features Enum.concat([
+              if(Application.compile_env(:chromex, :embeddings_backend, :ortex) == :native,
+                do: ["embeddings"],
+                else: []
+              ),
+              if(Application.compile_env(:chromex, :arrow_exports, false),
+                do: ["arrow"],
+                else: []
+              ),
+              if(Mix.env() == :test, do: ["test_hooks"], else: [])
+            ])
+
   use Rustler,
     otp_app: :chromex,
-    crate: "chromex_native"
-
-  def init(_allow_reset, _persist_path, _hnsw_cache_size),
+    crate: "chromex_native",
+    features: @features
+
+  @default_timeout 60_000
+
+  @doc """
+  Invokes an asynchronous NIF and waits for its reply.
+
+  `fun` receives a reply target and must call one of the NIFs below with it.
+  The NIF runs the operation on the native tokio runtime and sends its
+  result to a relay process, which forwards it to the caller through an
+  alias. Waits up to `opts[:timeout]` milliseconds (defaulting to the
+  `:timeout` application env, then 60 seconds); the alias is dropped on
+  timeout, so a reply arriving later never reaches the caller's mailbox.
+  """
+  @spec call(({pid(), reference()} -> :ok | {:error, term()}), keyword()) :: term()
+  def call(fun, opts \\ []) do
+    default_timeout = Application.get_env(:chromex, :timeout, @default_timeout)
+    timeout = Keyword.get(opts, :timeout, default_timeout)
+    ref = make_ref()
+    reply_to = Process.alias([:reply])
+    relay = spawn(fn -> relay(ref, reply_to, timeout) end)
+
+    result =
+      try do
+        fun.({relay, ref})
+      catch
+        kind, reason ->
+          stop_relay(relay, reply_to)
+          :erlang.raise(kind, reason, __STACKTRACE__)
+      end
+
+    case result do
+      :ok ->
+        receive do
+          {^ref, reply} -> reply
+        after
+          timeout ->
+            stop_relay(relay, reply_to)
+
+            # Forwarded just before the alias was dropped
+            receive do
+              {^ref, _reply} -> :ok
+            after
+              0 -> :ok
+            end
+
+            {:error, :timeout}
+        end
+
+      {:error, reason} ->
+        stop_relay(relay, reply_to)
+        {:error, reason}
+    end
+  end
+
+  # The NIF cannot send to an alias, so it replies here
+  defp relay(ref, reply_to, timeout) do
+    receive do
+      {^ref, reply} -> send(reply_to, {ref, reply})
+    after
+      timeout -> :ok
+    end
+  end
+
+  defp stop_relay(relay, reply_to) do
+    Process.unalias(reply_to)
+    Process.exit(relay, :kill)
+  end
+
+  def init(_allow_reset, _persist_path, _hnsw_cache_size_mb, _hnsw_cache_size),
     do: :erlang.nif_error(:nif_not_loaded)
 
   def heartbeat(), do: :erlang.nif_error(:nif_not_loaded)
   def get_version(), do: :erlang.nif_error(:nif_not_loaded)
   def get_max_batch_size(_resource), do: :erlang.nif_error(:nif_not_loaded)
+  def get_hnsw_cache_size(_resource), do: :erlang.nif_error(:nif_not_loaded)
+  def native_embeddings_available(), do: :erlang.nif_error(:nif_not_loaded)
+  def load_embedder(_resource, _name, _spec), do: :erlang.nif_error(:nif_not_loaded)
+  def embed(_resource, _ref, _model, _texts, _input), do: :erlang.nif_error(:nif_not_loaded)
+  def token_offsets(_resource, _ref, _model, _texts), do: :erlang.nif_error(:nif_not_loaded)
+  def backup(_resource, _ref, _dest), do: :erlang.nif_error(:nif_not_loaded)
+  def verify_backup(_dir), do: :erlang.nif_error(:nif_not_loaded)
+  def close(_resource, _timeout_ms), do: :erlang.nif_error(:nif_not_loaded)
+  def test_panic(_resource, _ref, _at), do: :erlang.nif_error(:nif_not_loaded)
+  def native_arrow_available(), do: :erlang.nif_error(:nif_not_loaded)
+  def open_export(_path, _format, _header), do: :erlang.nif_error(:nif_not_loaded)
+  def write_export(_writer, _records), do: :erlang.nif_error(:nif_not_loaded)
+  def finish_export(_writer), do: :erlang.nif_error(:nif_not_loaded)
+  def open_import(_path, _format, _batch_size), do: :erlang.nif_error(:nif_not_loaded)
+  def read_import(_reader), do: :erlang.nif_error(:nif_not_loaded)
 
   def create_collection(
         _resource,
+        _ref,
         _name,
         _config,
+        _embedding_function,
         _metadata,
+        _schema,
         _get_or_create,
         _tenant,
         _database
       ),
       do: :erlang.nif_error(:nif_not_loaded)
 
-  def get_collection(_resource, _name, _tenant, _database),
+  def get_collection(_resource, _ref, _name, _tenant, _database),
     do: :erlang.nif_error(:nif_not_loaded)
 
-  def delete_collection(_resource, _name, _tenant, _database),
+  def delete_collection(_resource, _ref, _name, _tenant, _database),
     do: :erlang.nif_error(:nif_not_loaded)
 
-  def list_collections(_resource, _limit, _offset, _tenant, _database),
+  def list_collections(_resource, _ref, _limit, _offset, _tenant, _database),
     do: :erlang.nif_error(:nif_not_loaded)
 
-  def count_collections(_resource, _tenant, _database),
+  def count_collections(_resource, _ref, _tenant, _database),
     do: :erlang.nif_error(:nif_not_loaded)
 
-  def update_collection(_resource, _collection_id, _new_name, _new_metadata, _new_config),
+  def update_collection(_resource, _ref, _collection_id, _new_name, _new_metadata, _new_config),
     do: :erlang.nif_error(:nif_not_loaded)
 
   def add(
         _resource,
+        _ref,
         _ids,
         _collection_id,
         _embeddings,
+        _embedding_model,
         _metadatas,
         _documents,
         _uris,
@@ -53,19 +156,27 @@ defmodule ChromEx.Native do
 
   def query(
         _resource,
+        _ref,
         _collection_id,
         _query_embeddings,
+        _query_texts,
+        _embedding_model,
         _n_results,
         _where,
         _where_document,
         _include,
+        _packed_embeddings,
         _tenant,
         _database
       ),
       do: :erlang.nif_error(:nif_not_loaded)
 
+  def search(_resource, _ref, _collection_id, _searches, _tenant, _database),
+    do: :erlang.nif_error(:nif_not_loaded)
+
   def get(
         _resource,
+        _ref,
         _collection_id,
         _ids,
         _where,
@@ -73,6 +184,22 @@ defmodule ChromEx.Native do
         _offset,
         _where_document,
         _include,
+        _packed_embeddings,
+        _tenant,
+        _database
+      ),
+      do: :erlang.nif_error(:nif_not_loaded)
+
+  def get_page(
+        _resource,
+        _ref,
+        _collection_id,
+        _after,
+        _limit,
+        _where,
+        _where_document,
+        _include,
+        _packed_embeddings,
         _tenant,
         _database
       ),
@@ -80,6 +207,7 @@ defmodule ChromEx.Native do
 
   def update(
         _resource,
+        _ref,
         _collection_id,
         _ids,
         _embeddings,
@@ -93,9 +221,11 @@ defmodule ChromEx.Native do
 
   def upsert(
         _resource,
+        _ref,
         _collection_id,
         _ids,
         _embeddings,
+        _embedding_model,
         _metadatas,
         _documents,
         _uris,
@@ -104,19 +234,21 @@ defmodule ChromEx.Native do
       ),
       do: :erlang.nif_error(:nif_not_loaded)
 
-  def delete(_resource, _collection_id, _ids, _where, _where_document, _tenant, _database),
+  def delete(_resource, _ref, _collection_id, _ids, _where, _where_document, _tenant, _database),
     do: :erlang.nif_error(:nif_not_loaded)
 
-  def count(_resource, _collection_id, _tenant, _database),
+  def count(_resource, _ref, _collection_id, _tenant, _database),
     do: :erlang.nif_error(:nif_not_loaded)
 
-  def create_database(_resource, _name, _tenant), do: :erlang.nif_error(:nif_not_loaded)
-  def get_database(_resource, _name, _tenant), do: :erlang.nif_error(:nif_not_loaded)
-  def delete_database(_resource, _name, _tenant), do: :erlang.nif_error(:nif_not_loaded)
-  def list_databases(_resource, _limit, _offset, _tenant), do: :erlang.nif_error(:nif_not_loaded)
+  def create_database(_resource, _ref, _name, _tenant), do: :erlang.nif_error(:nif_not_loaded)
+  def get_database(_resource, _ref, _name, _tenant), do: :erlang.nif_error(:nif_not_loaded)
+  def delete_database(_resource, _ref, _name, _tenant), do: :erlang.nif_error(:nif_not_loaded)
+
+  def list_databases(_resource, _ref, _limit, _offset, _tenant),
+    do: :erlang.nif_error(:nif_not_loaded)
 
-  def create_tenant(_resource, _name), do: :erlang.nif_error(:nif_not_loaded)
-  def get_tenant(_resource, _name), do: :erlang.nif_error(:nif_not_loaded)
+  def create_tenant(_resource, _ref, _name), do: :erlang.nif_error(:nif_not_loaded)
+  def get_tenant(_resource, _ref, _name), do: :erlang.nif_error(:nif_not_loaded)
 
-  def reset(_resource), do: :erlang.nif_error(:nif_not_loaded)
+  def reset(_resource, _ref), do: :erlang.nif_error(:nif_not_loaded)
 end
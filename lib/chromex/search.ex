@@ -0,0 +1,276 @@
+defmodule ChromEx.Search do
+  @moduledoc """
+  Builder for Chroma search payloads, run with `ChromEx.Collection.search/3`
+
+  A search combines a filter, a rank expression, a limit and the keys to
+  select. Rank expressions are evaluated by Chroma, so scores such as a
+  weighted sum of several KNN rankings come back already ordered.
+
+  ## Examples
+
+      alias ChromEx.Search
+
+      Search.new()
+      |> Search.where(%{"category" => "tech"})
+      |> Search.where_document(%{"$contains" => "elixir"})
+      |> Search.rank(
+        Search.weighted_sum([
+          {Search.knn(title_embedding, key: "#embedding"), 0.7},
+          {Search.knn(body_embedding, key: "#embedding"), 0.3}
+        ])
+      )
+      |> Search.limit(10)
+      |> Search.select(["#document", "#score", "title"])
+
+  ## Rank expressions
+
+  `knn/2` scores records by distance to a query vector; lower scores rank
+  first. Expressions are combined with `sum/1`, `sub/2`, `mul/1`, `div/2`,
+  `max/1`, `min/1`, `abs/1`, `exp/1`, `log/1` and constants from `val/1`.
+  `rrf/2` builds reciprocal rank fusion out of these.
+  """
+
+  import Kernel, except: [abs: 1, div: 2]
+
+  @compile {:no_warn_undefined, Nx}
+
+  defstruct where: nil,
+            where_document: nil,
+            ids: nil,
+            rank: nil,
+            limit: nil,
+            offset: 0,
+            select: []
+
+  @type rank_expression :: map()
+
+  @type t :: %__MODULE__{
+          where: map() | nil,
+          where_document: map() | nil,
+          ids: [String.t()] | nil,
+          rank: rank_expression() | nil,
+          limit: pos_integer() | nil,
+          offset: non_neg_integer(),
+          select: [String.t()]
+        }
+
+  @doc """
+  Creates an empty search
+  """
+  @spec new() :: t()
+  def new, do: %__MODULE__{}
+
+  @doc """
+  Filters records by metadata, using the same operators as `:where` in
+  `ChromEx.Collection.query/3`
+  """
+  @spec where(t(), map()) :: t()
+  def where(%__MODULE__{} = search, where), do: %{search | where: where}
+
+  @doc """
+  Filters records by document contents (`$contains`, `$not_contains`, `$regex`)
+  """
+  @spec where_document(t(), map()) :: t()
+  def where_document(%__MODULE__{} = search, where_document),
+    do: %{search | where_document: where_document}
+
+  @doc """
+  Restricts the search to the given record IDs
+  """
+  @spec ids(t(), [String.t()]) :: t()
+  def ids(%__MODULE__{} = search, ids), do: %{search | ids: ids}
+
+  @doc """
+  Sets the rank expression used to score and order results
+  """
+  @spec rank(t(), rank_expression()) :: t()
+  def rank(%__MODULE__{} = search, rank), do: %{search | rank: rank}
+
+  @doc """
+  Sets how many results to return, and optionally how many to skip
+  """
+  @spec limit(t(), pos_integer(), keyword()) :: t()
+  def limit(%__MODULE__{} = search, limit, opts \\ []) do
+    %{search | limit: limit, offset: Keyword.get(opts, :offset, 0)}
+  end
+
+  @doc """
+  Sets the keys returned for each result: `"#id"`, `"#document"`,
+  `"#embedding"`, `"#metadata"`, `"#score"` or a metadata key
+  """
+  @spec select(t(), [String.t() | atom()]) :: t()
+  def select(%__MODULE__{} = search, keys), do: %{search | select: Enum.map(keys, &to_string/1)}
+
+  @doc """
+  Scores records by distance to `query`.
+
+  ## Options
+
+    * `:key` - the field to search, `"#embedding"` (default) or, for a
+      `ChromEx.SparseVector` query, the metadata key holding sparse vectors
+    * `:limit` - how many nearest neighbours to consider (default 16)
+    * `:default` - score for records outside the nearest neighbours; they are
+      dropped when `nil` (default)
+    * `:return_rank` - use the neighbour's rank (0, 1, ...) instead of its
+      distance as the score (default `false`)
+  """
+  @spec knn([float()] | Nx.Tensor.t() | ChromEx.SparseVector.t(), keyword()) :: rank_expression()
+  def knn(query, opts \\ []) do
+    knn =
+      %{
+        "query" => knn_query(query),
+        "key" => Keyword.get(opts, :key, "#embedding"),
+        "limit" => Keyword.get(opts, :limit, 16),
+        "return_rank" => Keyword.get(opts, :return_rank, false)
+      }
+      |> put_present("default", Keyword.get(opts, :default))
+
+    %{"$knn" => knn}
+  end
+
+  @doc """
+  A constant score
+  """
+  @spec val(number()) :: rank_expression()
+  def val(value) when is_number(value), do: %{"$val" => value}
+
+  @doc """
+  Sum of the given expressions
+  """
+  @spec sum([rank_expression() | number()]) :: rank_expression()
+  def sum(expressions), do: %{"$sum" => Enum.map(expressions, &expression/1)}
+
+  @doc """
+  `left - right`
+  """
+  @spec sub(rank_expression() | number(), rank_expression() | number()) :: rank_expression()
+  def sub(left, right),
+    do: %{"$sub" => %{"left" => expression(left), "right" => expression(right)}}
+
+  @doc """
+  Product of the given expressions
+  """
+  @spec mul([rank_expression() | number()]) :: rank_expression()
+  def mul(expressions), do: %{"$mul" => Enum.map(expressions, &expression/1)}
+
+  @doc """
+  `left / right`
+  """
+  @spec div(rank_expression() | number(), rank_expression() | number()) :: rank_expression()
+  def div(left, right),
+    do: %{"$div" => %{"left" => expression(left), "right" => expression(right)}}
+
+  @doc """
+  Largest of the given expressions
+  """
+  @spec max([rank_expression() | number()]) :: rank_expression()
+  def max(expressions), do: %{"$max" => Enum.map(expressions, &expression/1)}
+
+  @doc """
+  Smallest of the given expressions
+  """
+  @spec min([rank_expression() | number()]) :: rank_expression()
+  def min(expressions), do: %{"$min" => Enum.map(expressions, &expression/1)}
+
+  @doc """
+  Absolute value of an expression
+  """
+  @spec abs(rank_expression()) :: rank_expression()
+  def abs(expression), do: %{"$abs" => expression(expression)}
+
+  @doc """
+  Exponential of an expression
+  """
+  @spec exp(rank_expression()) :: rank_expression()
+  def exp(expression), do: %{"$exp" => expression(expression)}
+
+  @doc """
+  Natural logarithm of an expression
+  """
+  @spec log(rank_expression()) :: rank_expression()
+  def log(expression), do: %{"$log" => expression(expression)}
+
+  @doc """
+  Weighted sum of expressions, given as `{expression, weight}` pairs
+  """
+  @spec weighted_sum([{rank_expression(), number()}]) :: rank_expression()
+  def weighted_sum(weighted) do
+    sum(Enum.map(weighted, fn {expression, weight} -> mul([expression, val(weight)]) end))
+  end
+
+  @doc """
+  Reciprocal rank fusion of KNN rankings: `-sum(weight / (k + rank))`.
+
+  `ranks` must be `knn/2` expressions with `return_rank: true`. The result is
+  negated so that, like distances, lower scores rank first.
+
+  ## Options
+
+    * `:k` - smoothing constant (default 60)
+    * `:weights` - one weight per rank (default 1.0 each)
+    * `:normalize` - scale weights to sum to 1 (default `false`)
+  """
+  @spec rrf([rank_expression()], keyword()) :: rank_expression()
+  def rrf([_ | _] = ranks, opts \\ []) do
+    k = Keyword.get(opts, :k, 60)
+    weights = Keyword.get(opts, :weights, List.duplicate(1.0, length(ranks)))
+
+    if length(weights) != length(ranks) do
+      raise ArgumentError, "expected #{length(ranks)} weights, got #{length(weights)}"
+    end
+
+    weights =
+      if Keyword.get(opts, :normalize, false) do
+        total = Enum.sum(weights)
+        Enum.map(weights, &(&1 / total))
+      else
+        weights
+      end
+
+    terms =
+      ranks
+      |> Enum.zip(weights)
+      |> Enum.map(fn {rank, weight} -> div(val(weight), sum([val(k), rank])) end)
+
+    mul([val(-1), sum(terms)])
+  end
+
+  @doc """
+  Converts a search to the payload sent to Chroma
+  """
+  @spec to_payload(t()) :: map()
+  def to_payload(%__MODULE__{} = search) do
+    %{
+      "filter" => filter(search),
+      "rank" => search.rank,
+      "limit" => put_present(%{"offset" => search.offset}, "limit", search.limit),
+      "select" => %{"keys" => search.select}
+    }
+  end
+
+  defp filter(%__MODULE__{where: where, where_document: where_document, ids: ids}) do
+    clauses =
+      [
+        ids && %{"#id" => %{"$in" => ids}},
+        where,
+        where_document && %{"#document" => where_document}
+      ]
+      |> Enum.reject(&is_nil/1)
+
+    case clauses do
+      [] -> nil
+      [clause] -> clause
+      clauses -> %{"$and" => clauses}
+    end
+  end
+
+  defp knn_query(%ChromEx.SparseVector{} = query), do: ChromEx.SparseVector.to_native(query)
+  defp knn_query(query) when is_struct(query, Nx.Tensor), do: Nx.to_flat_list(query)
+  defp knn_query(query), do: query
+
+  defp expression(value) when is_number(value), do: val(value)
+  defp expression(expression) when is_map(expression), do: expression
+
+  defp put_present(map, _key, nil), do: map
+  defp put_present(map, key, value), do: Map.put(map, key, value)
+end
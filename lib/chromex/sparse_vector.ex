@@ -0,0 +1,57 @@
+defmodule ChromEx.SparseVector do
+  @moduledoc """
+  A sparse vector, such as BM25 or SPLADE term weights
+
+  Sparse vectors are stored as metadata values under a key that has a sparse
+  vector index (see the `:sparse_vector_index` option of
+  `ChromEx.Collection.create/2`) and are searched with `ChromEx.Search.knn/2`
+  or `ChromEx.Collection.hybrid_query/2`.
+
+  ## Examples
+
+      ChromEx.SparseVector.new([4, 17, 203], [0.8, 0.3, 1.2])
+
+      ChromEx.SparseVector.from_map(%{4 => 0.8, 17 => 0.3, 203 => 1.2})
+  """
+
+  @enforce_keys [:indices, :values]
+  defstruct [:indices, :values]
+
+  @type t :: %__MODULE__{indices: [non_neg_integer()], values: [float()]}
+
+  @doc """
+  Creates a sparse vector from parallel lists of indices and values.
+  Entries are sorted by index.
+  """
+  @spec new([non_neg_integer()], [number()]) :: t()
+  def new(indices, values) when length(indices) == length(values) do
+    {indices, values} =
+      indices
+      |> Enum.zip(values)
+      |> Enum.sort_by(&elem(&1, 0))
+      |> Enum.unzip()
+
+    %__MODULE__{indices: indices, values: Enum.map(values, &(&1 * 1.0))}
+  end
+
+  def new(indices, values) do
+    raise ArgumentError,
+          "expected as many values as indices, got #{length(indices)} indices and #{length(values)} values"
+  end
+
+  @doc """
+  Creates a sparse vector from a map of index to value
+  """
+  @spec from_map(%{non_neg_integer() => number()}) :: t()
+  def from_map(weights) when is_map(weights) do
+    new(Map.keys(weights), Map.values(weights))
+  end
+
+  @doc false
+  # The form sparse vectors are sent to the native side in, both as metadata
+  # values and as KNN queries.
+  @spec to_native(t()) :: map()
+  def to_native(%__MODULE__{indices: indices, values: values}) do
+    %{"#type" => "sparse_vector", "indices" => indices, "values" => values}
+  end
+end
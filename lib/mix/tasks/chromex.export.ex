@@ -0,0 +1,49 @@
+defmodule Mix.Tasks.Chromex.Export do
+  @shortdoc "Exports a collection to a JSONL, Parquet or Arrow file"
+
+  @moduledoc """
+  Writes every record of a collection, with its configuration, to a file.
+
+      mix chromex.export NAME PATH [--format FORMAT] [--tenant T] [--database D]
+
+  The collection is read from the store configured for `:chromex`, e.g. its
+  `:persist_path`. See `ChromEx.Collection.export/3` for the file formats.
+
+  ## Options
+
+    * `--format` - `jsonl`, `parquet` or `arrow`; inferred from the extension
+      of `PATH` by default
+    * `--page-size` - records read and written at a time
+    * `--tenant`, `--database` - where the collection is
+  """
+
+  use Mix.Task
+
+  alias ChromEx.Collection
+
+  @switches [format: :string, page_size: :integer, tenant: :string, database: :string]
+
+  @impl true
+  def run(args) do
+    {opts, args} = OptionParser.parse!(args, strict: @switches)
+
+    [name, path] =
+      case args do
+        [_name, _path] -> args
+        _ -> Mix.raise("Usage: mix chromex.export NAME PATH [--format FORMAT]")
+      end
+
+    opts = Mix.Tasks.Chromex.Import.parse_format(opts)
+    Mix.Task.run("app.start")
+
+    with {:ok, collection} <- Collection.get(name, Keyword.take(opts, [:tenant, :database])),
+         {:ok, count} <- Collection.export(collection, path, opts) do
+      Mix.shell().info("Exported #{count} records from #{name} to #{path}")
+    else
+      {:error, reason} ->
+        Mix.raise(
+          "Could not export #{name}: " <> Exception.message(%ChromEx.Error{reason: reason})
+        )
+    end
+  end
+end
@@ -0,0 +1,67 @@
+defmodule Mix.Tasks.Chromex.Import do
+  @shortdoc "Creates a collection from a file written by chromex.export"
+
+  @moduledoc """
+  Creates a collection from an export, with the configuration and embedding
+  function recorded in it.
+
+      mix chromex.import NAME PATH [--format FORMAT] [--tenant T] [--database D]
+
+  The collection is created in the store configured for `:chromex`, and
+  must not exist yet. See `ChromEx.Collection.import/3`.
+
+  ## Options
+
+    * `--format` - `jsonl`, `parquet` or `arrow`; inferred from the extension
+      of `PATH` by default
+    * `--batch-size` - records per write
+    * `--tenant`, `--database` - where to create the collection
+  """
+
+  use Mix.Task
+
+  alias ChromEx.Collection
+
+  @switches [format: :string, batch_size: :integer, tenant: :string, database: :string]
+  @formats ~w(jsonl parquet arrow)
+
+  @impl true
+  def run(args) do
+    {opts, args} = OptionParser.parse!(args, strict: @switches)
+
+    [name, path] =
+      case args do
+        [_name, _path] -> args
+        _ -> Mix.raise("Usage: mix chromex.import NAME PATH [--format FORMAT]")
+      end
+
+    opts = parse_format(opts)
+    Mix.Task.run("app.start")
+
+    with {:ok, collection} <- Collection.import(name, path, opts),
+         {:ok, count} <- Collection.count(collection) do
+      Mix.shell().info("Imported #{count} records from #{path} into #{name}")
+    else
+      {:error, reason} ->
+        Mix.raise(
+          "Could not import #{path}: " <> Exception.message(%ChromEx.Error{reason: reason})
+        )
+    end
+  end
+
+  @doc false
+  def parse_format(opts) do
+    case Keyword.fetch(opts, :format) do
+      {:ok, format} when format in @formats ->
+        Keyword.put(opts, :format, String.to_atom(format))
+
+      {:ok, format} ->
+        Mix.raise(
+          "Unknown format #{inspect(format)}, expected one of: #{Enum.join(@formats, ", ")}"
+        )
+
+      :error ->
+        opts
+    end
+  end
+end
@@ -0,0 +1,56 @@
+defmodule Mix.Tasks.Chromex.Models.Fetch do
+  @shortdoc "Downloads embedding models into the model cache"
+
+  @moduledoc """
+  Downloads and verifies embedding models so they can be used offline.
+
+      mix chromex.models.fetch [MODEL...] [--cache-dir DIR]
+
+  Without model names, every registered model with a `:url` and no local
+  `:dir` is fetched. Models already in the cache are left as they are.
+  Run this while building a release and set `config :chromex, offline: true`
+  at runtime so nothing is downloaded in production.
+
+  ## Options
+
+    * `--cache-dir` - directory to download into, overriding the
+      `:model_cache_dir` application env
+  """
+
+  use Mix.Task
+
+  alias ChromEx.Embeddings.Model
+
+  @impl true
+  def run(args) do
+    {opts, names} = OptionParser.parse!(args, strict: [cache_dir: :string])
+
+    Mix.Task.run("app.config")
+    Application.put_env(:chromex, :offline, false)
+
+    if dir = opts[:cache_dir] do
+      Application.put_env(:chromex, :model_cache_dir, dir)
+    end
+
+    names = if names == [], do: downloadable(), else: names
+
+    Enum.each(names, fn name ->
+      with {:ok, model} <- Model.get(name),
+           {:ok, files} <- Model.files(model) do
+        Mix.shell().info("#{name}: #{Path.dirname(files.onnx)}")
+      else
+        {:error, reason} ->
+          Mix.raise(
+            "Could not fetch #{name}: " <> Exception.message(%ChromEx.Error{reason: reason})
+          )
+      end
+    end)
+  end
+
+  defp downloadable do
+    Enum.filter(Model.names(), fn name ->
+      {:ok, model} = Model.get(name)
+      model.url && is_nil(model.dir)
+    end)
+  end
+end
@@ -22,7 +22,7 @@ defmodule ChromEx.MixProject do
 
   def application do
     [
-      extra_applications: [:logger],
+      extra_applications: [:logger, :inets, :ssl, :public_key, :crypto],
       mod: {ChromEx.Application, []}
     ]
   end
@@ -31,10 +31,12 @@ defmodule ChromEx.MixProject do
     [
       {:rustler, "~> 0.37.0", runtime: false},
       {:jason, "~> 1.4"},
-      {:ortex, "~> 0.1.10"},
-      {:tokenizers, "~> 0.5.1"},
-      {:nx, "~> 0.10.0"},
+      # Only needed for the default :ortex embeddings backend and Nx tensors
+      {:ortex, "~> 0.1.10", optional: true},
+      {:tokenizers, "~> 0.5.1", optional: true},
+      {:nx, "~> 0.10.0", optional: true},
       {:nimble_pool, "~> 1.0"},
+      {:telemetry, "~> 1.0"},
       {:ex_doc, ">= 0.0.0", only: :dev, runtime: false}
     ]
   end
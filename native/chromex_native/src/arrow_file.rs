@@ -0,0 +1,328 @@
+//! Collection exports in Apache Parquet and Arrow IPC files. Every file has
+//! the columns `id`, `embedding`, `document`, `uri` and `metadata` (as JSON
+//! text), and keeps the export header, also JSON, in its schema metadata.
+//! The types below are always defined so that the NIF signatures stay the
+//! same; reading and writing files needs the `arrow` feature.
+
+#[cfg(feature = "arrow")]
+use std::collections::HashMap;
+#[cfg(feature = "arrow")]
+use std::fs::File;
+#[cfg(feature = "arrow")]
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "arrow")]
+use arrow_array::builder::{Float32Builder, ListBuilder};
+#[cfg(feature = "arrow")]
+use arrow_array::{
+    Array, ArrayRef, Float32Array, ListArray, RecordBatch, RecordBatchReader, StringArray,
+};
+#[cfg(feature = "arrow")]
+use arrow_ipc::reader::FileReader;
+#[cfg(feature = "arrow")]
+use arrow_ipc::writer::FileWriter;
+#[cfg(feature = "arrow")]
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+#[cfg(feature = "arrow")]
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+#[cfg(feature = "arrow")]
+use parquet::arrow::ArrowWriter;
+#[cfg(feature = "arrow")]
+use parquet::basic::Compression;
+#[cfg(feature = "arrow")]
+use parquet::file::properties::WriterProperties;
+
+use crate::error::ChromexError;
+
+/// Schema metadata key the export header is stored under.
+#[cfg(feature = "arrow")]
+const HEADER_KEY: &str = "chromex.export";
+
+/// Rows per Parquet row group. The writer buffers a whole row group, so this
+/// bounds its memory use.
+#[cfg(feature = "arrow")]
+const ROW_GROUP_SIZE: usize = 8192;
+
+#[derive(rustler::NifUnitEnum, Clone, Copy, Debug)]
+pub enum Format {
+    Parquet,
+    Arrow,
+}
+
+/// A page of records as parallel columns, `nil` for columns no record has.
+/// Metadata is JSON text.
+#[derive(rustler::NifMap, Debug)]
+pub struct Records {
+    pub ids: Vec<String>,
+    pub embeddings: Option<Vec<Vec<f32>>>,
+    pub documents: Option<Vec<Option<String>>>,
+    pub uris: Option<Vec<Option<String>>>,
+    pub metadatas: Option<Vec<Option<String>>>,
+}
+
+#[cfg(feature = "arrow")]
+enum Writer {
+    Parquet(ArrowWriter<File>),
+    Arrow(FileWriter<File>),
+}
+
+/// An export file being written, one page of records at a time.
+pub struct ExportWriter {
+    #[cfg(feature = "arrow")]
+    schema: SchemaRef,
+    /// `None` once the file is finished.
+    #[cfg(feature = "arrow")]
+    writer: Mutex<Option<Writer>>,
+}
+
+#[cfg(feature = "arrow")]
+impl ExportWriter {
+    /// Creates the file at `path`, recording `header` in it.
+    pub fn create(path: &str, format: Format, header: String) -> Result<Self, ChromexError> {
+        let schema = Arc::new(schema(header));
+        let file = File::create(path).map_err(ChromexError::internal)?;
+
+        let writer = match format {
+            Format::Parquet => {
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .set_max_row_group_size(ROW_GROUP_SIZE)
+                    .build();
+                Writer::Parquet(
+                    ArrowWriter::try_new(file, schema.clone(), Some(properties))
+                        .map_err(ChromexError::internal)?,
+                )
+            }
+            Format::Arrow => {
+                Writer::Arrow(FileWriter::try_new(file, &schema).map_err(ChromexError::internal)?)
+            }
+        };
+
+        Ok(ExportWriter { schema, writer: Mutex::new(Some(writer)) })
+    }
+
+    pub fn write(&self, records: Records) -> Result<(), ChromexError> {
+        let batch = record_batch(&self.schema, records)?;
+
+        match self.writer.lock().map_err(|_| poisoned())?.as_mut() {
+            Some(Writer::Parquet(writer)) => writer.write(&batch).map_err(ChromexError::internal),
+            Some(Writer::Arrow(writer)) => writer.write(&batch).map_err(ChromexError::internal),
+            None => Err(finished()),
+        }
+    }
+
+    /// Writes the file's footer. Nothing can be written afterwards.
+    pub fn finish(&self) -> Result<(), ChromexError> {
+        match self.writer.lock().map_err(|_| poisoned())?.take() {
+            Some(Writer::Parquet(writer)) => {
+                writer.close().map(|_| ()).map_err(ChromexError::internal)
+            }
+            Some(Writer::Arrow(mut writer)) => writer.finish().map_err(ChromexError::internal),
+            None => Err(finished()),
+        }
+    }
+}
+
+#[cfg(not(feature = "arrow"))]
+impl ExportWriter {
+    pub fn create(_path: &str, _format: Format, _header: String) -> Result<Self, ChromexError> {
+        Err(arrow_disabled())
+    }
+
+    pub fn write(&self, _records: Records) -> Result<(), ChromexError> {
+        Err(arrow_disabled())
+    }
+
+    pub fn finish(&self) -> Result<(), ChromexError> {
+        Err(arrow_disabled())
+    }
+}
+
+/// An export file being read, one record batch at a time.
+pub struct ImportReader {
+    #[cfg(feature = "arrow")]
+    reader: Mutex<Box<dyn RecordBatchReader + Send>>,
+    /// The export header, as written.
+    pub header: String,
+}
+
+#[cfg(feature = "arrow")]
+impl ImportReader {
+    /// Opens the file at `path`. Parquet files are read `batch_size` rows at
+    /// a time; Arrow IPC files in the batches they were written in.
+    pub fn open(path: &str, format: Format, batch_size: usize) -> Result<Self, ChromexError> {
+        let file = File::open(path).map_err(ChromexError::internal)?;
+
+        let (reader, metadata): (Box<dyn RecordBatchReader + Send>, HashMap<String, String>) =
+            match format {
+                Format::Parquet => {
+                    let builder = ParquetRecordBatchReaderBuilder::try_new(file)
+                        .map_err(ChromexError::validation)?;
+                    let metadata = builder.schema().metadata().clone();
+                    let reader = builder
+                        .with_batch_size(batch_size)
+                        .build()
+                        .map_err(ChromexError::validation)?;
+                    (Box::new(reader), metadata)
+                }
+                Format::Arrow => {
+                    let reader = FileReader::try_new(file, None).map_err(ChromexError::validation)?;
+                    let metadata = reader.schema().metadata().clone();
+                    (Box::new(reader), metadata)
+                }
+            };
+
+        let header = metadata.get(HEADER_KEY).cloned().ok_or_else(|| {
+            ChromexError::Validation(format!("{path} has no ChromEx export header"))
+        })?;
+
+        Ok(ImportReader { reader: Mutex::new(reader), header })
+    }
+
+    /// The next batch of records, or `None` at the end of the file.
+    pub fn next(&self) -> Result<Option<Records>, ChromexError> {
+        match self.reader.lock().map_err(|_| poisoned())?.next() {
+            Some(batch) => records(&batch.map_err(ChromexError::validation)?).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(not(feature = "arrow"))]
+impl ImportReader {
+    pub fn open(_path: &str, _format: Format, _batch_size: usize) -> Result<Self, ChromexError> {
+        Err(arrow_disabled())
+    }
+
+    pub fn next(&self) -> Result<Option<Records>, ChromexError> {
+        Err(arrow_disabled())
+    }
+}
+
+#[cfg(feature = "arrow")]
+fn schema(header: String) -> Schema {
+    Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new(
+            "embedding",
+            DataType::List(Arc::new(Field::new_list_field(DataType::Float32, true))),
+            true,
+        ),
+        Field::new("document", DataType::Utf8, true),
+        Field::new("uri", DataType::Utf8, true),
+        Field::new("metadata", DataType::Utf8, true),
+    ])
+    .with_metadata(HashMap::from([(HEADER_KEY.to_string(), header)]))
+}
+
+#[cfg(feature = "arrow")]
+fn record_batch(schema: &SchemaRef, records: Records) -> Result<RecordBatch, ChromexError> {
+    let count = records.ids.len();
+
+    let mut embeddings = ListBuilder::new(Float32Builder::new());
+    match records.embeddings {
+        Some(rows) => {
+            for row in rows {
+                embeddings.values().append_slice(&row);
+                embeddings.append(true);
+            }
+        }
+        None => (0..count).for_each(|_| embeddings.append_null()),
+    }
+
+    let strings = |values: Option<Vec<Option<String>>>| -> ArrayRef {
+        Arc::new(values.map(StringArray::from).unwrap_or_else(|| StringArray::new_null(count)))
+    };
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(records.ids)),
+        Arc::new(embeddings.finish()),
+        strings(records.documents),
+        strings(records.uris),
+        strings(records.metadatas),
+    ];
+
+    RecordBatch::try_new(schema.clone(), columns).map_err(ChromexError::validation)
+}
+
+#[cfg(feature = "arrow")]
+fn records(batch: &RecordBatch) -> Result<Records, ChromexError> {
+    let ids = strings(batch, "id")?
+        .ok_or_else(|| ChromexError::Validation("export has no ids".to_string()))?
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| ChromexError::Validation("every record must have an id".to_string()))?;
+
+    Ok(Records {
+        ids,
+        embeddings: embeddings(batch)?,
+        documents: strings(batch, "document")?,
+        uris: strings(batch, "uri")?,
+        metadatas: strings(batch, "metadata")?,
+    })
+}
+
+/// The values of the string column `name`, or `None` when the file has no
+/// such column or none of the batch's records has a value in it.
+#[cfg(feature = "arrow")]
+fn strings(batch: &RecordBatch, name: &str) -> Result<Option<Vec<Option<String>>>, ChromexError> {
+    let Some(column) = batch.column_by_name(name) else {
+        return Ok(None);
+    };
+    let column = column
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| ChromexError::Validation(format!("column {name} must hold strings")))?;
+
+    if column.null_count() == column.len() {
+        return Ok(None);
+    }
+    Ok(Some(column.iter().map(|value| value.map(str::to_string)).collect()))
+}
+
+#[cfg(feature = "arrow")]
+fn embeddings(batch: &RecordBatch) -> Result<Option<Vec<Vec<f32>>>, ChromexError> {
+    let Some(column) = batch.column_by_name("embedding") else {
+        return Ok(None);
+    };
+    let column = column.as_any().downcast_ref::<ListArray>().ok_or_else(|| {
+        ChromexError::Validation("column embedding must hold lists of float32".to_string())
+    })?;
+
+    match column.null_count() {
+        0 => (0..column.len())
+            .map(|row| {
+                let values = column.value(row);
+                values
+                    .as_any()
+                    .downcast_ref::<Float32Array>()
+                    .map(|values| values.values().to_vec())
+                    .ok_or_else(|| {
+                        ChromexError::Validation(
+                            "column embedding must hold lists of float32".to_string(),
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some),
+        nulls if nulls == column.len() => Ok(None),
+        _ => Err(ChromexError::Validation(
+            "records in a batch must all have embeddings or none".to_string(),
+        )),
+    }
+}
+
+#[cfg(feature = "arrow")]
+fn finished() -> ChromexError {
+    ChromexError::Validation("the export file is already finished".to_string())
+}
+
+#[cfg(feature = "arrow")]
+fn poisoned() -> ChromexError {
+    ChromexError::poisoned("the export file")
+}
+
+#[cfg(not(feature = "arrow"))]
+fn arrow_disabled() -> ChromexError {
+    ChromexError::Validation("chromex_native was built without the arrow feature".to_string())
+}
@@ -0,0 +1,227 @@
+//! Backups of a store's persist directory: `chroma.db`, copied with the
+//! SQLite online backup API, and the HNSW segment directories, copied file
+//! by file. A `manifest.json` lists every file with its size and SHA-256 so
+//! a backup can be verified before it is restored.
+//!
+//! The caller makes the copy consistent by keeping every other call on the
+//! store waiting while it runs, see `ChromaBindings::gate`, and by having
+//! Chroma apply its log beforehand, see `compact_all`.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Read};
+use std::path::{Component, Path, PathBuf};
+
+use rusqlite::{Connection, DatabaseName, OpenFlags};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::ChromexError;
+
+pub const MANIFEST: &str = "manifest.json";
+const DATABASE: &str = "chroma.db";
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, rustler::NifMap, Debug)]
+pub struct Manifest {
+    pub version: u32,
+    pub chromex_version: String,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    pub files: Vec<FileEntry>,
+}
+
+#[derive(Serialize, Deserialize, rustler::NifMap, Debug)]
+pub struct FileEntry {
+    /// Relative to the backup directory, with `/` separators.
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Copies the store in `source` into `dest`, which must not exist yet or be
+/// empty, and writes its manifest.
+pub fn backup(source: &Path, dest: &Path) -> Result<Manifest, ChromexError> {
+    if fs::read_dir(dest).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(ChromexError::Validation(format!(
+            "backup destination {} is not empty",
+            dest.display()
+        )));
+    }
+    // Checked before anything is created, so a refused destination is not
+    // left behind as an empty directory in the store
+    let source = canonical(source)?;
+    if resolved(dest)?.starts_with(&source) {
+        return Err(ChromexError::Validation(format!(
+            "backup destination {} is inside the store",
+            dest.display()
+        )));
+    }
+    fs::create_dir_all(dest).map_err(ChromexError::internal)?;
+    let dest = canonical(dest)?;
+
+    let database = Connection::open_with_flags(
+        source.join(DATABASE),
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(ChromexError::internal)?;
+    database
+        .backup(DatabaseName::Main, dest.join(DATABASE), None)
+        .map_err(ChromexError::internal)?;
+
+    // Everything else chroma keeps is a segment directory; top-level files
+    // are either the database's WAL or not chroma's
+    for entry in fs::read_dir(&source).map_err(ChromexError::internal)? {
+        let entry = entry.map_err(ChromexError::internal)?;
+        if entry.file_type().map_err(ChromexError::internal)?.is_dir() {
+            copy_dir(&entry.path(), &dest.join(entry.file_name()))?;
+        }
+    }
+
+    let mut files = Vec::new();
+    for path in relative_files(&dest)? {
+        files.push(FileEntry {
+            size: fs::metadata(dest.join(&path)).map_err(ChromexError::internal)?.len(),
+            sha256: sha256(&dest.join(&path))?,
+            path,
+        });
+    }
+
+    let manifest = Manifest {
+        version: VERSION,
+        chromex_version: env!("CARGO_PKG_VERSION").to_string(),
+        created_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(ChromexError::internal)?
+            .as_secs(),
+        files,
+    };
+
+    let file = File::create(dest.join(MANIFEST)).map_err(ChromexError::internal)?;
+    serde_json::to_writer_pretty(BufWriter::new(file), &manifest)
+        .map_err(ChromexError::internal)?;
+
+    Ok(manifest)
+}
+
+/// Reads the manifest of the backup in `dir` and checks that every file it
+/// lists is there, unchanged, and that it includes the database.
+pub fn verify(dir: &Path) -> Result<Manifest, ChromexError> {
+    let invalid = |message: String| {
+        ChromexError::Validation(format!("invalid backup {}: {message}", dir.display()))
+    };
+
+    let file = File::open(dir.join(MANIFEST)).map_err(|e| invalid(format!("{MANIFEST}: {e}")))?;
+    let manifest: Manifest =
+        serde_json::from_reader(file).map_err(|e| invalid(format!("{MANIFEST}: {e}")))?;
+
+    if manifest.version != VERSION {
+        return Err(invalid(format!("unsupported manifest version {}", manifest.version)));
+    }
+    if !manifest.files.iter().any(|entry| entry.path == DATABASE) {
+        return Err(invalid(format!("{DATABASE} is missing from the manifest")));
+    }
+
+    for entry in &manifest.files {
+        let relative = Path::new(&entry.path);
+        if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(invalid(format!("{} is not a relative path", entry.path)));
+        }
+
+        let path = dir.join(relative);
+        let size = fs::metadata(&path).map_err(|e| invalid(format!("{}: {e}", entry.path)))?.len();
+        if size != entry.size {
+            return Err(invalid(format!(
+                "{} is {size} bytes, expected {}",
+                entry.path, entry.size
+            )));
+        }
+        if sha256(&path)? != entry.sha256 {
+            return Err(invalid(format!("{} does not match its checksum", entry.path)));
+        }
+    }
+
+    Ok(manifest)
+}
+
+fn canonical(path: &Path) -> Result<PathBuf, ChromexError> {
+    path.canonicalize()
+        .map_err(|e| ChromexError::Validation(format!("{}: {e}", path.display())))
+}
+
+/// Where `path` would be once created: its nearest existing ancestor,
+/// canonicalized, followed by the components that do not exist yet.
+fn resolved(path: &Path) -> Result<PathBuf, ChromexError> {
+    let path = std::env::current_dir().map_err(ChromexError::internal)?.join(path);
+    let mut missing = Vec::new();
+    let mut existing = path.as_path();
+    while !existing.exists() {
+        match (existing.file_name(), existing.parent()) {
+            (Some(name), Some(parent)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            // `..` or the root, which then exists
+            _ => break,
+        }
+    }
+
+    let mut resolved = canonical(existing)?;
+    for name in missing.into_iter().rev() {
+        resolved.push(name);
+    }
+    Ok(resolved)
+}
+
+fn copy_dir(source: &Path, dest: &Path) -> Result<(), ChromexError> {
+    fs::create_dir_all(dest).map_err(ChromexError::internal)?;
+
+    for entry in fs::read_dir(source).map_err(ChromexError::internal)? {
+        let entry = entry.map_err(ChromexError::internal)?;
+        let target = dest.join(entry.file_name());
+
+        if entry.file_type().map_err(ChromexError::internal)?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target).map_err(ChromexError::internal)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Every file under `root`, relative to it and sorted.
+fn relative_files(root: &Path) -> Result<Vec<String>, ChromexError> {
+    let mut files = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).map_err(ChromexError::internal)? {
+            let path = entry.map_err(ChromexError::internal)?.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if let Ok(relative) = path.strip_prefix(root) {
+                let components: Vec<_> =
+                    relative.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+                files.push(components.join("/"));
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+fn sha256(path: &Path) -> Result<String, ChromexError> {
+    let mut file = File::open(path).map_err(ChromexError::internal)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+
+    loop {
+        match file.read(&mut buffer).map_err(ChromexError::internal)? {
+            0 => break,
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,263 @@
+use chroma_types::{
+    Metadata, MetadataValue, RawWhereFields, SearchPayload, SparseVector, UpdateMetadata,
+    UpdateMetadataValue, Where,
+};
+use rustler::{Binary, MapIterator, Term};
+use serde_json::{Map, Number, Value};
+
+use crate::error::ChromexError;
+
+/// Map keys may be given as atoms or strings, as with `Jason.encode!/1`.
+fn decode_key(term: Term) -> Result<String, ChromexError> {
+    if term.is_atom() {
+        term.atom_to_string().map_err(|_| invalid(term, "map key"))
+    } else {
+        term.decode::<String>().map_err(|_| invalid(term, "map key"))
+    }
+}
+
+fn invalid(term: Term, what: &str) -> ChromexError {
+    ChromexError::Validation(format!("invalid {}: {:?}", what, term))
+}
+
+fn map_entries<'a>(term: Term<'a>) -> Result<MapIterator<'a>, ChromexError> {
+    MapIterator::new(term).ok_or_else(|| invalid(term, "map"))
+}
+
+/// Decodes `%{"#type" => "sparse_vector", "indices" => [...], "values" => [...]}`,
+/// the form `ChromEx.SparseVector` structs are sent in.
+fn sparse_vector(term: Term) -> Result<SparseVector, ChromexError> {
+    let mut tag = None;
+    let mut indices = None;
+    let mut values = None;
+    for (key, item) in map_entries(term)? {
+        match decode_key(key)?.as_str() {
+            "#type" => tag = item.decode::<String>().ok(),
+            "indices" => indices = item.decode::<Vec<u32>>().ok(),
+            "values" => values = item.decode::<Vec<f32>>().ok(),
+            _ => {}
+        }
+    }
+
+    match (tag.as_deref(), indices, values) {
+        (Some("sparse_vector"), Some(indices), Some(values)) if indices.len() == values.len() => {
+            Ok(SparseVector::new(indices, values))
+        }
+        _ => Err(invalid(term, "metadata value")),
+    }
+}
+
+/// Decodes a scalar metadata value. Non-boolean atoms are stored as strings;
+/// the only map accepted is a sparse vector.
+fn decode_scalar(term: Term) -> Result<Option<MetadataValue>, ChromexError> {
+    if term.is_map() {
+        return sparse_vector(term).map(|vector| Some(MetadataValue::SparseVector(vector)));
+    }
+
+    if term.is_number() {
+        if let Ok(i) = term.decode::<i64>() {
+            return Ok(Some(MetadataValue::Int(i)));
+        }
+        return term
+            .decode::<f64>()
+            .map(|f| Some(MetadataValue::Float(f)))
+            .map_err(|_| invalid(term, "metadata value"));
+    }
+
+    if term.is_binary() {
+        return term
+            .decode::<String>()
+            .map(|s| Some(MetadataValue::Str(s)))
+            .map_err(|_| invalid(term, "metadata value"));
+    }
+
+    if term.is_atom() {
+        if let Ok(b) = term.decode::<bool>() {
+            return Ok(Some(MetadataValue::Bool(b)));
+        }
+        let name = term
+            .atom_to_string()
+            .map_err(|_| invalid(term, "metadata value"))?;
+        return Ok(if name == "nil" {
+            None
+        } else {
+            Some(MetadataValue::Str(name))
+        });
+    }
+
+    Err(invalid(term, "metadata value"))
+}
+
+/// Decodes an Elixir map into record or collection metadata.
+pub fn metadata(term: Term) -> Result<Metadata, ChromexError> {
+    let mut metadata = Metadata::new();
+    for (key, value) in map_entries(term)? {
+        let key = decode_key(key)?;
+        match decode_scalar(value)? {
+            Some(value) => {
+                metadata.insert(key, value);
+            }
+            None => {
+                return Err(ChromexError::Validation(format!(
+                    "metadata value for {:?} cannot be nil",
+                    key
+                )))
+            }
+        }
+    }
+    Ok(metadata)
+}
+
+/// Decodes an Elixir map into a metadata update. `nil` values delete the key.
+pub fn update_metadata(term: Term) -> Result<UpdateMetadata, ChromexError> {
+    let mut metadata = UpdateMetadata::new();
+    for (key, value) in map_entries(term)? {
+        let value = match decode_scalar(value)? {
+            Some(MetadataValue::Bool(b)) => UpdateMetadataValue::Bool(b),
+            Some(MetadataValue::Int(i)) => UpdateMetadataValue::Int(i),
+            Some(MetadataValue::Float(f)) => UpdateMetadataValue::Float(f),
+            Some(MetadataValue::Str(s)) => UpdateMetadataValue::Str(s),
+            Some(MetadataValue::SparseVector(v)) => UpdateMetadataValue::SparseVector(v),
+            None => UpdateMetadataValue::None,
+        };
+        metadata.insert(decode_key(key)?, value);
+    }
+    Ok(metadata)
+}
+
+/// Decodes a list of optional metadata maps, one per record.
+pub fn metadatas(terms: Option<Vec<Option<Term>>>) -> Result<Option<Vec<Option<Metadata>>>, ChromexError> {
+    terms
+        .map(|terms| {
+            terms
+                .into_iter()
+                .map(|term| term.map(metadata).transpose())
+                .collect()
+        })
+        .transpose()
+}
+
+/// Decodes a list of optional metadata update maps, one per record.
+pub fn update_metadatas(
+    terms: Option<Vec<Option<Term>>>,
+) -> Result<Option<Vec<Option<UpdateMetadata>>>, ChromexError> {
+    terms
+        .map(|terms| {
+            terms
+                .into_iter()
+                .map(|term| term.map(update_metadata).transpose())
+                .collect()
+        })
+        .transpose()
+}
+
+/// Splits a binary of little-endian f32 values into `rows` vectors of
+/// `dimension` values each.
+fn unpack_embeddings(
+    bytes: &[u8],
+    rows: usize,
+    dimension: usize,
+) -> Result<Vec<Vec<f32>>, ChromexError> {
+    let expected = rows.checked_mul(dimension).and_then(|values| values.checked_mul(4));
+    if expected != Some(bytes.len()) || (dimension == 0 && rows > 0) {
+        return Err(ChromexError::Validation(format!(
+            "packed embeddings hold {} bytes, expected {} rows of {} f32 values",
+            bytes.len(),
+            rows,
+            dimension
+        )));
+    }
+    if rows == 0 {
+        return Ok(Vec::new());
+    }
+
+    Ok(bytes
+        .chunks_exact(dimension * 4)
+        .map(|row| {
+            row.chunks_exact(4)
+                .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+                .collect()
+        })
+        .collect())
+}
+
+/// Decodes embeddings given either as a list of float lists or packed as
+/// `{binary, rows, dimension}`, which avoids building nested lists on the
+/// Elixir side.
+pub fn embeddings(term: Term) -> Result<Vec<Vec<f32>>, ChromexError> {
+    if term.is_tuple() {
+        let (binary, rows, dimension): (Binary, usize, usize) = term
+            .decode()
+            .map_err(|_| invalid(term, "packed embeddings"))?;
+        return unpack_embeddings(binary.as_slice(), rows, dimension);
+    }
+
+    term.decode().map_err(|_| invalid(term, "embeddings"))
+}
+
+/// Like [`embeddings`], but for updates where individual records may leave
+/// their embedding unchanged (`nil`).
+pub fn update_embeddings(term: Option<Term>) -> Result<Option<Vec<Option<Vec<f32>>>>, ChromexError> {
+    match term {
+        None => Ok(None),
+        Some(term) if term.is_tuple() => {
+            Ok(Some(embeddings(term)?.into_iter().map(Some).collect()))
+        }
+        Some(term) => term
+            .decode()
+            .map(Some)
+            .map_err(|_| invalid(term, "embeddings")),
+    }
+}
+
+/// Converts an arbitrary Elixir term (maps, lists, strings, numbers, booleans,
+/// atoms and nil) into the equivalent JSON value, for the Chroma types that
+/// are only constructible through serde.
+pub fn value(term: Term) -> Result<Value, ChromexError> {
+    if term.is_map() {
+        let mut fields = Map::new();
+        for (key, item) in map_entries(term)? {
+            fields.insert(decode_key(key)?, value(item)?);
+        }
+        return Ok(Value::Object(fields));
+    }
+
+    if term.is_list() {
+        let items: Vec<Term> = term.decode().map_err(|_| invalid(term, "list"))?;
+        return items.into_iter().map(value).collect::<Result<_, _>>().map(Value::Array);
+    }
+
+    match decode_scalar(term)? {
+        None => Ok(Value::Null),
+        Some(MetadataValue::Bool(b)) => Ok(Value::Bool(b)),
+        Some(MetadataValue::Int(i)) => Ok(Value::Number(i.into())),
+        Some(MetadataValue::Float(f)) => Number::from_f64(f)
+            .map(Value::Number)
+            .ok_or_else(|| invalid(term, "number")),
+        Some(MetadataValue::Str(s)) => Ok(Value::String(s)),
+        Some(MetadataValue::SparseVector(_)) => Err(invalid(term, "value")),
+    }
+}
+
+/// Parses a metadata `where` filter and a `where_document` full-text filter
+/// (`$contains`, `$not_contains`, `$regex`, ...), both given as Elixir maps,
+/// into a single `Where` combining them.
+pub fn where_clause(
+    where_term: Option<Term>,
+    where_document_term: Option<Term>,
+) -> Result<Option<Where>, ChromexError> {
+    let raw_where = RawWhereFields {
+        r#where: where_term.map(value).transpose()?.unwrap_or(Value::Null),
+        where_document: where_document_term
+            .map(value)
+            .transpose()?
+            .unwrap_or(Value::Null),
+    };
+    raw_where.parse().map_err(ChromexError::validation)
+}
+
+/// Decodes a search payload in the layout of Chroma's HTTP API:
+/// `%{"filter" => ..., "rank" => ..., "limit" => ..., "select" => ...}`.
+pub fn search_payload(term: Term) -> Result<SearchPayload, ChromexError> {
+    serde_json::from_value(value(term)?).map_err(ChromexError::validation)
+}
@@ -0,0 +1,269 @@
+//! Native embedding inference. The model specs below are always decoded so
+//! that the NIF signatures stay the same; the [`Embedder`] that runs them is
+//! only built with the `embeddings` feature. Tokenization and ONNX Runtime
+//! both run here, and each batch is padded only to its longest sequence
+//! rather than to the model's maximum length.
+
+#[cfg(feature = "embeddings")]
+use std::path::Path;
+#[cfg(feature = "embeddings")]
+use std::sync::Mutex;
+
+#[cfg(feature = "embeddings")]
+use ort::session::Session;
+#[cfg(feature = "embeddings")]
+use ort::value::Tensor;
+#[cfg(feature = "embeddings")]
+use tokenizers::{Encoding, PaddingParams, PaddingStrategy, Tokenizer, TruncationParams};
+
+#[cfg(feature = "embeddings")]
+use crate::error::ChromexError;
+
+/// How token embeddings are reduced to one embedding per text.
+#[derive(rustler::NifUnitEnum, Clone, Copy, Debug)]
+pub enum Pooling {
+    /// Average of the unmasked tokens.
+    Mean,
+    /// The first (`[CLS]`) token.
+    Cls,
+}
+
+/// Whether texts are stored documents or search queries; models such as e5
+/// and bge expect a different prefix for each.
+#[derive(rustler::NifUnitEnum, Clone, Copy, Debug)]
+pub enum Input {
+    Document,
+    Query,
+}
+
+/// A `ChromEx.Embeddings.Model`, with its files already resolved to
+/// absolute paths.
+#[derive(rustler::NifMap, Clone, Debug)]
+#[cfg_attr(not(feature = "embeddings"), allow(dead_code))]
+pub struct ModelSpec {
+    pub onnx_path: String,
+    pub tokenizer_path: String,
+    pub max_length: usize,
+    pub pooling: Pooling,
+    pub normalize: bool,
+    pub dimension: Option<usize>,
+    pub query_prefix: String,
+    pub document_prefix: String,
+}
+
+#[cfg(feature = "embeddings")]
+const BATCH_SIZE: usize = 32;
+
+/// The number of tokens a document may have before it is truncated, and the
+/// byte range of every token of each document, as returned by
+/// [`Embedder::token_offsets`].
+pub type TokenOffsets = (usize, Vec<Vec<(usize, usize)>>);
+
+#[cfg(feature = "embeddings")]
+pub struct Embedder {
+    session: Mutex<Session>,
+    tokenizer: Tokenizer,
+    /// The same tokenizer without padding or truncation, for counting tokens.
+    counter: Tokenizer,
+    /// Tokens of a model input taken up by special tokens and the document
+    /// prefix rather than the document.
+    reserved: usize,
+    /// Whether the model takes a `token_type_ids` input; not every BERT
+    /// export does.
+    token_type_ids: bool,
+    spec: ModelSpec,
+}
+
+#[cfg(feature = "embeddings")]
+impl Embedder {
+    /// Loads the model and tokenizer `spec` points at.
+    pub fn load(spec: ModelSpec) -> Result<Self, ChromexError> {
+        let mut tokenizer =
+            Tokenizer::from_file(Path::new(&spec.tokenizer_path)).map_err(ChromexError::internal)?;
+
+        let mut counter = tokenizer.clone();
+        counter.with_padding(None);
+        counter.with_truncation(None).map_err(ChromexError::internal)?;
+        let reserved = counter
+            .encode(spec.document_prefix.as_str(), true)
+            .map_err(ChromexError::internal)?
+            .len();
+
+        tokenizer.with_padding(Some(PaddingParams {
+            strategy: PaddingStrategy::BatchLongest,
+            ..Default::default()
+        }));
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: spec.max_length,
+                ..Default::default()
+            }))
+            .map_err(ChromexError::internal)?;
+
+        let session = Session::builder()
+            .and_then(|builder| builder.commit_from_file(Path::new(&spec.onnx_path)))
+            .map_err(ChromexError::internal)?;
+        let token_type_ids = session
+            .inputs
+            .iter()
+            .any(|input| input.name == "token_type_ids");
+
+        Ok(Embedder {
+            session: Mutex::new(session),
+            tokenizer,
+            counter,
+            reserved,
+            token_type_ids,
+            spec,
+        })
+    }
+
+    /// Returns one pooled embedding per text, L2-normalized if the spec asks
+    /// for it.
+    pub fn embed(&self, texts: &[String], input: Input) -> Result<Vec<Vec<f32>>, ChromexError> {
+        let prefix = match input {
+            Input::Document => &self.spec.document_prefix,
+            Input::Query => &self.spec.query_prefix,
+        };
+
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(BATCH_SIZE) {
+            let batch: Vec<String> = batch.iter().map(|text| format!("{prefix}{text}")).collect();
+            embeddings.extend(self.embed_batch(batch)?);
+        }
+        Ok(embeddings)
+    }
+
+    /// Tokenizes documents without truncating them, so that callers can tell
+    /// which ones [`Embedder::embed`] would cut short and where to split them.
+    pub fn token_offsets(&self, texts: Vec<String>) -> Result<TokenOffsets, ChromexError> {
+        let encodings = self
+            .counter
+            .encode_batch(texts, false)
+            .map_err(ChromexError::internal)?;
+        let offsets = encodings
+            .iter()
+            .map(|encoding| encoding.get_offsets().to_vec())
+            .collect();
+        Ok((self.spec.max_length.saturating_sub(self.reserved), offsets))
+    }
+
+    /// Panics while holding the session lock, poisoning it, for the tests of
+    /// panic handling.
+    #[cfg(feature = "test_hooks")]
+    pub fn panic_holding_session(&self, message: &str) -> ! {
+        let _session = self.session.lock();
+        panic!("{message}")
+    }
+
+    fn embed_batch(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ChromexError> {
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts, true)
+            .map_err(ChromexError::internal)?;
+
+        let batch = encodings.len();
+        let seq_len = encodings.first().map_or(0, Encoding::len);
+        let flatten = |field: fn(&Encoding) -> &[u32]| -> Vec<i64> {
+            encodings
+                .iter()
+                .flat_map(|encoding| field(encoding).iter().map(|&value| value as i64))
+                .collect()
+        };
+        let attention_mask = flatten(Encoding::get_attention_mask);
+
+        let input_ids = Tensor::from_array(([batch, seq_len], flatten(Encoding::get_ids)))
+            .map_err(ChromexError::internal)?;
+        let mask = Tensor::from_array(([batch, seq_len], attention_mask.clone()))
+            .map_err(ChromexError::internal)?;
+
+        let mut inputs = ort::inputs! {
+            "input_ids" => input_ids,
+            "attention_mask" => mask,
+        };
+        if self.token_type_ids {
+            let token_type_ids =
+                Tensor::from_array(([batch, seq_len], flatten(Encoding::get_type_ids)))
+                    .map_err(ChromexError::internal)?;
+            inputs.push(("token_type_ids".into(), token_type_ids.into()));
+        }
+
+        let mut session = self
+            .session
+            .lock()
+            .map_err(|_| ChromexError::poisoned("the embedding model's session"))?;
+        let outputs = session.run(inputs).map_err(ChromexError::internal)?;
+        let (shape, hidden) = outputs[0]
+            .try_extract_tensor::<f32>()
+            .map_err(ChromexError::internal)?;
+
+        // Models exported with pooling built in return `[batch, dimension]`.
+        let pooled: Vec<Vec<f32>> = match **shape {
+            [_, dimension] => hidden.chunks_exact(dimension as usize).map(<[f32]>::to_vec).collect(),
+            [_, _, dimension] => {
+                let dimension = dimension as usize;
+                (0..batch)
+                    .map(|row| {
+                        let mask = &attention_mask[row * seq_len..(row + 1) * seq_len];
+                        let tokens =
+                            &hidden[row * seq_len * dimension..(row + 1) * seq_len * dimension];
+                        match self.spec.pooling {
+                            Pooling::Mean => mean_pool(tokens, mask, dimension),
+                            Pooling::Cls => tokens[..dimension].to_vec(),
+                        }
+                    })
+                    .collect()
+            }
+            _ => {
+                return Err(ChromexError::Internal(format!(
+                    "unexpected model output shape {:?}",
+                    &**shape
+                )))
+            }
+        };
+
+        pooled
+            .into_iter()
+            .map(|mut embedding| {
+                if let Some(dimension) = self.spec.dimension {
+                    if embedding.len() != dimension {
+                        return Err(ChromexError::Internal(format!(
+                            "model returned {}-dimensional embeddings, expected {dimension}",
+                            embedding.len()
+                        )));
+                    }
+                }
+                if self.spec.normalize {
+                    normalize(&mut embedding);
+                }
+                Ok(embedding)
+            })
+            .collect()
+    }
+}
+
+/// Averages the token embeddings not masked out.
+#[cfg(feature = "embeddings")]
+fn mean_pool(tokens: &[f32], mask: &[i64], dimension: usize) -> Vec<f32> {
+    let mut pooled = vec![0.0f32; dimension];
+    let mut count = 0.0f32;
+    for (token, &keep) in tokens.chunks_exact(dimension).zip(mask) {
+        if keep == 0 {
+            continue;
+        }
+        count += 1.0;
+        for (sum, value) in pooled.iter_mut().zip(token) {
+            *sum += value;
+        }
+    }
+
+    let count = count.max(1e-9);
+    pooled.iter_mut().for_each(|value| *value /= count);
+    pooled
+}
+
+#[cfg(feature = "embeddings")]
+fn normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|value| value * value).sum::<f32>().sqrt().max(1e-12);
+    embedding.iter_mut().for_each(|value| *value /= norm);
+}
@@ -0,0 +1,274 @@
+use chroma_types::{
+    Collection, GetResponse, Include, Metadata, MetadataValue, QueryResponse, SearchResponse,
+};
+use rustler::{Encoder, Env, OwnedBinary, Term};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::atoms;
+use crate::error::ChromexError;
+
+/// Builds a map with string keys, matching the shape callers used to get
+/// from `Jason.decode!/1`.
+fn string_keyed_map<'a>(env: Env<'a>, pairs: Vec<(&str, Term<'a>)>) -> Term<'a> {
+    let (keys, values): (Vec<&str>, Vec<Term<'a>>) = pairs.into_iter().unzip();
+    Term::map_from_arrays(env, &keys, &values).unwrap()
+}
+
+fn encode_metadata<'a>(env: Env<'a>, metadata: &Metadata) -> Term<'a> {
+    let (keys, values): (Vec<&str>, Vec<Term<'a>>) = metadata
+        .iter()
+        .map(|(key, value)| (key.as_str(), encode_metadata_value(env, value)))
+        .unzip();
+    Term::map_from_arrays(env, &keys, &values).unwrap()
+}
+
+fn encode_metadata_value<'a>(env: Env<'a>, value: &MetadataValue) -> Term<'a> {
+    match value {
+        MetadataValue::Bool(b) => b.encode(env),
+        MetadataValue::Int(i) => i.encode(env),
+        MetadataValue::Float(f) => f.encode(env),
+        MetadataValue::Str(s) => s.encode(env),
+        MetadataValue::SparseVector(sparse) => Term::map_from_arrays(
+            env,
+            &[atoms::__struct__(), atoms::indices(), atoms::values()],
+            &[
+                atoms::sparse_vector_struct().encode(env),
+                sparse.indices.encode(env),
+                sparse.values.encode(env),
+            ],
+        )
+        .unwrap(),
+    }
+}
+
+fn encode_optional_metadata<'a>(env: Env<'a>, metadata: &Option<Metadata>) -> Term<'a> {
+    match metadata {
+        Some(metadata) => encode_metadata(env, metadata),
+        None => atoms::nil().encode(env),
+    }
+}
+
+fn encode_include<'a>(env: Env<'a>, include: &[Include]) -> Term<'a> {
+    include
+        .iter()
+        .map(|include| match include {
+            Include::Distance => "distances",
+            Include::Document => "documents",
+            Include::Embedding => "embeddings",
+            Include::Metadata => "metadatas",
+            Include::Uri => "uris",
+        })
+        .collect::<Vec<_>>()
+        .encode(env)
+}
+
+/// Packs equally sized embeddings into `{binary, rows, dimension}`, where
+/// `binary` holds `rows * dimension` little-endian f32 values.
+fn encode_packed_embeddings<'a>(env: Env<'a>, embeddings: &[&[f32]]) -> Term<'a> {
+    let dimension = embeddings.first().map_or(0, |row| row.len());
+    let mut binary = OwnedBinary::new(embeddings.len() * dimension * 4).unwrap();
+    let values = embeddings.iter().flat_map(|row| row.iter());
+    for (bytes, value) in binary.as_mut_slice().chunks_exact_mut(4).zip(values) {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    (binary.release(env), embeddings.len(), dimension).encode(env)
+}
+
+/// Converts a JSON value to the equivalent Elixir term: objects become maps
+/// with string keys, `null` becomes `nil`.
+pub fn encode_value<'a>(env: Env<'a>, value: &Value) -> Term<'a> {
+    match value {
+        Value::Null => atoms::nil().encode(env),
+        Value::Bool(b) => b.encode(env),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                i.encode(env)
+            } else if let Some(u) = n.as_u64() {
+                u.encode(env)
+            } else {
+                n.as_f64().unwrap_or_default().encode(env)
+            }
+        }
+        Value::String(s) => s.encode(env),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| encode_value(env, item))
+            .collect::<Vec<_>>()
+            .encode(env),
+        Value::Object(fields) => {
+            let (keys, values): (Vec<&str>, Vec<Term<'a>>) = fields
+                .iter()
+                .map(|(key, value)| (key.as_str(), encode_value(env, value)))
+                .unzip();
+            Term::map_from_arrays(env, &keys, &values).unwrap()
+        }
+    }
+}
+
+/// Any serializable Chroma response, encoded through its serde representation
+/// without going through a JSON string. Used for the small administrative
+/// responses (databases, tenants).
+pub struct ValueTerm(Value);
+
+impl ValueTerm {
+    pub fn new<T: Serialize>(value: &T) -> Result<Self, ChromexError> {
+        Ok(ValueTerm(
+            serde_json::to_value(value).map_err(ChromexError::internal)?,
+        ))
+    }
+}
+
+impl Encoder for ValueTerm {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        encode_value(env, &self.0)
+    }
+}
+
+pub struct CollectionTerm(pub Collection);
+
+impl Encoder for CollectionTerm {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let collection = &self.0;
+        let configuration = serde_json::to_value(&collection.config).unwrap_or_default();
+
+        string_keyed_map(
+            env,
+            vec![
+                ("id", collection.collection_id.0.to_string().encode(env)),
+                ("name", collection.name.encode(env)),
+                ("tenant", collection.tenant.encode(env)),
+                ("database", collection.database.encode(env)),
+                ("metadata", encode_optional_metadata(env, &collection.metadata)),
+                ("configuration", encode_value(env, &configuration)),
+                ("dimension", collection.dimension.encode(env)),
+            ],
+        )
+    }
+}
+
+pub struct QueryTerm {
+    pub response: QueryResponse,
+    /// Return each query's embeddings packed as `{binary, rows, dimension}`.
+    pub packed_embeddings: bool,
+}
+
+impl Encoder for QueryTerm {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let response = &self.response;
+        let embeddings = match &response.embeddings {
+            Some(queries) if self.packed_embeddings => queries
+                .iter()
+                .map(|rows| {
+                    match rows.iter().map(|row| row.as_deref()).collect::<Option<Vec<_>>>() {
+                        Some(rows) => encode_packed_embeddings(env, &rows),
+                        None => rows.encode(env),
+                    }
+                })
+                .collect::<Vec<_>>()
+                .encode(env),
+            embeddings => embeddings.encode(env),
+        };
+        let metadatas = response.metadatas.as_ref().map(|queries| {
+            queries
+                .iter()
+                .map(|rows| {
+                    rows.iter()
+                        .map(|metadata| encode_optional_metadata(env, metadata))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        });
+
+        string_keyed_map(
+            env,
+            vec![
+                ("ids", response.ids.encode(env)),
+                ("embeddings", embeddings),
+                ("documents", response.documents.encode(env)),
+                ("uris", response.uris.encode(env)),
+                ("metadatas", metadatas.encode(env)),
+                ("distances", response.distances.encode(env)),
+                ("include", encode_include(env, &response.include)),
+            ],
+        )
+    }
+}
+
+pub struct GetTerm {
+    pub response: GetResponse,
+    /// Return the embeddings packed as `{binary, rows, dimension}`.
+    pub packed_embeddings: bool,
+}
+
+impl Encoder for GetTerm {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let response = &self.response;
+        let embeddings = match &response.embeddings {
+            Some(rows) if self.packed_embeddings => {
+                let rows: Vec<&[f32]> = rows.iter().map(Vec::as_slice).collect();
+                encode_packed_embeddings(env, &rows)
+            }
+            embeddings => embeddings.encode(env),
+        };
+        let metadatas = response.metadatas.as_ref().map(|rows| {
+            rows.iter()
+                .map(|metadata| encode_optional_metadata(env, metadata))
+                .collect::<Vec<_>>()
+        });
+
+        string_keyed_map(
+            env,
+            vec![
+                ("ids", response.ids.encode(env)),
+                ("embeddings", embeddings),
+                ("documents", response.documents.encode(env)),
+                ("uris", response.uris.encode(env)),
+                ("metadatas", metadatas.encode(env)),
+                ("include", encode_include(env, &response.include)),
+            ],
+        )
+    }
+}
+
+pub struct SearchTerm(pub SearchResponse);
+
+impl Encoder for SearchTerm {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let response = &self.0;
+        let metadatas = response
+            .metadatas
+            .iter()
+            .map(|rows| {
+                rows.as_ref().map(|rows| {
+                    rows.iter()
+                        .map(|metadata| encode_optional_metadata(env, metadata))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        // Select keys are few and only serde knows their names, e.g.
+        // "#document" or a metadata field's
+        let select = response
+            .select
+            .iter()
+            .map(|keys| {
+                keys.iter()
+                    .map(|key| encode_value(env, &serde_json::to_value(key).unwrap_or_default()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        string_keyed_map(
+            env,
+            vec![
+                ("ids", response.ids.encode(env)),
+                ("documents", response.documents.encode(env)),
+                ("embeddings", response.embeddings.encode(env)),
+                ("metadatas", metadatas.encode(env)),
+                ("scores", response.scores.encode(env)),
+                ("select", select.encode(env)),
+            ],
+        )
+    }
+}
@@ -0,0 +1,118 @@
+use chroma_error::{ChromaError, ErrorCodes};
+use rustler::{Encoder, Env, Term};
+use std::any::Any;
+use std::fmt::Display;
+
+use crate::atoms;
+
+/// The kind of Chroma resource a `NotFound` or `AlreadyExists` error refers to.
+#[derive(Debug, Clone, Copy)]
+pub enum Resource {
+    Tenant,
+    Database,
+    Collection,
+}
+
+impl Encoder for Resource {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            Resource::Tenant => atoms::tenant().encode(env),
+            Resource::Database => atoms::database().encode(env),
+            Resource::Collection => atoms::collection().encode(env),
+        }
+    }
+}
+
+/// Errors returned to Elixir as tagged tuples, e.g.
+/// `{:not_found, :collection, "docs"}` or `{:validation, "..."}`.
+#[derive(Debug, thiserror::Error)]
+pub enum ChromexError {
+    #[error("{0:?} {1} not found")]
+    NotFound(Resource, String),
+    #[error("{0:?} {1} already exists")]
+    AlreadyExists(Resource, String),
+    #[error("validation error: {0}")]
+    Validation(String),
+    #[error("internal error: {0}")]
+    Internal(String),
+    /// Native code panicked while serving the call.
+    #[error("panic: {0}")]
+    Panic(String),
+    /// The store was closed; encoded as the bare atom `:closed`.
+    #[error("the store is closed")]
+    Closed,
+}
+
+impl ChromexError {
+    /// Classifies an error raised by Chroma using its error code. `resource`
+    /// and `name` identify what the operation targeted and are only reported
+    /// for not-found and already-exists failures.
+    pub fn from_chroma(err: &dyn ChromaError, resource: Resource, name: &str) -> Self {
+        match err.code() {
+            ErrorCodes::NotFound => ChromexError::NotFound(resource, name.to_string()),
+            ErrorCodes::AlreadyExists => ChromexError::AlreadyExists(resource, name.to_string()),
+            ErrorCodes::InvalidArgument
+            | ErrorCodes::FailedPrecondition
+            | ErrorCodes::OutOfRange => ChromexError::Validation(err.to_string()),
+            _ => ChromexError::Internal(err.to_string()),
+        }
+    }
+
+    pub fn validation(err: impl Display) -> Self {
+        ChromexError::Validation(err.to_string())
+    }
+
+    pub fn internal(err: impl Display) -> Self {
+        ChromexError::Internal(err.to_string())
+    }
+
+    /// Reports the payload of a caught panic.
+    pub fn panic(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "panic with a non-string payload".to_string(),
+            },
+        };
+        ChromexError::Panic(message)
+    }
+
+    /// Reports a task that did not complete, which is a panic unless the
+    /// runtime was shut down under it.
+    pub fn join(err: tokio::task::JoinError) -> Self {
+        match err.try_into_panic() {
+            Ok(payload) => ChromexError::panic(payload),
+            Err(err) => ChromexError::Internal(err.to_string()),
+        }
+    }
+
+    /// Reports state left behind by an earlier panic, e.g. a poisoned lock.
+    #[cfg(any(feature = "arrow", feature = "embeddings"))]
+    pub fn poisoned(what: &str) -> Self {
+        ChromexError::Panic(format!("{what} is unusable after an earlier panic"))
+    }
+}
+
+impl Encoder for ChromexError {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            ChromexError::NotFound(resource, name) => {
+                (atoms::not_found(), resource, name).encode(env)
+            }
+            ChromexError::AlreadyExists(resource, name) => {
+                (atoms::already_exists(), resource, name).encode(env)
+            }
+            ChromexError::Validation(message) => (atoms::validation(), message).encode(env),
+            ChromexError::Internal(message) => (atoms::internal(), message).encode(env),
+            ChromexError::Panic(message) => (atoms::panic(), message).encode(env),
+            ChromexError::Closed => atoms::closed().encode(env),
+        }
+    }
+}
+
+impl From<ChromexError> for rustler::Error {
+    fn from(err: ChromexError) -> Self {
+        rustler::Error::Term(Box::new(err))
+    }
+}
@@ -0,0 +1,78 @@
+//! Turns the `hnsw_cache_size_mb` memory budget into the capacity of
+//! Chroma's HNSW index cache.
+//!
+//! Chroma builds that cache itself and weighs every loaded index as 1, so
+//! its capacity can only be a number of indexes. When the store opens, the
+//! size of each collection's index is estimated from its dimension and
+//! element count in `chroma.db`. The capacity is then how many of the
+//! largest indexes fit in the budget together, so that any set of cached
+//! indexes does too. The estimate is only refreshed when the store is
+//! opened again, e.g. by `restart_native`.
+//!
+//! Reads Chroma's SQLite schema as of the pinned commit 8963e1df (see
+//! Cargo.toml): `collections.dimension`, and the `embeddings` of each
+//! collection's `'METADATA'` segment, as `get_page` does.
+
+use std::path::Path;
+
+use rusqlite::{Connection, OpenFlags};
+
+/// What an element costs in an index besides its vector: the level-0 links
+/// for Chroma's default `max_neighbors` of 16, its label and hnswlib's
+/// bookkeeping.
+const ELEMENT_OVERHEAD: u64 = 2 * 16 * 4 + 16;
+
+/// The number of HNSW indexes to keep loaded so that they fit in
+/// `budget_mb` megabytes, at least 1 and at most `max_indexes`.
+pub fn capacity(db_path: &Path, budget_mb: u64, max_indexes: usize) -> rusqlite::Result<usize> {
+    if !db_path.exists() {
+        return Ok(max_indexes);
+    }
+
+    let mut sizes = index_sizes(db_path)?;
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+
+    let budget = budget_mb.saturating_mul(1 << 20);
+    let mut total = 0u64;
+    let fitting = sizes
+        .iter()
+        .take_while(|&&size| {
+            total = total.saturating_add(size);
+            total <= budget
+        })
+        .count();
+
+    // Indexes beyond the known ones are new collections, whose indexes
+    // start out empty
+    let capacity = if fitting == sizes.len() {
+        max_indexes
+    } else {
+        fitting
+    };
+    Ok(capacity.clamp(1, max_indexes))
+}
+
+/// Estimated bytes of every collection's HNSW index.
+fn index_sizes(db_path: &Path) -> rusqlite::Result<Vec<u64>> {
+    let connection = Connection::open_with_flags(
+        db_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+
+    let mut statement = connection.prepare(
+        "SELECT c.dimension, COUNT(e.embedding_id) \
+         FROM collections c \
+         JOIN segments s ON s.collection = c.id AND s.scope = 'METADATA' \
+         LEFT JOIN embeddings e ON e.segment_id = s.id \
+         WHERE c.dimension IS NOT NULL \
+         GROUP BY c.id",
+    )?;
+
+    let sizes = statement
+        .query_map([], |row| {
+            let (dimension, elements): (u64, u64) = (row.get(0)?, row.get(1)?);
+            Ok(elements.saturating_mul(dimension.saturating_mul(4) + ELEMENT_OVERHEAD))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(sizes)
+}
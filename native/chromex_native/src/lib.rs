@@ -4,49 +4,122 @@ use chroma_frontend::executor::config::{ExecutorConfig, LocalExecutorConfig};
 use chroma_log::config::{LogConfig, SqliteLogConfig};
 use chroma_segment::local_segment_manager::LocalSegmentManagerConfig;
 use chroma_sqlite::config::{SqliteDBConfig, MigrationMode, MigrationHash};
+use chroma_sqlite::db::SqliteDb;
 use chroma_sysdb::{SqliteSysDbConfig, SysDbConfig};
 use chroma_system::System;
 use chroma_types::{
     AddCollectionRecordsRequest, CollectionUuid, CountRequest,
     CreateCollectionRequest, CreateDatabaseRequest, CreateTenantRequest,
     DeleteCollectionRecordsRequest, DeleteCollectionRequest, DeleteDatabaseRequest,
-    GetCollectionRequest, GetDatabaseRequest, GetRequest, GetTenantRequest, Include, IncludeList,
-    InternalCollectionConfiguration, ListCollectionsRequest, ListDatabasesRequest,
-    Metadata, QueryRequest, RawWhereFields, UpdateCollectionRecordsRequest, UpdateCollectionRequest,
-    UpsertCollectionRecordsRequest, Where, UpdateMetadata, CollectionMetadataUpdate,
+    EmbeddingFunctionConfiguration,
+    GetCollectionRequest, GetDatabaseRequest, GetRequest, GetResponse, GetTenantRequest, Include,
+    IncludeList,
+    InternalCollectionConfiguration, InternalUpdateCollectionConfiguration,
+    ListCollectionsRequest, ListDatabasesRequest,
+    QueryRequest, Schema, SearchRequest, UpdateCollectionRecordsRequest, UpdateCollectionRequest,
+    UpdateCollectionConfiguration, UpsertCollectionRecordsRequest, CollectionMetadataUpdate,
 };
-use rustler::{Env, Error, NifResult, ResourceArc, Term};
-use std::sync::{Arc, Mutex};
-use tokio::runtime::Runtime;
+use arrow_file::{ExportWriter, Format, ImportReader, Records};
+use embeddings::{Input, ModelSpec, TokenOffsets};
+use encode::{CollectionTerm, GetTerm, QueryTerm, SearchTerm, ValueTerm};
+use error::{ChromexError, Resource};
+use rustler::{Atom, Encoder, Env, LocalPid, NifResult, OwnedEnv, ResourceArc, Term};
+use std::future::Future;
+use std::mem::ManuallyDrop;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+#[cfg(feature = "embeddings")]
+use std::collections::HashMap;
+#[cfg(feature = "embeddings")]
+use std::sync::RwLock;
+use tokio::runtime::{Handle, Runtime};
 use uuid::Uuid;
 
+mod arrow_file;
+mod backup;
+mod decode;
+mod embeddings;
+mod encode;
+mod error;
+mod hnsw_cache;
+
 mod atoms {
     rustler::atoms! {
         ok,
         error,
         nil,
+        not_found,
+        already_exists,
+        validation,
+        internal,
+        closed,
+        panic,
+        tenant,
+        database,
+        collection,
+        indices,
+        values,
+        __struct__,
+        sparse_vector_struct = "Elixir.ChromEx.SparseVector",
     }
 }
 
+/// How long a store that was never closed gets to close once the BEAM
+/// collects its resource.
+const DROP_CLOSE_DEADLINE: Duration = Duration::from_secs(5);
+
 struct ChromaBindings {
-    runtime: Runtime,
-    frontend: Arc<Mutex<Frontend>>,
+    runtime: Handle,
+    /// The runtime itself, taken by `close`.
+    owned_runtime: Mutex<Option<Runtime>>,
+    /// Kept to stop chroma's components on `close`.
+    system: System,
+    /// Cloned for every call; clones share the underlying sysdb, log and
+    /// segment caches, so calls on separate clones run concurrently.
+    frontend: Frontend,
+    /// The frontend's own connection pool to `chroma.db`, for the reads it
+    /// has no request for.
+    sqlite: SqliteDb,
+    storage_path: PathBuf,
+    /// How many HNSW indexes the cache keeps, see `hnsw_cache::capacity`.
+    hnsw_cache_size: usize,
+    /// Held for reading by every call made through `spawn_reply` and for
+    /// writing by a backup, which thus waits for calls in flight and holds
+    /// up new ones until it is done.
+    gate: Arc<tokio::sync::RwLock<()>>,
+    calls: Arc<Calls>,
+    /// Models loaded by `load_embedder`, by name. Only ever inserted into,
+    /// so it is used as is even if a panic poisoned the lock.
+    #[cfg(feature = "embeddings")]
+    embedders: RwLock<HashMap<String, Arc<embeddings::Embedder>>>,
 }
 
+type EmbeddingsFuture = Pin<Box<dyn Future<Output = Result<Vec<Vec<f32>>, ChromexError>> + Send>>;
+
 impl ChromaBindings {
     fn new(
         allow_reset: bool,
         persist_path: Option<String>,
+        hnsw_cache_size_mb: u64,
+        max_hnsw_indexes: usize,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let runtime = Runtime::new()?;
 
         let storage_path = persist_path.unwrap_or_else(|| "./chroma_data".to_string());
         std::fs::create_dir_all(&storage_path)?;
-
+        let hnsw_cache_size = hnsw_cache::capacity(
+            &PathBuf::from(&storage_path).join("chroma.db"),
+            hnsw_cache_size_mb,
+            max_hnsw_indexes,
+        )?;
+
+        let registry = Registry::new();
+        let system = runtime.block_on(async { System::new() });
         let frontend = runtime.block_on(async {
-            let system = System::new();
-            let registry = Registry::new();
-
             let db_path = format!("{}/chroma.db", storage_path);
 
             let sqlite_config = SqliteDBConfig {
@@ -65,10 +138,12 @@ impl ChromaBindings {
                 topic_namespace: "default".to_string(),
             });
 
+            // The cache gives every loaded HNSW index the same weight, so
+            // the capacity is the number of indexes that fit in the budget.
             let segment_manager_config = LocalSegmentManagerConfig {
                 hnsw_index_pool_cache_config: chroma_cache::CacheConfig::Memory(
                     chroma_cache::FoyerCacheConfig {
-                        capacity: 65536,
+                        capacity: hnsw_cache_size,
                         ..Default::default()
                     },
                 ),
@@ -92,55 +167,338 @@ impl ChromaBindings {
 
             Frontend::try_from_config(&(fe_config, system.clone()), &registry).await
         })?;
+        // Registered by the frontend when it opened the database
+        let sqlite = registry.get::<SqliteDb>().map_err(|e| e.to_string())?;
 
         Ok(ChromaBindings {
-            runtime,
-            frontend: Arc::new(Mutex::new(frontend)),
+            runtime: runtime.handle().clone(),
+            owned_runtime: Mutex::new(Some(runtime)),
+            system,
+            frontend,
+            sqlite,
+            storage_path: PathBuf::from(storage_path),
+            gate: Arc::new(tokio::sync::RwLock::new(())),
+            calls: Arc::new(Calls::default()),
+            hnsw_cache_size,
+            #[cfg(feature = "embeddings")]
+            embedders: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Embeds `texts` with the loaded model `model` on a blocking thread, so
+    /// that inference does not hold up the runtime's other calls.
+    #[cfg(feature = "embeddings")]
+    fn embed(&self, model: String, texts: Vec<String>, input: Input) -> EmbeddingsFuture {
+        let embedder = self
+            .embedders
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&model)
+            .cloned();
+        Box::pin(async move {
+            let embedder = embedder.ok_or_else(|| {
+                ChromexError::Validation(format!("embedding model {model} is not loaded"))
+            })?;
+            tokio::task::spawn_blocking(move || embedder.embed(&texts, input))
+                .await
+                .map_err(ChromexError::join)?
+        })
+    }
+
+    #[cfg(not(feature = "embeddings"))]
+    fn embed(&self, _model: String, _texts: Vec<String>, _input: Input) -> EmbeddingsFuture {
+        Box::pin(std::future::ready(Err(embeddings_disabled())))
+    }
+
+    /// Tokenizes `texts` with the loaded model `model`, see
+    /// [`embeddings::Embedder::token_offsets`].
+    #[cfg(feature = "embeddings")]
+    fn token_offsets(
+        &self,
+        model: String,
+        texts: Vec<String>,
+    ) -> impl Future<Output = Result<TokenOffsets, ChromexError>> + Send + 'static {
+        let embedder = self
+            .embedders
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&model)
+            .cloned();
+        async move {
+            let embedder = embedder.ok_or_else(|| {
+                ChromexError::Validation(format!("embedding model {model} is not loaded"))
+            })?;
+            tokio::task::spawn_blocking(move || embedder.token_offsets(texts))
+                .await
+                .map_err(ChromexError::join)?
+        }
+    }
+
+    #[cfg(not(feature = "embeddings"))]
+    fn token_offsets(
+        &self,
+        _model: String,
+        _texts: Vec<String>,
+    ) -> impl Future<Output = Result<TokenOffsets, ChromexError>> + Send + 'static {
+        std::future::ready(Err(embeddings_disabled()))
+    }
+
+    /// Resolves to the caller's embeddings when given, and otherwise to
+    /// embeddings of `texts` by `model`, computed inside the same call.
+    fn embeddings_or_texts(
+        &self,
+        embeddings: Option<Term>,
+        model: Option<String>,
+        texts: Option<Vec<String>>,
+        input: Input,
+    ) -> Result<EmbeddingsFuture, ChromexError> {
+        match (embeddings, model, texts) {
+            (Some(embeddings), _, _) => {
+                let embeddings = decode::embeddings(embeddings)?;
+                Ok(Box::pin(std::future::ready(Ok(embeddings))))
+            }
+            (None, Some(model), Some(texts)) => Ok(self.embed(model, texts, input)),
+            (None, None, _) => Err(ChromexError::Validation(
+                "embeddings or an embedding model are required".to_string(),
+            )),
+            (None, Some(_), None) => Err(ChromexError::Validation(
+                "embeddings are required unless every record has a document".to_string(),
+            )),
+        }
+    }
+
+    /// Runs `future` on the tokio runtime instead of the calling scheduler and
+    /// sends `{reply_ref, result}` to the calling process once it completes,
+    /// or `{reference, result}` to `pid` when `reply_ref` is
+    /// `{pid, reference}`.
+    /// Waits for a backup in progress first. Fails with `:closed` once the
+    /// store was closed.
+    fn spawn_reply<F, T>(&self, env: Env, reply_ref: Term, future: F) -> NifResult<Atom>
+    where
+        F: Future<Output = Result<T, ChromexError>> + Send + 'static,
+        T: Encoder + Send + 'static,
+    {
+        let gate = self.gate.clone();
+        self.spawn_ungated_reply(env, reply_ref, async move {
+            let _gate = gate.read().await;
+            future.await
+        })
+    }
+
+    /// [`Self::spawn_reply`] for the calls that take the gate themselves.
+    fn spawn_ungated_reply<F, T>(&self, env: Env, reply_ref: Term, future: F) -> NifResult<Atom>
+    where
+        F: Future<Output = Result<T, ChromexError>> + Send + 'static,
+        T: Encoder + Send + 'static,
+    {
+        let running = Calls::start(&self.calls)?;
+        // `{pid, reference}` replies to another process than the caller
+        let (pid, reply_ref) = match reply_ref.decode::<(LocalPid, Term)>() {
+            Ok((pid, reply_ref)) => (pid, reply_ref),
+            Err(_) => (env.pid(), reply_ref),
+        };
+        let mut owned_env = OwnedEnv::new();
+        let saved_ref = owned_env.save(reply_ref);
+
+        self.runtime.spawn(async move {
+            let _running = running;
+            // Run as a task of its own so that a panic in it is caught by
+            // tokio and replied with rather than leaving the caller waiting
+            let result = match tokio::spawn(future).await {
+                Ok(result) => result,
+                Err(err) => Err(ChromexError::join(err)),
+            };
+            let _ = owned_env.send_and_clear(&pid, |env| {
+                let reply_ref = saved_ref.load(env);
+                match result {
+                    Ok(value) => (reply_ref, value).encode(env),
+                    Err(reason) => (reply_ref, (atoms::error(), reason)).encode(env),
+                }
+            });
+        });
+
+        Ok(atoms::ok())
+    }
+
+    /// Runs `future` to completion on the runtime from a dirty scheduler.
+    /// Fails with `:closed` once the store was closed.
+    fn block_on<F: Future>(&self, future: F) -> Result<F::Output, ChromexError> {
+        let _running = Calls::start(&self.calls)?;
+        Ok(self.runtime.block_on(future))
+    }
+
+    /// Closes the store: refuses new calls, waits for the ones in flight,
+    /// stops chroma's components, which flushes the local log into the
+    /// segments, and closes `chroma.db`, then shuts the runtime down. All of
+    /// it within `deadline`; tasks still running then are abandoned. Closing
+    /// a closed store does nothing.
+    fn close(&self, deadline: Duration) -> Result<(), ChromexError> {
+        let runtime = self.owned_runtime.lock().unwrap_or_else(PoisonError::into_inner).take();
+        let Some(runtime) = runtime else {
+            return Ok(());
+        };
+        let started = Instant::now();
+
+        let closed = runtime.block_on(tokio::time::timeout(deadline, async {
+            self.calls.close().await;
+            self.system.stop().await;
+            self.system.join().await;
+            // Checkpoints the WAL into chroma.db
+            self.sqlite.get_conn().close().await;
+        }));
+        runtime.shutdown_timeout(deadline.saturating_sub(started.elapsed()));
+
+        closed.map_err(|_| {
+            ChromexError::Internal(format!("the store did not close within {deadline:?}"))
         })
     }
+}
 
-    fn parse_metadata(&self, json_str: &str) -> Result<Metadata, Box<dyn std::error::Error>> {
-        Ok(serde_json::from_str(json_str)?)
+impl Drop for ChromaBindings {
+    /// Closes the store unless it was closed already, see
+    /// [`ChromaBindingsResource`]'s `drop` for where this runs.
+    fn drop(&mut self) {
+        let _ = self.close(DROP_CLOSE_DEADLINE);
     }
+}
 
-    fn parse_update_metadata(&self, json_str: &str) -> Result<UpdateMetadata, Box<dyn std::error::Error>> {
-        Ok(serde_json::from_str(json_str)?)
+/// Counts the calls in flight on a store so that `close` can wait for them.
+#[derive(Default)]
+struct Calls {
+    closed: AtomicBool,
+    running: AtomicUsize,
+    finished: tokio::sync::Notify,
+}
+
+/// A call counted by [`Calls::start`], until it is dropped.
+struct Running(Arc<Calls>);
+
+impl Calls {
+    fn start(calls: &Arc<Calls>) -> Result<Running, ChromexError> {
+        // Counted before checking, so that `close` either sees this call or
+        // this call sees the store closed
+        calls.running.fetch_add(1, Ordering::SeqCst);
+        let running = Running(calls.clone());
+        if calls.closed.load(Ordering::SeqCst) {
+            return Err(ChromexError::Closed);
+        }
+        Ok(running)
     }
 
-    fn parse_where(&self, json_str: &str) -> Result<Option<Where>, Box<dyn std::error::Error>> {
-        let raw_where = RawWhereFields::from_json_str(Some(json_str), None)?;
-        Ok(raw_where.parse()?)
+    /// Refuses new calls and waits for the running ones to finish.
+    async fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        loop {
+            let finished = self.finished.notified();
+            tokio::pin!(finished);
+            finished.as_mut().enable();
+            if self.running.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            finished.await;
+        }
     }
 }
 
+impl Drop for Running {
+    fn drop(&mut self) {
+        if self.0.running.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.finished.notify_waiters();
+        }
+    }
+}
+
+/// Runs `f`, returning `{:error, {:panic, message}}` if it panics rather than
+/// the bare `:nif_panicked` Rustler raises. Wraps the body of every NIF.
+fn catch_panic<T, E: From<ChromexError>>(f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    std::panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(ChromexError::panic(payload).into()))
+}
+
+/// The documents to embed when no embeddings were given, provided every
+/// record has one.
+fn document_texts(documents: &Option<Vec<Option<String>>>) -> Option<Vec<String>> {
+    documents.as_ref()?.iter().cloned().collect()
+}
+
+/// The fields named in `include`, in the order Chroma returns them.
+fn include_list(include: &[String]) -> Vec<Include> {
+    [
+        ("documents", Include::Document),
+        ("embeddings", Include::Embedding),
+        ("metadatas", Include::Metadata),
+        ("distances", Include::Distance),
+        ("uris", Include::Uri),
+    ]
+    .into_iter()
+    .filter(|(name, _)| include.iter().any(|field| field == name))
+    .map(|(_, field)| field)
+    .collect()
+}
+
+#[cfg(not(feature = "embeddings"))]
+fn embeddings_disabled() -> ChromexError {
+    ChromexError::Validation(
+        "chromex_native was built without the embeddings feature".to_string(),
+    )
+}
+
 struct ChromaBindingsResource {
-    inner: Arc<Mutex<ChromaBindings>>,
+    bindings: ManuallyDrop<ChromaBindings>,
+}
+
+impl Drop for ChromaBindingsResource {
+    /// The BEAM runs resource destructors on whichever scheduler collected
+    /// the resource, so a store that was never closed is dropped, and thus
+    /// closed, on a thread of its own rather than holding that scheduler up.
+    fn drop(&mut self) {
+        // SAFETY: `self.bindings` is not used again after being taken here
+        let bindings = unsafe { ManuallyDrop::take(&mut self.bindings) };
+        std::thread::spawn(move || drop(bindings));
+    }
 }
 
 fn on_load(env: Env, _info: Term) -> bool {
     rustler::resource!(ChromaBindingsResource, env);
+    rustler::resource!(ExportWriter, env);
+    rustler::resource!(ImportReader, env);
     true
 }
 
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
 fn init(
     allow_reset: bool,
     persist_path: Option<String>,
-    _hnsw_cache_size: usize,
+    hnsw_cache_size_mb: u64,
+    hnsw_cache_size: usize,
 ) -> NifResult<ResourceArc<ChromaBindingsResource>> {
-    let bindings = ChromaBindings::new(allow_reset, persist_path)
-        .map_err(|e| Error::Term(Box::new(format!("{:?}", e))))?;
+    catch_panic(|| {
+        if hnsw_cache_size_mb == 0 {
+            return Err(ChromexError::Validation(
+                "hnsw_cache_size_mb must be greater than 0".to_string(),
+            )
+            .into());
+        }
+        if hnsw_cache_size == 0 {
+            return Err(ChromexError::Validation(
+                "hnsw_cache_size must be greater than 0".to_string(),
+            )
+            .into());
+        }
+
+        let bindings =
+            ChromaBindings::new(allow_reset, persist_path, hnsw_cache_size_mb, hnsw_cache_size)
+                .map_err(ChromexError::internal)?;
 
-    Ok(ResourceArc::new(ChromaBindingsResource {
-        inner: Arc::new(Mutex::new(bindings)),
-    }))
+        Ok(ResourceArc::new(ChromaBindingsResource { bindings: ManuallyDrop::new(bindings) }))
+    })
 }
 
 #[rustler::nif]
 fn heartbeat() -> i64 {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
+        .unwrap_or_default()
         .as_nanos() as i64
 }
 
@@ -149,774 +507,1221 @@ fn get_version() -> String {
     env!("CARGO_PKG_VERSION").to_string()
 }
 
+/// The most records the frontend accepts in one write.
+#[rustler::nif(schedule = "DirtyIo")]
+fn get_max_batch_size(resource: ResourceArc<ChromaBindingsResource>) -> NifResult<u32> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+        let mut frontend = bindings.frontend.clone();
+        Ok(bindings.block_on(frontend.get_max_batch_size())?)
+    })
+}
+
+#[rustler::nif]
+fn get_hnsw_cache_size(resource: ResourceArc<ChromaBindingsResource>) -> NifResult<usize> {
+    catch_panic(|| {
+        Ok(resource.bindings.hnsw_cache_size)
+    })
+}
+
 #[rustler::nif]
-fn get_max_batch_size(resource: ResourceArc<ChromaBindingsResource>) -> NifResult<i32> {
-    let _bindings = resource.inner.lock().unwrap();
-    Ok(40000)
+fn native_embeddings_available() -> bool {
+    cfg!(feature = "embeddings")
 }
 
 #[rustler::nif]
-fn create_collection(
+fn native_arrow_available() -> bool {
+    cfg!(feature = "arrow")
+}
+
+/// Creates a Parquet or Arrow IPC export file at `path`.
+#[rustler::nif(schedule = "DirtyIo")]
+fn open_export(
+    path: String,
+    format: Format,
+    header: String,
+) -> NifResult<(Atom, ResourceArc<ExportWriter>)> {
+    catch_panic(|| {
+        let writer = ExportWriter::create(&path, format, header)?;
+        Ok((atoms::ok(), ResourceArc::new(writer)))
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn write_export(writer: ResourceArc<ExportWriter>, records: Records) -> NifResult<Atom> {
+    catch_panic(|| {
+        writer.write(records)?;
+        Ok(atoms::ok())
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn finish_export(writer: ResourceArc<ExportWriter>) -> NifResult<Atom> {
+    catch_panic(|| {
+        writer.finish()?;
+        Ok(atoms::ok())
+    })
+}
+
+/// Opens the Parquet or Arrow IPC export file at `path`, returning a reader
+/// and the export header.
+#[rustler::nif(schedule = "DirtyIo")]
+fn open_import(
+    path: String,
+    format: Format,
+    batch_size: usize,
+) -> NifResult<(Atom, ResourceArc<ImportReader>, String)> {
+    catch_panic(|| {
+        let reader = ImportReader::open(&path, format, batch_size)?;
+        let header = reader.header.clone();
+        Ok((atoms::ok(), ResourceArc::new(reader), header))
+    })
+}
+
+/// The next batch of records from an export file, or `nil` at its end.
+#[rustler::nif(schedule = "DirtyIo")]
+fn read_import(reader: ResourceArc<ImportReader>) -> NifResult<(Atom, Option<Records>)> {
+    catch_panic(|| {
+        Ok((atoms::ok(), reader.next()?))
+    })
+}
+
+/// Copies the store into the directory `dest` while no other call runs on
+/// it and its log is applied, replying with the backup's manifest.
+#[rustler::nif]
+fn backup<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
+    dest: String,
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let gate = bindings.gate.clone();
+        let source = bindings.storage_path.clone();
+        let pool = bindings.sqlite.get_conn().clone();
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_ungated_reply(env, reply_ref, async move {
+            let _quiesced = gate.write().await;
+            compact_all(&mut frontend, &pool).await?;
+            let manifest =
+                tokio::task::spawn_blocking(move || backup::backup(&source, &PathBuf::from(dest)))
+                    .await
+                    .map_err(ChromexError::join)??;
+            Ok((atoms::ok(), manifest))
+        })
+    })
+}
+
+/// Applies the log of every collection to its segments and waits for it to
+/// be done: a count makes Chroma's local compaction manager do so for its
+/// collection, and as the manager handles one request at a time, it is idle
+/// once the last count returns. Called with the gate held for writing, so no
+/// call queues more work meanwhile. Reads the `collections` and `databases`
+/// tables of the schema described at [`get_page`].
+async fn compact_all(
+    frontend: &mut Frontend,
+    pool: &sqlx::SqlitePool,
+) -> Result<(), ChromexError> {
+    let collections: Vec<(String, String, String)> = sqlx::query_as(
+        "SELECT c.id, d.tenant_id, d.name FROM collections c \
+         JOIN databases d ON d.id = c.database_id",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(ChromexError::internal)?;
+
+    for (collection_id, tenant, database) in collections {
+        let collection_uuid = Uuid::parse_str(&collection_id).map_err(ChromexError::internal)?;
+        let request = CountRequest::try_new(tenant, database, CollectionUuid(collection_uuid))
+            .map_err(ChromexError::validation)?;
+        frontend
+            .count(request)
+            .await
+            .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &collection_id))?;
+    }
+    Ok(())
+}
+
+/// Checks the backup in `dir` against its manifest, returning the manifest.
+#[rustler::nif(schedule = "DirtyIo")]
+fn verify_backup(dir: String) -> NifResult<(Atom, backup::Manifest)> {
+    catch_panic(|| {
+        Ok((atoms::ok(), backup::verify(&PathBuf::from(dir))?))
+    })
+}
+
+/// Closes the store within `timeout_ms`, see [`ChromaBindings::close`].
+/// Every later call on it fails with `{:error, :closed}`.
+#[rustler::nif(schedule = "DirtyIo")]
+fn close(resource: ResourceArc<ChromaBindingsResource>, timeout_ms: u64) -> NifResult<Atom> {
+    catch_panic(|| {
+        resource.bindings.close(Duration::from_millis(timeout_ms))?;
+        Ok(atoms::ok())
+    })
+}
+
+/// Loads the embedding model described by `spec` under `name`. Loading a
+/// name again replaces the model.
+#[rustler::nif(schedule = "DirtyIo")]
+fn load_embedder(
     resource: ResourceArc<ChromaBindingsResource>,
     name: String,
-    config_json: Option<String>,
-    metadata_json: Option<String>,
+    spec: ModelSpec,
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        #[cfg(feature = "embeddings")]
+        {
+            let embedder = embeddings::Embedder::load(spec)?;
+            resource
+                .bindings
+                .embedders
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(name, Arc::new(embedder));
+            Ok(atoms::ok())
+        }
+
+        #[cfg(not(feature = "embeddings"))]
+        {
+            let _ = (resource, name, spec);
+            Err(embeddings_disabled().into())
+        }
+    })
+}
+
+#[rustler::nif]
+fn embed<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
+    model: String,
+    texts: Vec<String>,
+    input: Input,
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let embeddings = bindings.embed(model, texts, input);
+        bindings.spawn_reply(env, reply_ref, async move {
+            Ok((atoms::ok(), embeddings.await?))
+        })
+    })
+}
+
+/// Replies with each text's token offsets under `model`, and how many tokens
+/// fit before truncation.
+#[rustler::nif]
+fn token_offsets<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
+    model: String,
+    texts: Vec<String>,
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let offsets = bindings.token_offsets(model, texts);
+        bindings.spawn_reply(env, reply_ref, async move {
+            Ok((atoms::ok(), offsets.await?))
+        })
+    })
+}
+
+#[rustler::nif]
+fn create_collection<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
+    name: String,
+    config: Option<Term<'a>>,
+    embedding_function: Option<Term<'a>>,
+    metadata: Option<Term<'a>>,
+    schema: Option<Term<'a>>,
     get_or_create: bool,
     tenant: String,
     database: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let metadata = if let Some(json) = metadata_json {
-        Some(
-            bindings
-                .parse_metadata(&json)
-                .map_err(|e| Error::Term(Box::new(format!("Metadata error: {:?}", e))))?,
-        )
-    } else {
-        None
-    };
-
-    let configuration = if let Some(json) = config_json {
-        let config: InternalCollectionConfiguration = serde_json::from_str(&json)
-            .map_err(|e| Error::Term(Box::new(format!("Config error: {:?}", e))))?;
-        Some(config)
-    } else {
-        None
-    };
-
-    let request = CreateCollectionRequest::try_new(
-        tenant,
-        database,
-        name,
-        metadata,
-        configuration,
-        None,
-        get_or_create,
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.create_collection(request).await
-    });
-
-    match result {
-        Ok(collection) => {
-            let json = serde_json::to_string(&collection)
-                .map_err(|e| Error::Term(Box::new(format!("Serialization error: {:?}", e))))?;
-            Ok(json)
-        }
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let metadata = metadata.map(decode::metadata).transpose()?;
+
+        let configuration = if let Some(config) = config {
+            let config: InternalCollectionConfiguration =
+                serde_json::from_value(decode::value(config)?).map_err(ChromexError::validation)?;
+            Some(config)
+        } else {
+            None
+        };
+
+        // Records the function the collection's embeddings come from, so that
+        // it is used again when the collection is reopened.
+        let configuration = match embedding_function {
+            Some(embedding_function) => {
+                let embedding_function: EmbeddingFunctionConfiguration =
+                    serde_json::from_value(decode::value(embedding_function)?)
+                        .map_err(ChromexError::validation)?;
+                let mut configuration =
+                    configuration.unwrap_or_else(InternalCollectionConfiguration::default_hnsw);
+                configuration.embedding_function = Some(embedding_function);
+                Some(configuration)
+            }
+            None => configuration,
+        };
+
+        // Declares per-key indexes, e.g. a sparse vector index on a metadata key.
+        // Chroma fills in everything not given from its default schema.
+        let schema = match schema {
+            Some(schema) => Some(
+                serde_json::from_value::<Schema>(decode::value(schema)?)
+                    .map_err(ChromexError::validation)?,
+            ),
+            None => None,
+        };
+
+        let request = CreateCollectionRequest::try_new(
+            tenant,
+            database,
+            name.clone(),
+            metadata,
+            configuration,
+            schema,
+            get_or_create,
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let collection = frontend
+                .create_collection(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &name))?;
+
+            Ok((atoms::ok(), CollectionTerm(collection)))
+        })
+    })
 }
 
 #[rustler::nif]
-fn get_collection(
+fn get_collection<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     name: String,
     tenant: String,
     database: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let request = GetCollectionRequest::try_new(
-        tenant,
-        database,
-        name,
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.get_collection(request).await
-    });
-
-    match result {
-        Ok(collection) => {
-            let json = serde_json::to_string(&collection)
-                .map_err(|e| Error::Term(Box::new(format!("Serialization error: {:?}", e))))?;
-            Ok(json)
-        }
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let request = GetCollectionRequest::try_new(
+            tenant,
+            database,
+            name.clone(),
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let collection = frontend
+                .get_collection(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &name))?;
+
+            Ok((atoms::ok(), CollectionTerm(collection)))
+        })
+    })
 }
 
 #[rustler::nif]
-fn delete_collection(
+fn delete_collection<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     name: String,
     tenant: String,
     database: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let request = DeleteCollectionRequest::try_new(
-        tenant,
-        database,
-        name,
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.delete_collection(request).await
-    });
-
-    match result {
-        Ok(_) => Ok("ok".to_string()),
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let request = DeleteCollectionRequest::try_new(
+            tenant,
+            database,
+            name.clone(),
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            frontend
+                .delete_collection(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &name))?;
+
+            Ok(atoms::ok())
+        })
+    })
 }
 
 #[rustler::nif]
-fn list_collections(
+fn list_collections<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     limit: Option<u32>,
     offset: Option<u32>,
     tenant: String,
     database: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let request = ListCollectionsRequest::try_new(
-        tenant,
-        database,
-        limit,
-        offset.unwrap_or(0),
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.list_collections(request).await
-    });
-
-    match result {
-        Ok(collections) => {
-            let json = serde_json::to_string(&collections)
-                .map_err(|e| Error::Term(Box::new(format!("Serialization error: {:?}", e))))?;
-            Ok(json)
-        }
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let request = ListCollectionsRequest::try_new(
+            tenant,
+            database.clone(),
+            limit,
+            offset.unwrap_or(0),
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let collections = frontend
+                .list_collections(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Database, &database))?;
+
+            let collections: Vec<CollectionTerm> =
+                collections.into_iter().map(CollectionTerm).collect();
+            Ok((atoms::ok(), collections))
+        })
+    })
 }
 
 #[rustler::nif]
-fn count_collections(
+fn count_collections<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     tenant: String,
     database: String,
-) -> NifResult<i32> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let request = ListCollectionsRequest::try_new(
-        tenant,
-        database,
-        None,
-        0,
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.list_collections(request).await
-    });
-
-    match result {
-        Ok(collections) => Ok(collections.len() as i32),
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let request = ListCollectionsRequest::try_new(
+            tenant,
+            database.clone(),
+            None,
+            0,
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let collections = frontend
+                .list_collections(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Database, &database))?;
+
+            Ok((atoms::ok(), collections.len() as i32))
+        })
+    })
 }
 
-#[rustler::nif]
-fn add(
+#[rustler::nif(schedule = "DirtyCpu")]
+fn add<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     ids: Vec<String>,
     collection_id: String,
-    embeddings: Vec<Vec<f32>>,
-    metadatas_json: Option<Vec<Option<String>>>,
+    embeddings: Option<Term<'a>>,
+    embedding_model: Option<String>,
+    metadatas: Option<Vec<Option<Term<'a>>>>,
     documents: Option<Vec<Option<String>>>,
     uris: Option<Vec<Option<String>>>,
     tenant: String,
     database: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let collection_uuid = Uuid::parse_str(&collection_id)
-        .map_err(|e| Error::Term(Box::new(format!("UUID error: {:?}", e))))?;
-
-    let parsed_metadatas = if let Some(json_vec) = metadatas_json {
-        let mut metadatas = Vec::new();
-        for opt_json in json_vec {
-            if let Some(json) = opt_json {
-                let metadata = bindings
-                    .parse_metadata(&json)
-                    .map_err(|e| Error::Term(Box::new(format!("Metadata error: {:?}", e))))?;
-                metadatas.push(Some(metadata));
-            } else {
-                metadatas.push(None);
-            }
-        }
-        Some(metadatas)
-    } else {
-        None
-    };
-
-    let request = AddCollectionRecordsRequest::try_new(
-        tenant,
-        database,
-        CollectionUuid(collection_uuid),
-        ids,
-        embeddings,
-        documents,
-        uris,
-        parsed_metadatas,
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.add(request).await
-    });
-
-    match result {
-        Ok(_) => Ok("ok".to_string()),
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let collection_uuid = Uuid::parse_str(&collection_id)
+            .map_err(ChromexError::validation)?;
+
+        let embeddings = bindings.embeddings_or_texts(
+            embeddings,
+            embedding_model,
+            document_texts(&documents),
+            Input::Document,
+        )?;
+        let parsed_metadatas = decode::metadatas(metadatas)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let request = AddCollectionRecordsRequest::try_new(
+                tenant,
+                database,
+                CollectionUuid(collection_uuid),
+                ids,
+                embeddings.await?,
+                documents,
+                uris,
+                parsed_metadatas,
+            ).map_err(ChromexError::validation)?;
+
+            frontend
+                .add(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &collection_id))?;
+
+            Ok(atoms::ok())
+        })
+    })
 }
 
-#[rustler::nif]
-fn query(
+#[rustler::nif(schedule = "DirtyCpu")]
+fn query<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     collection_id: String,
-    query_embeddings: Vec<Vec<f32>>,
+    query_embeddings: Option<Term<'a>>,
+    query_texts: Option<Vec<String>>,
+    embedding_model: Option<String>,
     n_results: u32,
-    where_json: Option<String>,
-    _where_document_json: Option<String>,
+    where_clause: Option<Term<'a>>,
+    where_document: Option<Term<'a>>,
     include: Vec<String>,
+    packed_embeddings: bool,
     tenant: String,
     database: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let collection_uuid = Uuid::parse_str(&collection_id)
-        .map_err(|e| Error::Term(Box::new(format!("UUID error: {:?}", e))))?;
-
-    let parsed_where = if let Some(json) = where_json {
-        bindings
-            .parse_where(&json)
-            .map_err(|e| Error::Term(Box::new(format!("Where error: {:?}", e))))?
-    } else {
-        None
-    };
-
-    let mut include_list = Vec::new();
-    if include.contains(&"documents".to_string()) {
-        include_list.push(Include::Document);
-    }
-    if include.contains(&"embeddings".to_string()) {
-        include_list.push(Include::Embedding);
-    }
-    if include.contains(&"metadatas".to_string()) {
-        include_list.push(Include::Metadata);
-    }
-    if include.contains(&"distances".to_string()) {
-        include_list.push(Include::Distance);
-    }
-    if include.contains(&"uris".to_string()) {
-        include_list.push(Include::Uri);
-    }
-
-    let request = QueryRequest::try_new(
-        tenant,
-        database,
-        CollectionUuid(collection_uuid),
-        None,
-        parsed_where,
-        query_embeddings,
-        n_results,
-        IncludeList(include_list),
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.query(request).await
-    });
-
-    match result {
-        Ok(query_result) => {
-            let json = serde_json::to_string(&query_result)
-                .map_err(|e| Error::Term(Box::new(format!("Serialization error: {:?}", e))))?;
-            Ok(json)
-        }
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let collection_uuid = Uuid::parse_str(&collection_id)
+            .map_err(ChromexError::validation)?;
+
+        let query_embeddings = bindings.embeddings_or_texts(
+            query_embeddings,
+            embedding_model,
+            query_texts,
+            Input::Query,
+        )?;
+        let parsed_where = decode::where_clause(where_clause, where_document)?;
+
+        let include_list = include_list(&include);
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let request = QueryRequest::try_new(
+                tenant,
+                database,
+                CollectionUuid(collection_uuid),
+                None,
+                parsed_where,
+                query_embeddings.await?,
+                n_results,
+                IncludeList(include_list),
+            ).map_err(ChromexError::validation)?;
+
+            let query_result = frontend
+                .query(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &collection_id))?;
+
+            Ok((atoms::ok(), QueryTerm { response: query_result, packed_embeddings }))
+        })
+    })
 }
 
-#[rustler::nif]
-fn get(
+#[rustler::nif(schedule = "DirtyCpu")]
+fn get<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     collection_id: String,
     ids: Option<Vec<String>>,
-    where_json: Option<String>,
+    where_clause: Option<Term<'a>>,
     limit: Option<u32>,
     offset: Option<u32>,
-    _where_document_json: Option<String>,
+    where_document: Option<Term<'a>>,
     include: Vec<String>,
+    packed_embeddings: bool,
     tenant: String,
     database: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let collection_uuid = Uuid::parse_str(&collection_id)
-        .map_err(|e| Error::Term(Box::new(format!("UUID error: {:?}", e))))?;
-
-    let parsed_where = if let Some(json) = where_json {
-        bindings
-            .parse_where(&json)
-            .map_err(|e| Error::Term(Box::new(format!("Where error: {:?}", e))))?
-    } else {
-        None
-    };
-
-    let mut include_list = Vec::new();
-    if include.contains(&"documents".to_string()) {
-        include_list.push(Include::Document);
-    }
-    if include.contains(&"embeddings".to_string()) {
-        include_list.push(Include::Embedding);
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let collection_uuid = Uuid::parse_str(&collection_id)
+            .map_err(ChromexError::validation)?;
+
+        let parsed_where = decode::where_clause(where_clause, where_document)?;
+
+        let include_list = include_list(&include);
+
+        let request = GetRequest::try_new(
+            tenant,
+            database,
+            CollectionUuid(collection_uuid),
+            ids,
+            parsed_where,
+            limit,
+            offset.unwrap_or(0),
+            IncludeList(include_list),
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let get_result = frontend
+                .get(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &collection_id))?;
+
+            Ok((atoms::ok(), GetTerm { response: get_result, packed_embeddings }))
+        })
+    })
+}
+
+/// Replies with `{next_cursor, page}`: the collection's next `limit` records
+/// whose ids sort after `after`, in id order, narrowed by the filters.
+/// `next_cursor` is `nil` once the last record has been read.
+///
+/// Chroma has no request for reading ids after a cursor, so they are read
+/// from its SQLite schema as of the pinned commit 8963e1df (see
+/// Cargo.toml): the `embeddings` table's `embedding_id` and `segment_id`,
+/// and the `segments` table's `id`, `collection` and `scope`, where
+/// `'METADATA'` marks the segment holding the records. The stream tests pin
+/// this down; check it when upgrading Chroma.
+#[rustler::nif]
+fn get_page<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
+    collection_id: String,
+    after: Option<String>,
+    limit: u32,
+    where_clause: Option<Term<'a>>,
+    where_document: Option<Term<'a>>,
+    include: Vec<String>,
+    packed_embeddings: bool,
+    tenant: String,
+    database: String,
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let collection_uuid = Uuid::parse_str(&collection_id)
+            .map_err(ChromexError::validation)?;
+        if limit == 0 {
+            return Err(
+                ChromexError::Validation("page size must be greater than 0".to_string()).into()
+            );
+        }
+
+        let parsed_where = decode::where_clause(where_clause, where_document)?;
+        let include_list = include_list(&include);
+
+        let count = CountRequest::try_new(
+            tenant.clone(),
+            database.clone(),
+            CollectionUuid(collection_uuid),
+        ).map_err(ChromexError::validation)?;
+
+        let pool = bindings.sqlite.get_conn().clone();
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            // Chroma applies the writes still in its log to the segments
+            // before serving any read, and the SQL below reads the metadata
+            // segment directly; a count is the cheapest read that does so.
+            frontend
+                .count(count)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &collection_id))?;
+
+            // Ids are unique within a segment and, unlike the rowids behind
+            // them, never stand for another record, so a cursor on them skips
+            // or repeats nothing however the collection changes meanwhile.
+            let ids: Vec<String> = sqlx::query_scalar(
+                "SELECT e.embedding_id FROM embeddings e \
+                 JOIN segments s ON s.id = e.segment_id \
+                 WHERE s.collection = ?1 AND s.scope = 'METADATA' \
+                 AND (?2 IS NULL OR e.embedding_id > ?2) \
+                 ORDER BY e.embedding_id LIMIT ?3",
+            )
+            .bind(&collection_id)
+            .bind(after)
+            .bind(i64::from(limit))
+            .fetch_all(&pool)
+            .await
+            .map_err(ChromexError::internal)?;
+
+            let next_cursor = match ids.last() {
+                Some(id) if ids.len() == limit as usize => Some(id.clone()),
+                _ => None,
+            };
+
+            let request = GetRequest::try_new(
+                tenant,
+                database,
+                CollectionUuid(collection_uuid),
+                Some(ids),
+                parsed_where,
+                None,
+                0,
+                IncludeList(include_list),
+            ).map_err(ChromexError::validation)?;
+
+            let mut page = frontend
+                .get(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &collection_id))?;
+            sort_by_id(&mut page);
+
+            Ok((atoms::ok(), (next_cursor, GetTerm { response: page, packed_embeddings })))
+        })
+    })
+}
+
+/// Sorts a page's records by id, the order its ids were read in: `get` does
+/// not promise to return records in the order they were asked for. Rust
+/// compares strings bytewise, as SQLite's default collation does.
+fn sort_by_id(page: &mut GetResponse) {
+    let mut order: Vec<usize> = (0..page.ids.len()).collect();
+    order.sort_by(|&a, &b| page.ids[a].cmp(&page.ids[b]));
+
+    fn reorder<T>(values: &mut Vec<T>, order: &[usize]) {
+        let mut taken: Vec<Option<T>> = std::mem::take(values).into_iter().map(Some).collect();
+        *values = order.iter().filter_map(|&row| taken[row].take()).collect();
     }
-    if include.contains(&"metadatas".to_string()) {
-        include_list.push(Include::Metadata);
+
+    reorder(&mut page.ids, &order);
+    if let Some(embeddings) = page.embeddings.as_mut() {
+        reorder(embeddings, &order);
     }
-    if include.contains(&"distances".to_string()) {
-        include_list.push(Include::Distance);
+    if let Some(documents) = page.documents.as_mut() {
+        reorder(documents, &order);
     }
-    if include.contains(&"uris".to_string()) {
-        include_list.push(Include::Uri);
+    if let Some(uris) = page.uris.as_mut() {
+        reorder(uris, &order);
     }
-
-    let request = GetRequest::try_new(
-        tenant,
-        database,
-        CollectionUuid(collection_uuid),
-        ids,
-        parsed_where,
-        limit,
-        offset.unwrap_or(0),
-        IncludeList(include_list),
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.get(request).await
-    });
-
-    match result {
-        Ok(get_result) => {
-            let json = serde_json::to_string(&get_result)
-                .map_err(|e| Error::Term(Box::new(format!("Serialization error: {:?}", e))))?;
-            Ok(json)
-        }
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
+    if let Some(metadatas) = page.metadatas.as_mut() {
+        reorder(metadatas, &order);
     }
 }
 
-#[rustler::nif]
-fn update(
+#[rustler::nif(schedule = "DirtyCpu")]
+fn update<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     collection_id: String,
     ids: Vec<String>,
-    embeddings: Option<Vec<Option<Vec<f32>>>>,
-    metadatas_json: Option<Vec<Option<String>>>,
+    embeddings: Option<Term<'a>>,
+    metadatas: Option<Vec<Option<Term<'a>>>>,
     documents: Option<Vec<Option<String>>>,
     uris: Option<Vec<Option<String>>>,
     tenant: String,
     database: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let collection_uuid = Uuid::parse_str(&collection_id)
-        .map_err(|e| Error::Term(Box::new(format!("UUID error: {:?}", e))))?;
-
-    let parsed_metadatas = if let Some(json_vec) = metadatas_json {
-        let mut metadatas = Vec::new();
-        for opt_json in json_vec {
-            if let Some(json) = opt_json {
-                let metadata = bindings
-                    .parse_update_metadata(&json)
-                    .map_err(|e| Error::Term(Box::new(format!("Metadata error: {:?}", e))))?;
-                metadatas.push(Some(metadata));
-            } else {
-                metadatas.push(None);
-            }
-        }
-        Some(metadatas)
-    } else {
-        None
-    };
-
-    let request = UpdateCollectionRecordsRequest::try_new(
-        tenant,
-        database,
-        CollectionUuid(collection_uuid),
-        ids,
-        embeddings,
-        documents,
-        uris,
-        parsed_metadatas,
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.update(request).await
-    });
-
-    match result {
-        Ok(_) => Ok("ok".to_string()),
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let collection_uuid = Uuid::parse_str(&collection_id)
+            .map_err(ChromexError::validation)?;
+
+        let embeddings = decode::update_embeddings(embeddings)?;
+        let parsed_metadatas = decode::update_metadatas(metadatas)?;
+
+        let request = UpdateCollectionRecordsRequest::try_new(
+            tenant,
+            database,
+            CollectionUuid(collection_uuid),
+            ids,
+            embeddings,
+            documents,
+            uris,
+            parsed_metadatas,
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            frontend
+                .update(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &collection_id))?;
+
+            Ok(atoms::ok())
+        })
+    })
 }
 
-#[rustler::nif]
-fn upsert(
+#[rustler::nif(schedule = "DirtyCpu")]
+fn upsert<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     collection_id: String,
     ids: Vec<String>,
-    embeddings: Vec<Vec<f32>>,
-    metadatas_json: Option<Vec<Option<String>>>,
+    embeddings: Option<Term<'a>>,
+    embedding_model: Option<String>,
+    metadatas: Option<Vec<Option<Term<'a>>>>,
     documents: Option<Vec<Option<String>>>,
     uris: Option<Vec<Option<String>>>,
     tenant: String,
     database: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let collection_uuid = Uuid::parse_str(&collection_id)
-        .map_err(|e| Error::Term(Box::new(format!("UUID error: {:?}", e))))?;
-
-    let parsed_metadatas = if let Some(json_vec) = metadatas_json {
-        let mut metadatas = Vec::new();
-        for opt_json in json_vec {
-            if let Some(json) = opt_json {
-                let metadata = bindings
-                    .parse_update_metadata(&json)
-                    .map_err(|e| Error::Term(Box::new(format!("Metadata error: {:?}", e))))?;
-                metadatas.push(Some(metadata));
-            } else {
-                metadatas.push(None);
-            }
-        }
-        Some(metadatas)
-    } else {
-        None
-    };
-
-    let request = UpsertCollectionRecordsRequest::try_new(
-        tenant,
-        database,
-        CollectionUuid(collection_uuid),
-        ids,
-        embeddings,
-        documents,
-        uris,
-        parsed_metadatas,
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.upsert(request).await
-    });
-
-    match result {
-        Ok(_) => Ok("ok".to_string()),
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let collection_uuid = Uuid::parse_str(&collection_id)
+            .map_err(ChromexError::validation)?;
+
+        let embeddings = bindings.embeddings_or_texts(
+            embeddings,
+            embedding_model,
+            document_texts(&documents),
+            Input::Document,
+        )?;
+        let parsed_metadatas = decode::update_metadatas(metadatas)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let request = UpsertCollectionRecordsRequest::try_new(
+                tenant,
+                database,
+                CollectionUuid(collection_uuid),
+                ids,
+                embeddings.await?,
+                documents,
+                uris,
+                parsed_metadatas,
+            ).map_err(ChromexError::validation)?;
+
+            frontend
+                .upsert(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &collection_id))?;
+
+            Ok(atoms::ok())
+        })
+    })
 }
 
 #[rustler::nif]
-fn delete(
+fn delete<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     collection_id: String,
     ids: Option<Vec<String>>,
-    where_json: Option<String>,
-    _where_document_json: Option<String>,
+    where_clause: Option<Term<'a>>,
+    where_document: Option<Term<'a>>,
     tenant: String,
     database: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let collection_uuid = Uuid::parse_str(&collection_id)
-        .map_err(|e| Error::Term(Box::new(format!("UUID error: {:?}", e))))?;
-
-    let parsed_where = if let Some(json) = where_json {
-        bindings
-            .parse_where(&json)
-            .map_err(|e| Error::Term(Box::new(format!("Where error: {:?}", e))))?
-    } else {
-        None
-    };
-
-    let request = DeleteCollectionRecordsRequest::try_new(
-        tenant,
-        database,
-        CollectionUuid(collection_uuid),
-        ids,
-        parsed_where,
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.delete(request).await
-    });
-
-    match result {
-        Ok(_) => Ok("ok".to_string()),
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let collection_uuid = Uuid::parse_str(&collection_id)
+            .map_err(ChromexError::validation)?;
+
+        let parsed_where = decode::where_clause(where_clause, where_document)?;
+
+        let request = DeleteCollectionRecordsRequest::try_new(
+            tenant,
+            database,
+            CollectionUuid(collection_uuid),
+            ids,
+            parsed_where,
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            frontend
+                .delete(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &collection_id))?;
+
+            Ok(atoms::ok())
+        })
+    })
 }
 
 #[rustler::nif]
-fn count(
+fn count<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     collection_id: String,
     tenant: String,
     database: String,
-) -> NifResult<i32> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let collection_uuid = Uuid::parse_str(&collection_id)
-        .map_err(|e| Error::Term(Box::new(format!("UUID error: {:?}", e))))?;
-
-    let request = CountRequest::try_new(
-        tenant,
-        database,
-        CollectionUuid(collection_uuid),
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.count(request).await
-    });
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let collection_uuid = Uuid::parse_str(&collection_id)
+            .map_err(ChromexError::validation)?;
+
+        let request = CountRequest::try_new(
+            tenant,
+            database,
+            CollectionUuid(collection_uuid),
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let count = frontend
+                .count(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &collection_id))?;
+
+            Ok((atoms::ok(), count as i32))
+        })
+    })
+}
 
-    match result {
-        Ok(count) => Ok(count as i32),
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+/// Runs one or more search payloads (filter, rank expression, limit and
+/// select) against a collection. Results are returned per payload in the
+/// same order.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn search<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
+    collection_id: String,
+    searches: Vec<Term<'a>>,
+    tenant: String,
+    database: String,
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let collection_uuid = Uuid::parse_str(&collection_id)
+            .map_err(ChromexError::validation)?;
+
+        let searches = searches
+            .into_iter()
+            .map(decode::search_payload)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let request = SearchRequest::try_new(
+            tenant,
+            database,
+            CollectionUuid(collection_uuid),
+            searches,
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let search_result = frontend
+                .search(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &collection_id))?;
+
+            Ok((atoms::ok(), SearchTerm(search_result)))
+        })
+    })
 }
 
 #[rustler::nif]
-fn create_database(
+fn create_database<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     name: String,
     tenant: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let request = CreateDatabaseRequest::try_new(
-        tenant,
-        name,
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.create_database(request).await
-    });
-
-    match result {
-        Ok(database) => {
-            let json = serde_json::to_string(&database)
-                .map_err(|e| Error::Term(Box::new(format!("Serialization error: {:?}", e))))?;
-            Ok(json)
-        }
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let request = CreateDatabaseRequest::try_new(
+            tenant,
+            name.clone(),
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let database = frontend
+                .create_database(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Database, &name))?;
+
+            Ok((atoms::ok(), ValueTerm::new(&database)?))
+        })
+    })
 }
 
 #[rustler::nif]
-fn get_database(
+fn get_database<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     name: String,
     tenant: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let request = GetDatabaseRequest::try_new(
-        tenant,
-        name,
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.get_database(request).await
-    });
-
-    match result {
-        Ok(database) => {
-            let json = serde_json::to_string(&database)
-                .map_err(|e| Error::Term(Box::new(format!("Serialization error: {:?}", e))))?;
-            Ok(json)
-        }
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let request = GetDatabaseRequest::try_new(
+            tenant,
+            name.clone(),
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let database = frontend
+                .get_database(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Database, &name))?;
+
+            Ok((atoms::ok(), ValueTerm::new(&database)?))
+        })
+    })
 }
 
 #[rustler::nif]
-fn delete_database(
+fn delete_database<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     name: String,
     tenant: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let request = DeleteDatabaseRequest::try_new(
-        tenant,
-        name,
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.delete_database(request).await
-    });
-
-    match result {
-        Ok(_) => Ok("ok".to_string()),
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let request = DeleteDatabaseRequest::try_new(
+            tenant,
+            name.clone(),
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            frontend
+                .delete_database(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Database, &name))?;
+
+            Ok(atoms::ok())
+        })
+    })
 }
 
 #[rustler::nif]
-fn list_databases(
+fn list_databases<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     limit: Option<u32>,
     offset: Option<u32>,
     tenant: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let request = ListDatabasesRequest::try_new(
-        tenant,
-        limit,
-        offset.unwrap_or(0),
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.list_databases(request).await
-    });
-
-    match result {
-        Ok(databases) => {
-            let json = serde_json::to_string(&databases)
-                .map_err(|e| Error::Term(Box::new(format!("Serialization error: {:?}", e))))?;
-            Ok(json)
-        }
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let request = ListDatabasesRequest::try_new(
+            tenant.clone(),
+            limit,
+            offset.unwrap_or(0),
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let databases = frontend
+                .list_databases(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Tenant, &tenant))?;
+
+            Ok((atoms::ok(), ValueTerm::new(&databases)?))
+        })
+    })
 }
 
 #[rustler::nif]
-fn create_tenant(
+fn create_tenant<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     name: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let request = CreateTenantRequest::try_new(name)
-        .map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.create_tenant(request).await
-    });
-
-    match result {
-        Ok(tenant) => {
-            let json = serde_json::to_string(&tenant)
-                .map_err(|e| Error::Term(Box::new(format!("Serialization error: {:?}", e))))?;
-            Ok(json)
-        }
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let request = CreateTenantRequest::try_new(name.clone())
+            .map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let tenant = frontend
+                .create_tenant(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Tenant, &name))?;
+
+            Ok((atoms::ok(), ValueTerm::new(&tenant)?))
+        })
+    })
 }
 
 #[rustler::nif]
-fn get_tenant(
+fn get_tenant<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     name: String,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let request = GetTenantRequest::try_new(name)
-        .map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.get_tenant(request).await
-    });
-
-    match result {
-        Ok(tenant) => {
-            let json = serde_json::to_string(&tenant)
-                .map_err(|e| Error::Term(Box::new(format!("Serialization error: {:?}", e))))?;
-            Ok(json)
-        }
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
-}
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
 
-#[rustler::nif]
-fn reset(resource: ResourceArc<ChromaBindingsResource>) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
+        let request = GetTenantRequest::try_new(name.clone())
+            .map_err(ChromexError::validation)?;
 
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.reset().await
-    });
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            let tenant = frontend
+                .get_tenant(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Tenant, &name))?;
 
-    match result {
-        Ok(_) => Ok("ok".to_string()),
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+            Ok((atoms::ok(), ValueTerm::new(&tenant)?))
+        })
+    })
 }
 
 #[rustler::nif]
-fn update_collection(
+fn reset<'a>(
+    env: Env<'a>,
     resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            frontend
+                .reset()
+                .await
+                .map_err(ChromexError::internal)?;
+
+            Ok(atoms::ok())
+        })
+    })
+}
+
+#[rustler::nif]
+fn update_collection<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
     collection_id: String,
     new_name: Option<String>,
-    new_metadata_json: Option<String>,
-    _new_config_json: Option<String>,
-) -> NifResult<String> {
-    let bindings = resource.inner.lock().unwrap();
-
-    let collection_uuid = Uuid::parse_str(&collection_id)
-        .map_err(|e| Error::Term(Box::new(format!("UUID error: {:?}", e))))?;
-
-    let parsed_metadata = if let Some(json) = new_metadata_json {
-        let metadata = bindings
-            .parse_update_metadata(&json)
-            .map_err(|e| Error::Term(Box::new(format!("Metadata error: {:?}", e))))?;
-        Some(CollectionMetadataUpdate::UpdateMetadata(metadata))
-    } else {
-        None
-    };
-
-    let request = UpdateCollectionRequest::try_new(
-        CollectionUuid(collection_uuid),
-        new_name,
-        parsed_metadata,
-        None,
-    ).map_err(|e| Error::Term(Box::new(format!("Request error: {:?}", e))))?;
-
-    let mut frontend = bindings.frontend.lock().unwrap();
-    let result = bindings.runtime.block_on(async {
-        frontend.update_collection(request).await
-    });
-
-    match result {
-        Ok(collection) => {
-            let json = serde_json::to_string(&collection)
-                .map_err(|e| Error::Term(Box::new(format!("Serialization error: {:?}", e))))?;
-            Ok(json)
+    new_metadata: Option<Term<'a>>,
+    new_config: Option<Term<'a>>,
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        let collection_uuid = Uuid::parse_str(&collection_id)
+            .map_err(ChromexError::validation)?;
+
+        let parsed_metadata = new_metadata
+            .map(decode::update_metadata)
+            .transpose()?
+            .map(CollectionMetadataUpdate::UpdateMetadata);
+
+        // e.g. %{"hnsw" => %{"ef_search" => 200, "num_threads" => 4}}; fields that
+        // cannot be changed after creation are rejected by serde or `try_from`.
+        let parsed_config = match new_config {
+            Some(config) => {
+                let config: UpdateCollectionConfiguration =
+                    serde_json::from_value(decode::value(config)?)
+                        .map_err(ChromexError::validation)?;
+                Some(
+                    InternalUpdateCollectionConfiguration::try_from(config)
+                        .map_err(ChromexError::validation)?,
+                )
+            }
+            None => None,
+        };
+
+        let request = UpdateCollectionRequest::try_new(
+            CollectionUuid(collection_uuid),
+            new_name,
+            parsed_metadata,
+            parsed_config,
+        ).map_err(ChromexError::validation)?;
+
+        let mut frontend = bindings.frontend.clone();
+        bindings.spawn_reply(env, reply_ref, async move {
+            frontend
+                .update_collection(request)
+                .await
+                .map_err(|e| ChromexError::from_chroma(&e, Resource::Collection, &collection_id))?;
+
+            Ok(atoms::ok())
+        })
+    })
+}
+
+/// Where [`test_panic`] panics.
+#[cfg(feature = "test_hooks")]
+#[derive(rustler::NifUnitEnum)]
+enum PanicAt {
+    /// While preparing the call, before anything is spawned.
+    Call,
+    /// In the spawned task.
+    Task,
+    /// While holding the session lock of a loaded embedding model, which
+    /// leaves the model unusable until the store is restarted.
+    Lock,
+}
+
+/// Panics where `at` says, for the tests of panic handling.
+#[cfg(feature = "test_hooks")]
+#[rustler::nif]
+fn test_panic<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ChromaBindingsResource>,
+    reply_ref: Term<'a>,
+    at: PanicAt,
+) -> NifResult<Atom> {
+    catch_panic(|| {
+        let bindings = &resource.bindings;
+
+        match at {
+            PanicAt::Call => panic_now("in the call"),
+            PanicAt::Task => bindings.spawn_reply(env, reply_ref, async move {
+                panic_now::<Result<Atom, ChromexError>>("in the task")
+            }),
+            PanicAt::Lock => poison_embedder(bindings),
         }
-        Err(e) => Err(Error::Term(Box::new(format!("{:?}", e)))),
-    }
+    })
+}
+
+#[cfg(all(feature = "test_hooks", feature = "embeddings"))]
+fn poison_embedder(bindings: &ChromaBindings) -> NifResult<Atom> {
+    let embedder = bindings
+        .embedders
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .values()
+        .next()
+        .cloned()
+        .ok_or_else(|| ChromexError::Validation("no embedding model is loaded".to_string()))?;
+    embedder.panic_holding_session("test panic while holding a lock")
+}
+
+#[cfg(all(feature = "test_hooks", not(feature = "embeddings")))]
+fn poison_embedder(_bindings: &ChromaBindings) -> NifResult<Atom> {
+    Err(embeddings_disabled().into())
+}
+
+#[cfg(feature = "test_hooks")]
+fn panic_now<T>(place: &str) -> T {
+    panic!("test panic {place}")
 }
 
 rustler::init!("Elixir.ChromEx.Native", load = on_load);
@@ -0,0 +1,110 @@
+defmodule ChromEx.BackupTest do
+  use ExUnit.Case, async: false
+
+  alias ChromEx.Collection
+
+  setup do
+    suffix = :rand.uniform(100000)
+    persist_path = Path.join(System.tmp_dir!(), "chromex_backup_store_#{suffix}")
+    dest = Path.join(System.tmp_dir!(), "chromex_backup_#{suffix}")
+
+    on_exit(fn ->
+      File.rm_rf(persist_path)
+      File.rm_rf(dest)
+    end)
+
+    start_supervised!({ChromEx.Client, name: __MODULE__.Store, persist_path: persist_path})
+    {:ok, collection} = Collection.create("backed_up", client: __MODULE__.Store)
+
+    :ok =
+      Collection.add(collection,
+        ids: for(i <- 1..20, do: "id#{i}"),
+        embeddings: for(i <- 1..20, do: [i * 1.0, 1.0]),
+        documents: for(i <- 1..20, do: "document #{i}")
+      )
+
+    %{client: __MODULE__.Store, collection: collection, dest: dest, persist_path: persist_path}
+  end
+
+  test "writes a manifest covering the database and segments",
+       %{client: client, dest: dest} do
+    assert {:ok, manifest} = ChromEx.backup(dest, client: client)
+
+    assert %{version: 1, files: files} = manifest
+    assert Enum.any?(files, &(&1.path == "chroma.db"))
+    assert Enum.any?(files, &String.contains?(&1.path, "/"))
+    assert File.exists?(Path.join(dest, "manifest.json"))
+
+    for %{path: path, size: size} <- files do
+      assert File.stat!(Path.join(dest, path)).size == size
+    end
+  end
+
+  test "refuses a destination that is not empty", %{client: client, dest: dest} do
+    File.mkdir_p!(dest)
+    File.write!(Path.join(dest, "other"), "")
+
+    assert {:error, {:validation, _message}} = ChromEx.backup(dest, client: client)
+  end
+
+  test "refuses a destination inside the store without creating it",
+       %{client: client, persist_path: persist_path} do
+    dest = Path.join([persist_path, "nested", "backup"])
+
+    assert {:error, {:validation, message}} = ChromEx.backup(dest, client: client)
+    assert message =~ "inside the store"
+    refute File.exists?(Path.join(persist_path, "nested"))
+  end
+
+  test "is consistent while writes are in flight",
+       %{client: client, collection: collection, dest: dest} do
+    writer =
+      Task.async(fn ->
+        for i <- 21..120 do
+          :ok = Collection.add(collection, ids: ["id#{i}"], embeddings: [[i * 1.0, 1.0]])
+        end
+      end)
+
+    assert {:ok, _manifest} = ChromEx.backup(dest, client: client)
+    Task.await(writer, 60_000)
+
+    assert {:ok, _manifest} = ChromEx.restore(dest, client: client)
+    {:ok, restored} = Collection.get("backed_up", client: client)
+    {:ok, count} = Collection.count(restored)
+    {:ok, %{"ids" => ids}} = Collection.get_documents(restored, include: [])
+
+    assert count in 20..120
+    assert Enum.sort(ids) == Enum.sort(for(i <- 1..count, do: "id#{i}"))
+  end
+
+  test "restores the store as it was backed up",
+       %{client: client, collection: collection, dest: dest} do
+    {:ok, _manifest} = ChromEx.backup(dest, client: client)
+
+    :ok = Collection.delete_documents(collection, ids: ["id1", "id2"])
+    {:ok, _other} = Collection.create("created_later", client: client)
+
+    assert {:ok, %{version: 1}} = ChromEx.restore(dest, client: client)
+
+    assert {:ok, restored} = Collection.get("backed_up", client: client)
+    assert {:ok, 20} = Collection.count(restored)
+
+    assert {:ok, %{"ids" => [["id3"]]}} =
+             Collection.query(restored, query_embeddings: [[3.0, 1.0]], n_results: 1)
+
+    assert {:error, {:not_found, :collection, "created_later"}} =
+             Collection.get("created_later", client: client)
+  end
+
+  test "rejects a backup that does not match its manifest",
+       %{client: client, collection: collection, dest: dest} do
+    {:ok, _manifest} = ChromEx.backup(dest, client: client)
+    :ok = Collection.delete_documents(collection, ids: ["id1"])
+
+    File.write!(Path.join(dest, "chroma.db"), "corrupted", [:append])
+
+    assert {:error, {:validation, message}} = ChromEx.restore(dest, client: client)
+    assert message =~ "chroma.db"
+    assert {:ok, 19} = Collection.count(collection)
+  end
+end
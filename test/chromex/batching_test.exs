@@ -0,0 +1,114 @@
+defmodule ChromEx.BatchingTest do
+  use ExUnit.Case, async: false
+
+  setup do
+    collection_name = "test_batching_#{:rand.uniform(100000)}"
+    {:ok, collection} = ChromEx.Collection.create(collection_name)
+
+    on_exit(fn ->
+      try do
+        ChromEx.Collection.delete(collection_name)
+      rescue
+        _ -> :ok
+      end
+    end)
+
+    %{collection: collection}
+  end
+
+  # The last record's embedding has the wrong dimension, so its batch fails
+  defp records(count) do
+    ids = Enum.map(1..count, &"id#{&1}")
+    embeddings = Enum.map(1..(count - 1), &[&1 * 1.0, 1.0]) ++ [[1.0, 2.0, 3.0]]
+    [ids: ids, embeddings: embeddings]
+  end
+
+  test "splits writes larger than the batch size", %{collection: collection} do
+    ids = Enum.map(1..7, &"id#{&1}")
+    embeddings = Nx.iota({7, 2}, type: :f32)
+
+    :ok = ChromEx.Collection.add(collection, ids: ids, embeddings: embeddings, batch_size: 3)
+    assert {:ok, 7} = ChromEx.Collection.count(collection)
+
+    :ok =
+      ChromEx.Collection.update_documents(collection,
+        ids: ids,
+        documents: Enum.map(ids, &"doc #{&1}"),
+        batch_size: 2,
+        max_concurrency: 4
+      )
+
+    assert {:ok, %{"documents" => ["doc id7"]}} =
+             ChromEx.Collection.get_documents(collection, ids: ["id7"])
+  end
+
+  test "atomic writes undo earlier batches when one fails", %{collection: collection} do
+    assert {:error, {:batches_failed, [%{batch: 2, ids: ["id5"]}]}} =
+             ChromEx.Collection.add(collection, records(5) ++ [batch_size: 2])
+
+    assert {:ok, 0} = ChromEx.Collection.count(collection)
+  end
+
+  test "atomic adds keep records that existed before", %{collection: collection} do
+    :ok =
+      ChromEx.Collection.add(collection,
+        ids: ["id2", "id3"],
+        embeddings: [[9.0, 9.0], [8.0, 8.0]]
+      )
+
+    assert {:error, {:batches_failed, [%{batch: 2, ids: ["id5"]}]}} =
+             ChromEx.Collection.add(collection, records(5) ++ [batch_size: 2])
+
+    assert {:ok, %{"ids" => ids, "embeddings" => embeddings}} =
+             ChromEx.Collection.get_documents(collection, include: ["embeddings"])
+
+    assert Enum.sort(Enum.zip(ids, embeddings)) == [{"id2", [9.0, 9.0]}, {"id3", [8.0, 8.0]}]
+  end
+
+  test "atomic upserts restore overwritten records", %{collection: collection} do
+    :ok =
+      ChromEx.Collection.add(collection,
+        ids: ["id1"],
+        embeddings: [[9.0, 9.0]],
+        documents: ["original"]
+      )
+
+    upsert = records(3) ++ [documents: ["new", "new", "new"], batch_size: 1]
+    assert {:error, {:batches_failed, _}} = ChromEx.Collection.upsert(collection, upsert)
+
+    assert {:ok, %{"ids" => ["id1"], "documents" => ["original"], "embeddings" => [[9.0, 9.0]]}} =
+             ChromEx.Collection.get_documents(collection, include: ["documents", "embeddings"])
+  end
+
+  test "atomic updates drop metadata keys they added", %{collection: collection} do
+    :ok =
+      ChromEx.Collection.add(collection,
+        ids: ["id1", "id2"],
+        embeddings: [[9.0, 9.0], [8.0, 8.0]],
+        metadatas: [%{"kept" => 1}, %{"kept" => 2}]
+      )
+
+    update = records(2) ++ [metadatas: [%{"added" => true}, %{"added" => true}], batch_size: 1]
+
+    assert {:error, {:batches_failed, _}} =
+             ChromEx.Collection.update_documents(collection, update)
+
+    assert {:ok, %{"ids" => ids, "metadatas" => metadatas, "documents" => documents}} =
+             ChromEx.Collection.get_documents(collection, include: ["metadatas", "documents"])
+
+    assert Enum.sort(Enum.zip(ids, metadatas)) ==
+             [{"id1", %{"kept" => 1}}, {"id2", %{"kept" => 2}}]
+
+    assert documents == [nil, nil]
+  end
+
+  test "best-effort writes keep the batches that succeeded", %{collection: collection} do
+    add = records(5) ++ [batch_size: 2, batch_mode: :best_effort]
+
+    assert {:error, {:batches_failed, [%{batch: 2, ids: ["id5"], reason: _}]} = reason} =
+             ChromEx.Collection.add(collection, add)
+
+    assert {:ok, 4} = ChromEx.Collection.count(collection)
+    assert Exception.message(%ChromEx.Error{reason: reason}) =~ "batch 2"
+  end
+end
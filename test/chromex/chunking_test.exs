@@ -0,0 +1,190 @@
+defmodule ChromEx.ChunkingTest do
+  use ExUnit.Case, async: false
+
+  import ExUnit.CaptureLog
+
+  # Well over all-MiniLM-L6-v2's 256 token input
+  @long Enum.map_join(1..400, " ", &"word#{&1}")
+
+  setup do
+    collection_name = "test_chunking_#{:rand.uniform(100000)}"
+    {:ok, collection} = ChromEx.Collection.create(collection_name)
+
+    on_exit(fn ->
+      try do
+        ChromEx.Collection.delete(collection_name)
+      rescue
+        _ -> :ok
+      end
+    end)
+
+    %{collection: collection}
+  end
+
+  test "truncates silently by default", %{collection: collection} do
+    log =
+      capture_log(fn ->
+        :ok = ChromEx.Collection.add(collection, ids: ["long"], documents: [@long])
+      end)
+
+    refute log =~ "longer than"
+  end
+
+  test "reports documents truncated by default through telemetry", %{collection: collection} do
+    test_pid = self()
+    handler = "truncated-#{inspect(test_pid)}"
+
+    :telemetry.attach(
+      handler,
+      [:chromex, :collection, :truncated],
+      fn _event, measurements, metadata, _config ->
+        send(test_pid, {:truncated, measurements, metadata})
+      end,
+      nil
+    )
+
+    on_exit(fn -> :telemetry.detach(handler) end)
+
+    :ok = ChromEx.Collection.add(collection, ids: ["long", "short"], documents: [@long, "short"])
+
+    assert_receive {:truncated, %{count: 1},
+                    %{collection_id: id, documents: [%{id: "long", max_tokens: 254}]}}
+
+    assert id == collection.id
+  end
+
+  test "warns about truncated documents", %{collection: collection} do
+    log =
+      capture_log(fn ->
+        :ok =
+          ChromEx.Collection.add(collection,
+            ids: ["long", "short"],
+            documents: [@long, "short"],
+            truncation: :warn
+          )
+      end)
+
+    assert log =~ ~s(longer than 254 tokens: "long")
+    refute log =~ ~s("short")
+  end
+
+  test "reports truncation per document", %{collection: collection} do
+    assert {:error, {:truncated, [%{id: "long", tokens: tokens, max_tokens: 254}]}} =
+             ChromEx.Collection.add(collection,
+               ids: ["long", "short"],
+               documents: [@long, "short"],
+               truncation: :error
+             )
+
+    assert tokens > 254
+    assert {:ok, 0} = ChromEx.Collection.count(collection)
+  end
+
+  test "stores over-length documents as overlapping chunks", %{collection: collection} do
+    :ok =
+      ChromEx.Collection.add(collection,
+        ids: ["long", "short"],
+        documents: [@long, "short"],
+        metadatas: [%{"source" => "manual"}, %{"source" => "faq"}],
+        truncation: :chunk,
+        chunk_overlap: 16
+      )
+
+    {:ok, chunks} =
+      ChromEx.Collection.get_documents(collection, where: %{"parent_id" => "long"})
+
+    assert length(chunks["ids"]) > 1
+    assert Enum.all?(chunks["ids"], &String.starts_with?(&1, "long#chunk-"))
+    assert Enum.all?(chunks["metadatas"], &(&1["source"] == "manual"))
+    chunk_indexes = Enum.map(chunks["metadatas"], & &1["chunk_index"])
+    assert Enum.sort(chunk_indexes) == Enum.to_list(0..(length(chunks["ids"]) - 1))
+    assert Enum.all?(chunks["documents"], &String.contains?(@long, &1))
+
+    {:ok, results} =
+      ChromEx.Collection.query(collection,
+        query_texts: ["word1 word2 word3"],
+        n_results: 2,
+        include: ["documents"],
+        collapse_chunks: true
+      )
+
+    assert results["ids"] |> hd() |> Enum.sort() == ["long", "short"]
+    assert results["metadatas"] == nil
+  end
+
+  test "upserting a chunked document replaces its chunks", %{collection: collection} do
+    :ok = ChromEx.Collection.add(collection, ids: ["doc"], documents: [@long], truncation: :chunk)
+    :ok =
+      ChromEx.Collection.upsert(collection,
+        ids: ["doc"],
+        documents: ["now short"],
+        truncation: :chunk
+      )
+
+    assert {:ok, %{"ids" => ["doc"]}} = ChromEx.Collection.get_documents(collection)
+  end
+
+  test "a failed upsert keeps the chunks it would replace", %{collection: collection} do
+    :ok = ChromEx.Collection.add(collection, ids: ["doc"], documents: [@long], truncation: :chunk)
+    {:ok, %{"ids" => chunks}} = ChromEx.Collection.get_documents(collection)
+
+    assert {:error, {:validation, _message}} =
+             ChromEx.Collection.upsert(collection,
+               ids: ["doc"],
+               documents: ["now short"],
+               metadatas: [%{"nested" => %{"a" => 1}}],
+               truncation: :chunk
+             )
+
+    assert {:ok, %{"ids" => ids}} = ChromEx.Collection.get_documents(collection)
+    assert Enum.sort(ids) == Enum.sort(chunks)
+  end
+
+  test "cuts multi-byte documents on character boundaries with either backend" do
+    document = Enum.map_join(1..120, " ", &"café#{&1} 東京 🚀")
+
+    for {capacity, [offsets]} <- backend_offsets(document) do
+      split =
+        ChromEx.Chunking.split([ids: ["doc"], documents: [document]], [offsets], capacity, 16)
+
+      assert length(split[:documents]) > 1
+      assert Enum.all?(split[:documents], &String.valid?/1)
+      assert Enum.all?(split[:documents], &String.contains?(document, &1))
+      assert hd(split[:documents]) =~ ~r/^café1 東京 🚀/u
+    end
+  end
+
+  # Token offsets from each backend this build can run, not only the
+  # configured one
+  defp backend_offsets(document) do
+    model = ChromEx.Embeddings.Model.default()
+
+    native =
+      if ChromEx.Native.native_embeddings_available() do
+        :ok = ChromEx.Client.ensure_embedder(ChromEx.Client, model)
+
+        {:ok, offsets} =
+          ChromEx.Native.call(
+            &ChromEx.Native.token_offsets(ChromEx.Client.get_resource(), &1, model, [document])
+          )
+
+        [offsets]
+      else
+        []
+      end
+
+    ortex =
+      if Code.ensure_loaded?(Ortex) do
+        {:ok, spec} = ChromEx.Embeddings.Model.get(model)
+        {:ok, _files} = ChromEx.Embeddings.Model.files(spec)
+        worker = start_supervised!(ChromEx.EmbeddingsWorker)
+        {:ok, offsets} = ChromEx.EmbeddingsWorker.token_offsets(worker, [document], model)
+        [offsets]
+      else
+        []
+      end
+
+    assert native ++ ortex != []
+    native ++ ortex
+  end
+end
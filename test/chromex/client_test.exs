@@ -0,0 +1,151 @@
+defmodule ChromEx.ClientTest do
+  use ExUnit.Case, async: false
+
+  setup do
+    persist_path = Path.join(System.tmp_dir!(), "chromex_client_#{:rand.uniform(100000)}")
+    on_exit(fn -> File.rm_rf(persist_path) end)
+
+    start_supervised!(
+      {ChromEx.Client,
+       name: __MODULE__.Secondary, persist_path: persist_path, hnsw_cache_size: 64}
+    )
+
+    %{client: __MODULE__.Secondary}
+  end
+
+  describe "named clients" do
+    test "keep collections in separate stores", %{client: client} do
+      name = "test_client_#{:rand.uniform(100000)}"
+
+      assert {:ok, collection} = ChromEx.Collection.create(name, client: client)
+      assert collection.client == client
+
+      assert {:error, {:not_found, :collection, ^name}} = ChromEx.Collection.get(name)
+      assert {:ok, %{id: id}} = ChromEx.Collection.get(name, client: client)
+      assert id == collection.id
+    end
+
+    test "route collection operations to the collection's client", %{client: client} do
+      {:ok, collection} = ChromEx.Collection.create("routed", client: client)
+
+      :ok = ChromEx.Collection.add(collection, ids: ["a"], embeddings: [[1.0, 0.0]])
+
+      assert {:ok, 1} = ChromEx.Collection.count(collection)
+      assert {:ok, [%{client: ^client}]} = ChromEx.Collection.list(client: client)
+    end
+
+    test "have their own configuration", %{client: client} do
+      assert ChromEx.Client.hnsw_cache_size(client) == 64
+    end
+
+    test "carry the client on databases", %{client: client} do
+      assert {:ok, database} = ChromEx.Database.create("client_db", client: client)
+      assert database.client == client
+    end
+  end
+
+  describe "HNSW index cache" do
+    setup do
+      persist_path = Path.join(System.tmp_dir!(), "chromex_cache_#{:rand.uniform(100000)}")
+      on_exit(fn -> File.rm_rf(persist_path) end)
+
+      opts = [name: __MODULE__.SingleIndex, persist_path: persist_path, hnsw_cache_size: 1]
+      start_supervised!(Supervisor.child_spec({ChromEx.Client, opts}, id: :single_index))
+
+      %{small: __MODULE__.SingleIndex}
+    end
+
+    test "reloads indexes evicted by the cache size", %{small: client} do
+      collections =
+        for name <- ["evicted_a", "evicted_b", "evicted_c"] do
+          {:ok, collection} = ChromEx.Collection.create(name, client: client)
+
+          :ok =
+            ChromEx.Collection.add(collection,
+              ids: for(i <- 1..200, do: "#{name}_#{i}"),
+              embeddings: for(i <- 1..200, do: [i * 1.0, 1.0])
+            )
+
+          {name, collection}
+        end
+
+      # Each query loads its collection's index, evicting the previous one
+      for _round <- 1..2, {name, collection} <- collections do
+        assert {:ok, %{"ids" => [[id]]}} =
+                 ChromEx.Collection.query(collection, [[50.0, 1.0]], n_results: 1)
+
+        assert id == "#{name}_50"
+      end
+
+      assert ChromEx.Client.hnsw_cache_size(client) == 1
+    end
+
+    test "keeps as many indexes as fit in hnsw_cache_size_mb" do
+      persist_path = Path.join(System.tmp_dir!(), "chromex_cache_mb_#{:rand.uniform(100000)}")
+      on_exit(fn -> File.rm_rf(persist_path) end)
+
+      opts = [name: __MODULE__.Budget, persist_path: persist_path, hnsw_cache_size_mb: 1]
+      start_supervised!(Supervisor.child_spec({ChromEx.Client, opts}, id: :budget))
+      client = __MODULE__.Budget
+
+      # The store is new, so only the count caps the cache
+      assert ChromEx.Client.hnsw_cache_size(client) == 1000
+
+      # 1000 records of dimension 128 take about 0.6 MB each, so only one
+      # of these indexes fits in 1 MB
+      collections =
+        for name <- ["budget_a", "budget_b"] do
+          {:ok, collection} = ChromEx.Collection.create(name, client: client)
+
+          :ok =
+            ChromEx.Collection.add(collection,
+              ids: for(i <- 1..1000, do: "#{name}_#{i}"),
+              embeddings: for(i <- 1..1000, do: [i * 1.0 | List.duplicate(1.0, 127)])
+            )
+
+          {name, collection}
+        end
+
+      :ok = ChromEx.Client.restart_native(client)
+      assert ChromEx.Client.hnsw_cache_size(client) == 1
+
+      query = [50.0 | List.duplicate(1.0, 127)]
+
+      for {name, collection} <- collections do
+        assert {:ok, %{"ids" => [[id]]}} =
+                 ChromEx.Collection.query(collection, [query], n_results: 1)
+
+        assert id == "#{name}_50"
+      end
+    end
+  end
+
+  describe "ensure_embedder/2" do
+    test "answers every caller waiting for the same model", %{client: client} do
+      waiting =
+        for _caller <- 1..3 do
+          Task.async(fn -> ChromEx.Client.ensure_embedder(client, "no-such-model") end)
+        end
+
+      assert is_reference(ChromEx.Client.get_resource(client))
+
+      for result <- Task.await_many(waiting) do
+        assert result == {:error, {:unknown_model, "no-such-model"}}
+      end
+    end
+  end
+
+  describe "restart_native/1" do
+    test "reopens a closed store with its data", %{client: client} do
+      {:ok, collection} = ChromEx.Collection.create("restarted", client: client)
+      :ok = ChromEx.Collection.add(collection, ids: ["a"], embeddings: [[1.0, 0.0]])
+
+      :ok = ChromEx.Native.close(ChromEx.Client.get_resource(client), 5_000)
+      assert {:error, :closed} = ChromEx.Collection.count(collection)
+
+      assert :ok = ChromEx.Client.restart_native(client)
+      assert {:ok, 1} = ChromEx.Collection.count(collection)
+      assert ChromEx.Client.hnsw_cache_size(client) == 64
+    end
+  end
+end
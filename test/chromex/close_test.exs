@@ -0,0 +1,67 @@
+defmodule ChromEx.CloseTest do
+  use ExUnit.Case, async: false
+
+  alias ChromEx.{Client, Collection, Native}
+
+  setup do
+    persist_path = Path.join(System.tmp_dir!(), "chromex_close_#{:rand.uniform(100000)}")
+    on_exit(fn -> File.rm_rf(persist_path) end)
+
+    client_opts = [name: __MODULE__.Store, persist_path: persist_path]
+    start_supervised!({Client, client_opts})
+
+    %{client: __MODULE__.Store, client_opts: client_opts}
+  end
+
+  test "refuses calls on a closed store", %{client: client} do
+    {:ok, collection} = Collection.create("closing", client: client)
+    resource = Client.get_resource(client)
+
+    assert :ok = Native.close(resource, 5_000)
+
+    assert {:error, :closed} = Collection.count(collection)
+    assert {:error, :closed} = Collection.get("closing", client: client)
+    assert {:error, :closed} = Native.get_max_batch_size(resource)
+
+    assert_raise ChromEx.Error, "Failed to count documents: the store is closed", fn ->
+      Collection.count!(collection)
+    end
+  end
+
+  test "closing again does nothing", %{client: client} do
+    resource = Client.get_resource(client)
+
+    assert :ok = Native.close(resource, 5_000)
+    assert :ok = Native.close(resource, 5_000)
+  end
+
+  test "waits for the calls in flight", %{client: client} do
+    {:ok, collection} = Collection.create("in_flight", client: client)
+
+    writer =
+      Task.async(fn ->
+        Collection.add(collection,
+          ids: for(i <- 1..2000, do: "id#{i}"),
+          embeddings: for(i <- 1..2000, do: [i * 1.0, 1.0])
+        )
+      end)
+
+    Process.sleep(10)
+    assert :ok = Native.close(Client.get_resource(client), 30_000)
+    assert Task.await(writer, 30_000) in [:ok, {:error, :closed}]
+  end
+
+  test "stopping the client closes the store and keeps its data",
+       %{client: client, client_opts: client_opts} do
+    {:ok, collection} = Collection.create("persisted", client: client)
+    :ok = Collection.add(collection, ids: ["a", "b"], embeddings: [[1.0, 0.0], [0.0, 1.0]])
+    resource = Client.get_resource(client)
+
+    :ok = stop_supervised(client)
+    assert {:error, :closed} = Native.get_max_batch_size(resource)
+
+    start_supervised!({Client, client_opts})
+    {:ok, reopened} = Collection.get("persisted", client: client)
+    assert {:ok, 2} = Collection.count(reopened)
+  end
+end
@@ -23,6 +23,13 @@ defmodule ChromEx.CollectionTest do
       assert collection.database == "default_database"
     end
 
+    test "returns already_exists without get_or_create", %{collection_name: name} do
+      {:ok, _} = ChromEx.Collection.create(name)
+
+      assert {:error, {:already_exists, :collection, ^name}} =
+               ChromEx.Collection.create(name, get_or_create: false)
+    end
+
     test "creates collection with get_or_create option", %{collection_name: name} do
       assert {:ok, collection1} = ChromEx.Collection.create(name, get_or_create: true)
       assert {:ok, collection2} = ChromEx.Collection.create(name, get_or_create: true)
@@ -43,7 +50,17 @@ defmodule ChromEx.CollectionTest do
     end
 
     test "returns error for non-existent collection" do
-      assert {:error, _} = ChromEx.Collection.get("nonexistent_collection_xyz")
+      assert {:error, {:not_found, :collection, "nonexistent_collection_xyz"}} =
+               ChromEx.Collection.get("nonexistent_collection_xyz")
+    end
+
+    test "get!/1 raises ChromEx.Error for non-existent collection" do
+      error =
+        assert_raise ChromEx.Error, ~r/collection "nonexistent_collection_xyz" not found/, fn ->
+          ChromEx.Collection.get!("nonexistent_collection_xyz")
+        end
+
+      assert ChromEx.Error.type(error) == :not_found
     end
 
     test "get!/1 returns collection directly", %{collection_name: name} do
@@ -53,6 +70,42 @@ defmodule ChromEx.CollectionTest do
     end
   end
 
+  describe "update/2" do
+    test "applies HNSW configuration updates", %{collection_name: name} do
+      {:ok, collection} = ChromEx.Collection.create(name)
+
+      assert {:ok, updated} =
+               ChromEx.Collection.update(collection,
+                 configuration: %{"hnsw" => %{"ef_search" => 200, "num_threads" => 2}}
+               )
+
+      assert get_in(updated.configuration, ["vector_index", "hnsw", "ef_search"]) == 200
+      assert get_in(updated.configuration, ["vector_index", "hnsw", "num_threads"]) == 2
+
+      {:ok, reloaded} = ChromEx.Collection.get(name)
+      assert reloaded.configuration == updated.configuration
+    end
+
+    test "rejects configuration that cannot be changed", %{collection_name: name} do
+      {:ok, collection} = ChromEx.Collection.create(name)
+
+      assert {:error, {:validation, _}} =
+               ChromEx.Collection.update(collection,
+                 configuration: %{"hnsw" => %{"space" => "ip"}}
+               )
+    end
+
+    test "renames and returns the stored collection", %{collection_name: name} do
+      {:ok, collection} = ChromEx.Collection.create(name)
+      new_name = name <> "_renamed"
+      on_exit(fn -> cleanup_collection(new_name) end)
+
+      assert {:ok, %{name: ^new_name, id: id}} =
+               ChromEx.Collection.update(collection, name: new_name)
+      assert id == collection.id
+    end
+  end
+
   describe "list/1" do
     test "lists all collections", %{collection_name: name} do
       {:ok, _} = ChromEx.Collection.create(name)
@@ -99,4 +152,29 @@ defmodule ChromEx.CollectionTest do
       assert 0 = ChromEx.Collection.count!(collection)
     end
   end
+
+  describe "async native calls" do
+    test "serves concurrent callers", %{collection_name: name} do
+      {:ok, collection} = ChromEx.Collection.create(name)
+
+      results =
+        1..20
+        |> Task.async_stream(fn _ -> ChromEx.Collection.count(collection) end)
+        |> Enum.map(fn {:ok, result} -> result end)
+
+      assert Enum.all?(results, &(&1 == {:ok, 0}))
+    end
+
+    test "returns a timeout error when the reply does not arrive in time",
+         %{collection_name: name} do
+      {:ok, collection} = ChromEx.Collection.create(name)
+
+      assert {:error, :timeout} =
+               ChromEx.Collection.add(collection,
+                 ids: Enum.map(1..2_000, &"id#{&1}"),
+                 embeddings: List.duplicate(List.duplicate(0.1, 384), 2_000),
+                 timeout: 0
+               )
+    end
+  end
 end
@@ -60,6 +60,31 @@ defmodule ChromEx.DocumentOperationsTest do
       assert hd(docs["metadatas"])["source"] == "test"
     end
 
+    test "round-trips metadata value types", %{collection: collection} do
+      assert :ok = ChromEx.Collection.add(collection,
+        ids: ["doc1"],
+        documents: ["Test doc"],
+        metadatas: [%{"count" => 3, "score" => 0.5, "active" => true, "source" => :web}]
+      )
+
+      {:ok, docs} = ChromEx.Collection.get_documents(collection, ids: ["doc1"])
+
+      assert hd(docs["metadatas"]) == %{
+               "count" => 3,
+               "score" => 0.5,
+               "active" => true,
+               "source" => "web"
+             }
+    end
+
+    test "rejects unsupported metadata values", %{collection: collection} do
+      assert {:error, {:validation, _}} = ChromEx.Collection.add(collection,
+        ids: ["doc1"],
+        documents: ["Test doc"],
+        metadatas: [%{"nested" => %{"a" => 1}}]
+      )
+    end
+
     test "supports positional IDs syntax", %{collection: collection} do
       assert :ok = ChromEx.Collection.add(collection, ["doc1", "doc2"],
         documents: ["First", "Second"]
@@ -157,4 +182,97 @@ defmodule ChromEx.DocumentOperationsTest do
       assert {:ok, 2} = ChromEx.Collection.count(collection)
     end
   end
+
+  describe "tensor embeddings" do
+    test "adds and reads back embeddings as Nx tensors", %{collection: collection} do
+      tensor = Nx.tensor([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0]], type: :f32)
+
+      assert :ok = ChromEx.Collection.add(collection, ids: ["a", "b"], embeddings: tensor)
+
+      {:ok, docs} =
+        ChromEx.Collection.get_documents(collection,
+          ids: ["a", "b"],
+          include: ["embeddings"],
+          embeddings_as: :tensor
+        )
+
+      assert Nx.shape(docs["embeddings"]) == {2, 3}
+      assert Nx.to_flat_list(docs["embeddings"]) == Nx.to_flat_list(tensor)
+    end
+
+    test "queries with a tensor", %{collection: collection} do
+      :ok =
+        ChromEx.Collection.add(collection,
+          ids: ["a", "b"],
+          embeddings: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]
+        )
+
+      assert {:ok, results} =
+               ChromEx.Collection.query(collection, Nx.tensor([0.0, 1.0, 0.0]),
+                 n_results: 1,
+                 include: ["embeddings"],
+                 embeddings_as: :tensor
+               )
+
+      assert [["b"]] = results["ids"]
+      assert [embeddings] = results["embeddings"]
+      assert Nx.shape(embeddings) == {1, 3}
+    end
+
+    test "reads packed little-endian binaries back as tensors", %{collection: collection} do
+      packed = <<1.0::float-little-32, 2.0::float-little-32, 3.0::float-little-32>>
+
+      assert :ok = ChromEx.Collection.add(collection, ids: ["a"], embeddings: {packed, 1, 3})
+
+      {:ok, docs} =
+        ChromEx.Collection.get_documents(collection,
+          ids: ["a"],
+          include: ["embeddings"],
+          embeddings_as: :tensor
+        )
+
+      assert Nx.to_flat_list(docs["embeddings"]) == [1.0, 2.0, 3.0]
+    end
+
+    test "returns no embeddings when nothing matches", %{collection: collection} do
+      :ok = ChromEx.Collection.add(collection, ids: ["a"], embeddings: [[1.0, 0.0, 0.0]])
+
+      assert {:ok, docs} =
+               ChromEx.Collection.get_documents(collection,
+                 ids: ["missing"],
+                 include: ["embeddings"],
+                 embeddings_as: :tensor
+               )
+
+      assert docs["ids"] == []
+      assert docs["embeddings"] == []
+
+      assert {:ok, results} =
+               ChromEx.Collection.query(collection, Nx.tensor([1.0, 0.0, 0.0]),
+                 n_results: 1,
+                 where: %{"missing" => "value"},
+                 include: ["embeddings"],
+                 embeddings_as: :tensor
+               )
+
+      assert [[]] = results["ids"]
+      assert [[]] = results["embeddings"]
+    end
+
+    test "rejects packed binaries of the wrong size", %{collection: collection} do
+      assert {:error, {:validation, _}} =
+               ChromEx.Collection.add(collection,
+                 ids: ["a"],
+                 embeddings: {<<0, 0, 128, 63>>, 1, 3}
+               )
+    end
+
+    test "rejects packed shapes whose size overflows", %{collection: collection} do
+      # rows * dimension * 4 wraps around to 0 bytes in 64 bits
+      assert {:error, {:validation, message}} =
+               ChromEx.Collection.add(collection, ids: ["a"], embeddings: {<<>>, 2 ** 62, 4})
+
+      assert message =~ "packed embeddings"
+    end
+  end
 end
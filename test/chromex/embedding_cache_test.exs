@@ -0,0 +1,128 @@
+defmodule ChromEx.EmbeddingCacheTest do
+  use ExUnit.Case, async: false
+
+  alias ChromEx.EmbeddingCache
+
+  defmodule CountingEmbeddings do
+    @behaviour ChromEx.EmbeddingFunction
+
+    @impl true
+    def name, do: "counting"
+
+    @impl true
+    def generate(texts, _config) do
+      send(ChromEx.EmbeddingCacheTest, {:embedded, texts})
+      Enum.map(texts, fn text -> [String.length(text) * 1.0, 1.0] end)
+    end
+  end
+
+  defmodule WideEmbeddings do
+    @behaviour ChromEx.EmbeddingFunction
+
+    @impl true
+    def name, do: "wide"
+
+    @impl true
+    def generate(texts, _config), do: Enum.map(texts, fn _text -> List.duplicate(0.5, 1024) end)
+  end
+
+  setup do
+    Process.register(self(), __MODULE__)
+    Application.put_env(:chromex, :embedding_functions, [CountingEmbeddings, WideEmbeddings])
+    persist_path = Path.join(System.tmp_dir!(), "chromex_cache_#{:rand.uniform(100000)}")
+
+    on_exit(fn ->
+      Application.delete_env(:chromex, :embedding_functions)
+      File.rm_rf(persist_path)
+    end)
+
+    client = __MODULE__.Client
+
+    start_supervised!(
+      {ChromEx.Client,
+       name: client, persist_path: persist_path, embedding_cache: [max_entries: 4]}
+    )
+
+    {:ok, collection} =
+      ChromEx.Collection.create("cached", client: client, embedding_function: CountingEmbeddings)
+
+    %{client: client, collection: collection}
+  end
+
+  test "only embeds documents not seen before", %{client: client, collection: collection} do
+    :ok = ChromEx.Collection.add(collection, ids: ["a", "b"], documents: ["one", "three"])
+    assert_received {:embedded, ["one", "three"]}
+
+    :ok =
+      ChromEx.Collection.upsert(collection,
+        ids: ["a", "b", "c"],
+        documents: ["one", "three", "four"]
+      )
+
+    assert_received {:embedded, ["four"]}
+
+    :ok = ChromEx.Collection.upsert(collection, ids: ["a"], documents: ["one"])
+    refute_received {:embedded, _}
+
+    {:ok, %{"embeddings" => embeddings}} =
+      ChromEx.Collection.get_documents(collection, ids: ["b"], include: ["embeddings"])
+
+    assert embeddings == [[5.0, 1.0]]
+
+    assert %{hits: 3, misses: 3, hit_rate: 0.5, size: 3} = EmbeddingCache.stats(client)
+  end
+
+  test "evicts the least recently used entries", %{client: client, collection: collection} do
+    :ok = ChromEx.Collection.add(collection, ids: ["a"], documents: ["oldest"])
+    Process.sleep(5)
+
+    :ok =
+      ChromEx.Collection.add(collection,
+        ids: ["b", "c", "d", "e"],
+        documents: ["b", "c", "d", "e"]
+      )
+
+    assert %{size: 3, evictions: 2} = EmbeddingCache.stats(client)
+
+    :ok = ChromEx.Collection.upsert(collection, ids: ["a"], documents: ["oldest"])
+    assert_received {:embedded, ["oldest"]}
+  end
+
+  test "keeps entries that were hit since they were added",
+       %{client: client, collection: collection} do
+    :ok = ChromEx.Collection.add(collection, ids: ["a"], documents: ["hit"])
+    Process.sleep(5)
+    :ok = ChromEx.Collection.add(collection, ids: ["b", "c"], documents: ["missed", "c"])
+    Process.sleep(5)
+    :ok = ChromEx.Collection.upsert(collection, ids: ["a"], documents: ["hit"])
+    Process.sleep(5)
+
+    # Evicts the two entries not used since "hit" was
+    :ok = ChromEx.Collection.add(collection, ids: ["d", "e"], documents: ["d", "e"])
+    assert %{size: 3, evictions: 2} = EmbeddingCache.stats(client)
+
+    :ok = ChromEx.Collection.upsert(collection, ids: ["a", "b"], documents: ["hit", "missed"])
+    assert_received {:embedded, ["missed"]}
+  end
+
+  test "keeps no more large embeddings than fit in a DETS file" do
+    persist_path = Path.join(System.tmp_dir!(), "chromex_wide_#{:rand.uniform(100000)}")
+    on_exit(fn -> File.rm_rf(persist_path) end)
+
+    opts = [name: __MODULE__.Wide, persist_path: persist_path, embedding_cache: true]
+    start_supervised!(Supervisor.child_spec({ChromEx.Client, opts}, id: :wide))
+
+    {:ok, collection} =
+      ChromEx.Collection.create("wide",
+        client: __MODULE__.Wide,
+        embedding_function: WideEmbeddings
+      )
+
+    assert %{max_entries: 500_000} = EmbeddingCache.stats(__MODULE__.Wide)
+    :ok = ChromEx.Collection.add(collection, ids: ["a"], documents: ["wide"])
+
+    assert %{max_entries: max_entries} = EmbeddingCache.stats(__MODULE__.Wide)
+    assert max_entries < 500_000
+    assert max_entries * 1024 * 4 < 2 * 1024 ** 3
+  end
+end
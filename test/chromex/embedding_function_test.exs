@@ -0,0 +1,97 @@
+defmodule ChromEx.EmbeddingFunctionTest do
+  use ExUnit.Case, async: false
+
+  defmodule LengthEmbeddings do
+    @behaviour ChromEx.EmbeddingFunction
+
+    @impl true
+    def name, do: "length"
+
+    @impl true
+    def default_config, do: %{"scale" => 1.0}
+
+    @impl true
+    def generate(texts, %{"scale" => scale}) do
+      Enum.map(texts, fn text -> [String.length(text) * scale, 1.0] end)
+    end
+  end
+
+  setup do
+    Application.put_env(:chromex, :embedding_functions, [LengthEmbeddings])
+    collection_name = "test_embedding_function_#{:rand.uniform(100000)}"
+
+    on_exit(fn ->
+      Application.delete_env(:chromex, :embedding_functions)
+
+      try do
+        ChromEx.Collection.delete(collection_name)
+      rescue
+        _ -> :ok
+      end
+    end)
+
+    %{collection_name: collection_name}
+  end
+
+  test "persists the embedding function in the collection configuration",
+       %{collection_name: name} do
+    {:ok, collection} = ChromEx.Collection.create(name, embedding_function: LengthEmbeddings)
+
+    assert collection.configuration["embedding_function"] ==
+             %{"type" => "known", "name" => "length", "config" => %{"scale" => 1.0}}
+  end
+
+  test "reopened collections embed with the persisted function", %{collection_name: name} do
+    {:ok, _} =
+      ChromEx.Collection.create(name, embedding_function: {LengthEmbeddings, %{scale: 2.0}})
+    {:ok, collection} = ChromEx.Collection.get(name)
+
+    assert collection.embedding_function == {LengthEmbeddings, %{"scale" => 2.0}}
+
+    :ok =
+      ChromEx.Collection.add(collection, ids: ["short", "long"], documents: ["ab", "abcdefgh"])
+
+    {:ok, %{"embeddings" => embeddings}} =
+      ChromEx.Collection.get_documents(collection, ids: ["short"], include: ["embeddings"])
+
+    assert embeddings == [[4.0, 1.0]]
+
+    {:ok, results} = ChromEx.Collection.query(collection, query_texts: ["abcdefg"], n_results: 1)
+    assert results["ids"] == [["long"]]
+  end
+
+  test "a different function is a mismatch", %{collection_name: name} do
+    {:ok, _} = ChromEx.Collection.create(name, embedding_function: LengthEmbeddings)
+
+    assert {:error, {:embedding_function_mismatch, persisted, given}} =
+             ChromEx.Collection.get(name, embedding_function: ChromEx.EmbeddingFunction.Default)
+
+    assert %{"name" => "length"} = persisted
+    assert %{"name" => "default"} = given
+
+    assert {:error, {:embedding_function_mismatch, _, _}} =
+             ChromEx.Collection.create(name,
+               embedding_function: {LengthEmbeddings, %{"scale" => 3.0}}
+             )
+
+    assert_raise ChromEx.Error, ~r/embedding function/, fn ->
+      ChromEx.Collection.get!(name, embedding_function: ChromEx.EmbeddingFunction.Default)
+    end
+  end
+
+  test "unregistered functions fail when embedding", %{collection_name: name} do
+    {:ok, _} = ChromEx.Collection.create(name, embedding_function: LengthEmbeddings)
+    Application.put_env(:chromex, :embedding_functions, [])
+    {:ok, collection} = ChromEx.Collection.get(name)
+
+    assert {:error, {:unknown_embedding_function, "length"}} =
+             ChromEx.Collection.add(collection, ids: ["a"], documents: ["text"])
+  end
+
+  test "collections default to all-MiniLM-L6-v2", %{collection_name: name} do
+    {:ok, collection} = ChromEx.Collection.create(name)
+
+    assert collection.configuration["embedding_function"]["name"] == "default"
+    assert collection.embedding_function == {ChromEx.EmbeddingFunction.Default, %{}}
+  end
+end
@@ -0,0 +1,122 @@
+defmodule ChromEx.EmbeddingsBatcherTest do
+  use ExUnit.Case, async: false
+
+  alias ChromEx.EmbeddingsBatcher
+
+  setup do
+    # Long enough that concurrent callers always land in the same batch
+    batcher =
+      start_supervised!(
+        {EmbeddingsBatcher, name: :test_batcher, max_batch_size: 8, max_wait: 200}
+      )
+    %{batcher: batcher}
+  end
+
+  test "coalesces concurrent requests and fans results back", %{batcher: batcher} do
+    texts = Enum.map(1..8, &"Document number #{&1}")
+
+    results =
+      texts
+      |> Enum.map(fn text ->
+        Task.async(fn ->
+          EmbeddingsBatcher.generate(batcher, [text], "all-MiniLM-L6-v2", :document)
+        end)
+      end)
+      |> Task.await_many(60_000)
+
+    expected = ChromEx.Embeddings.generate(texts)
+
+    Enum.zip(results, expected)
+    |> Enum.each(fn {{:ok, [embedding]}, expected} ->
+      Enum.zip(embedding, expected) |> Enum.each(fn {a, b} -> assert_in_delta a, b, 1.0e-5 end)
+    end)
+
+    assert %{requests: 8, texts: 8, batches: 1, full_batches: 1, largest_batch: 8} =
+             EmbeddingsBatcher.stats(batcher)
+  end
+
+  test "runs a partial batch after max_wait", %{batcher: batcher} do
+    assert {:ok, [_, _]} =
+             EmbeddingsBatcher.generate(batcher, ["a", "b"], "all-MiniLM-L6-v2", :query)
+
+    assert %{batches: 1, full_batches: 0, mean_batch_size: 2.0, pending: 0} =
+             EmbeddingsBatcher.stats(batcher)
+  end
+
+  test "keeps models and input types in separate batches", %{batcher: batcher} do
+    [document, query, unknown] =
+      [
+        {"all-MiniLM-L6-v2", :document},
+        {"all-MiniLM-L6-v2", :query},
+        {"no-such-model", :document}
+      ]
+      |> Enum.map(fn {model, input} ->
+        Task.async(fn -> EmbeddingsBatcher.generate(batcher, ["text"], model, input) end)
+      end)
+      |> Task.await_many(60_000)
+
+    assert {:ok, [_]} = document
+    assert {:ok, [_]} = query
+    assert {:error, {:unknown_model, "no-such-model"}} = unknown
+    assert EmbeddingsBatcher.stats(batcher).batches == 3
+  end
+
+  test "runs native batches on the client they were requested for", %{batcher: batcher} do
+    request = fn client ->
+      EmbeddingsBatcher.generate(batcher, ["text"], "all-MiniLM-L6-v2", :document, client: client)
+    end
+
+    assert {:ok, [_]} = request.(ChromEx.Client)
+
+    ExUnit.CaptureLog.capture_log(fn ->
+      case ChromEx.Embeddings.backend() do
+        :native -> assert {:error, {:exit, _}} = request.(__MODULE__.NoSuchClient)
+        :ortex -> assert {:ok, [_]} = request.(__MODULE__.NoSuchClient)
+      end
+    end)
+  end
+
+  test "waits for a batch no longer than the caller's timeout", %{batcher: batcher} do
+    # The batch only runs once max_wait has passed
+    assert {:timeout, _} =
+             catch_exit(
+               EmbeddingsBatcher.generate(batcher, ["a"], "all-MiniLM-L6-v2", :query, timeout: 50)
+             )
+  end
+
+  test "holds due batches back while max_concurrency are running" do
+    batcher =
+      start_supervised!(
+        {EmbeddingsBatcher, name: :single_batcher, max_batch_size: 1, max_concurrency: 1},
+        id: :single_batcher
+      )
+
+    # Queue all three calls before the batcher handles any of them
+    :sys.suspend(batcher)
+
+    tasks =
+      for text <- ["one", "two", "three"] do
+        Task.async(fn ->
+          EmbeddingsBatcher.generate(batcher, [text], "all-MiniLM-L6-v2", :query)
+        end)
+      end
+
+    wait_for_messages(batcher, 3)
+    :sys.resume(batcher)
+
+    assert [{:ok, [_]}, {:ok, [_]}, {:ok, [_]}] = Task.await_many(tasks, 60_000)
+
+    # The first call runs alone; the other two wait for it in one batch
+    assert %{batches: 2, largest_batch: 2, running: 0, pending: 0} =
+             EmbeddingsBatcher.stats(batcher)
+  end
+
+  defp wait_for_messages(pid, count) do
+    {:message_queue_len, length} = Process.info(pid, :message_queue_len)
+
+    if length < count do
+      Process.sleep(1)
+      wait_for_messages(pid, count)
+    end
+  end
+end
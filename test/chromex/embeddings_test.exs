@@ -62,5 +62,270 @@ defmodule ChromEx.EmbeddingsTest do
 
       assert emb1 == emb2
     end
+
+    test "padding to the longest text in a batch does not change embeddings" do
+      text = "Short text"
+      [alone] = ChromEx.Embeddings.generate([text])
+      [batched, _] =
+        ChromEx.Embeddings.generate([text, String.duplicate("much longer text ", 40)])
+
+      Enum.zip(alone, batched)
+      |> Enum.each(fn {a, b} -> assert_in_delta a, b, 1.0e-4 end)
+    end
   end
+
+  describe "models" do
+    setup do
+      # The default model's files, downloaded by the first generate/2 call
+      ChromEx.Embeddings.generate(["warm up"])
+      dir = Path.expand("~/.cache/chroma/onnx_models/all-MiniLM-L6-v2")
+
+      Application.put_env(:chromex, :models, %{
+        "minilm-local" => [dir: dir, max_length: 256, dimension: 384, query_prefix: "query: "],
+        "minilm-cls" => [dir: dir, max_length: 256, pooling: :cls],
+        "missing" => [dir: Path.join(System.tmp_dir!(), "chromex_missing_model")]
+      })
+
+      on_exit(fn -> Application.delete_env(:chromex, :models) end)
+    end
+
+    test "loads models from a local directory" do
+      text = "Local models work offline"
+
+      assert ChromEx.Embeddings.generate([text], model: "minilm-local") ==
+               ChromEx.Embeddings.generate([text])
+    end
+
+    test "applies the query prefix to queries only" do
+      text = "reset my password"
+      [document] = ChromEx.Embeddings.generate([text], model: "minilm-local")
+      [query] = ChromEx.Embeddings.generate([text], model: "minilm-local", input: :query)
+      [prefixed] = ChromEx.Embeddings.generate(["query: " <> text], model: "minilm-local")
+
+      refute document == query
+      Enum.zip(query, prefixed) |> Enum.each(fn {a, b} -> assert_in_delta a, b, 1.0e-5 end)
+    end
+
+    test "pools with the CLS token" do
+      [mean] = ChromEx.Embeddings.generate(["Pooling test"])
+      [cls] = ChromEx.Embeddings.generate(["Pooling test"], model: "minilm-cls")
+
+      assert length(cls) == 384
+      refute mean == cls
+    end
+
+    test "reports unknown models and missing files" do
+      assert_raise ChromEx.Error, ~r/unknown_model/, fn ->
+        ChromEx.Embeddings.generate(["text"], model: "nope")
+      end
+
+      assert {:error, {:model_files_missing, "missing", _dir}} =
+               "missing"
+               |> ChromEx.Embeddings.Model.get()
+               |> elem(1)
+               |> ChromEx.Embeddings.Model.files()
+    end
+  end
+
+  describe "model exports" do
+    setup do
+      ChromEx.Embeddings.generate(["warm up"])
+      minilm = Path.expand("~/.cache/chroma/onnx_models/all-MiniLM-L6-v2")
+      dir = Path.join(System.tmp_dir!(), "chromex_exports_#{System.unique_integer([:positive])}")
+
+      for name <- ["cls", "pooled"] do
+        File.mkdir_p!(Path.join(dir, name))
+        tokenizer = Path.join([dir, name, "tokenizer.json"])
+        File.cp!(Path.join(minilm, "onnx/tokenizer.json"), tokenizer)
+      end
+
+      # Neither takes token_type_ids. "cls" returns each token's id and mask
+      # as its hidden state, {batch, sequence, 2}; "pooled" returns the masked
+      # token ids as the embedding, {batch, sequence}.
+      ids_and_mask = [
+        onnx_node("Cast", ["input_ids"], ["ids"], [{"to", 1}]),
+        onnx_node("Cast", ["attention_mask"], ["mask"], [{"to", 1}])
+      ]
+
+      File.write!(
+        Path.join([dir, "cls", "model.onnx"]),
+        onnx_model(
+          ids_and_mask ++
+            [
+              onnx_node("Unsqueeze", ["ids"], ["ids3"], [{"axes", [2]}]),
+              onnx_node("Unsqueeze", ["mask"], ["mask3"], [{"axes", [2]}]),
+              onnx_node("Concat", ["ids3", "mask3"], ["last_hidden_state"], [{"axis", 2}])
+            ],
+          {"last_hidden_state", ["batch", "sequence", 2]}
+        )
+      )
+
+      File.write!(
+        Path.join([dir, "pooled", "model.onnx"]),
+        onnx_model(
+          ids_and_mask ++ [onnx_node("Mul", ["ids", "mask"], ["embeddings"], [])],
+          {"embeddings", ["batch", "sequence"]}
+        )
+      )
+
+      files = [onnx_path: "model.onnx", tokenizer_path: "tokenizer.json", max_length: 16]
+
+      Application.put_env(:chromex, :models, %{
+        "export-cls" => [dir: Path.join(dir, "cls"), pooling: :cls] ++ files,
+        "export-pooled" => [dir: Path.join(dir, "pooled")] ++ files
+      })
+
+      on_exit(fn ->
+        Application.delete_env(:chromex, :models)
+        File.rm_rf!(dir)
+      end)
+    end
+
+    # "hello" is [CLS] hello [SEP], token ids 101, 7592 and 102
+    test "runs models without token_type_ids and pools with the CLS token" do
+      [embedding] = ChromEx.Embeddings.generate(["hello"], model: "export-cls")
+
+      Enum.zip(embedding, l2_normalize([101.0, 1.0]))
+      |> Enum.each(fn {a, b} -> assert_in_delta a, b, 1.0e-6 end)
+    end
+
+    test "uses the output of models that pool themselves as is" do
+      [embedding] = ChromEx.Embeddings.generate(["hello"], model: "export-pooled")
+
+      Enum.zip(embedding, l2_normalize([101.0, 7592.0, 102.0]))
+      |> Enum.each(fn {a, b} -> assert_in_delta a, b, 1.0e-6 end)
+    end
+  end
+
+  describe "downloads" do
+    setup do
+      cache = Path.join(System.tmp_dir!(), "chromex_models_#{System.unique_integer([:positive])}")
+      archive = Path.join([cache, "tiny", "onnx.tar.gz"])
+      File.mkdir_p!(Path.dirname(archive))
+
+      :ok =
+        :erl_tar.create(
+          to_charlist(archive),
+          [{~c"onnx/model.onnx", "model"}, {~c"onnx/tokenizer.json", "{}"}],
+          [:compressed]
+        )
+
+      sha256 = :crypto.hash(:sha256, File.read!(archive)) |> Base.encode16(case: :lower)
+      # Nothing listens here, so any attempt to download fails
+      url = "http://127.0.0.1:1/onnx.tar.gz"
+
+      Application.put_env(:chromex, :model_cache_dir, cache)
+
+      Application.put_env(:chromex, :models, %{
+        "tiny" => [url: url, sha256: sha256],
+        "tiny-bad-checksum" => [url: url, sha256: String.duplicate("0", 64)]
+      })
+
+      on_exit(fn ->
+        Application.delete_env(:chromex, :models)
+        Application.delete_env(:chromex, :model_cache_dir)
+        Application.delete_env(:chromex, :offline)
+        File.rm_rf!(cache)
+      end)
+
+      %{cache: cache}
+    end
+
+    test "extracts a verified archive into the cache", %{cache: cache} do
+      {:ok, model} = ChromEx.Embeddings.Model.get("tiny")
+
+      assert {:ok, %{onnx: onnx}} = ChromEx.Embeddings.Model.files(model)
+      assert onnx == Path.join([cache, "tiny", "onnx", "model.onnx"])
+      assert File.read!(onnx) == "model"
+      assert File.ls!(Path.join(cache, "tiny")) |> Enum.sort() == ["onnx", "onnx.tar.gz"]
+    end
+
+    test "refetches an archive that fails verification", %{cache: cache} do
+      File.mkdir_p!(Path.join(cache, "tiny-bad-checksum"))
+      File.cp!(
+        Path.join([cache, "tiny", "onnx.tar.gz"]),
+        Path.join([cache, "tiny-bad-checksum", "onnx.tar.gz"])
+      )
+      {:ok, model} = ChromEx.Embeddings.Model.get("tiny-bad-checksum")
+
+      assert {:error, {:download_failed, _url, _reason}} = ChromEx.Embeddings.Model.files(model)
+      refute File.exists?(Path.join([cache, "tiny-bad-checksum", "onnx"]))
+    end
+
+    test "fails clearly instead of downloading when offline" do
+      Application.put_env(:chromex, :offline, true)
+      {:ok, model} = ChromEx.Embeddings.Model.get("tiny")
+
+      assert {:error, {:model_offline, "tiny", _dir} = reason} =
+               ChromEx.Embeddings.Model.files(model)
+      assert Exception.message(%ChromEx.Error{reason: reason}) =~ "mix chromex.models.fetch tiny"
+    end
+  end
+
+  describe "backend/0" do
+    test "matches the features the native crate was built with" do
+      assert ChromEx.Native.native_embeddings_available() ==
+               (ChromEx.Embeddings.backend() == :native)
+    end
+  end
+
+  defp l2_normalize(vector) do
+    norm = vector |> Enum.map(&(&1 * &1)) |> Enum.sum() |> :math.sqrt()
+    Enum.map(vector, &(&1 / norm))
+  end
+
+  # A minimal ONNX model, protobuf encoded, over the int64 inputs input_ids
+  # and attention_mask of shape {batch, sequence} with a float output
+  defp onnx_model(nodes, {output, dims}) do
+    inputs = Enum.map(["input_ids", "attention_mask"], &onnx_value(&1, 7, ["batch", "sequence"]))
+
+    graph = [
+      Enum.map(nodes, &proto_field(1, &1)),
+      proto_field(2, "test"),
+      Enum.map(inputs, &proto_field(11, &1)),
+      proto_field(12, onnx_value(output, 1, dims))
+    ]
+
+    # IR version 6, opset 11, where Unsqueeze takes its axes as an attribute
+    [proto_field(1, 6), proto_field(7, graph), proto_field(8, proto_field(2, 11))]
+    |> IO.iodata_to_binary()
+  end
+
+  defp onnx_node(op, inputs, outputs, attributes) do
+    [
+      Enum.map(inputs, &proto_field(1, &1)),
+      Enum.map(outputs, &proto_field(2, &1)),
+      proto_field(4, op),
+      Enum.map(attributes, fn
+        {name, ints} when is_list(ints) ->
+          ints = Enum.map(ints, &proto_field(8, &1))
+          proto_field(5, [proto_field(1, name), ints, proto_field(20, 7)])
+
+        {name, int} ->
+          proto_field(5, [proto_field(1, name), proto_field(3, int), proto_field(20, 2)])
+      end)
+    ]
+  end
+
+  defp onnx_value(name, elem_type, dims) do
+    shape =
+      Enum.map(dims, fn
+        dim when is_integer(dim) -> proto_field(1, proto_field(1, dim))
+        dim -> proto_field(1, proto_field(2, dim))
+      end)
+
+    tensor_type = [proto_field(1, elem_type), proto_field(2, shape)]
+    [proto_field(1, name), proto_field(2, proto_field(1, tensor_type))]
+  end
+
+  defp proto_field(number, value) when is_integer(value),
+    do: [varint(Bitwise.bsl(number, 3)), varint(value)]
+
+  defp proto_field(number, value) do
+    bytes = IO.iodata_to_binary(value)
+    [varint(Bitwise.bsl(number, 3) + 2), varint(byte_size(bytes)), bytes]
+  end
+
+  defp varint(n) when n < 128, do: <<n>>
+  defp varint(n), do: <<1::1, Bitwise.band(n, 127)::7, varint(Bitwise.bsr(n, 7))::binary>>
 end
@@ -0,0 +1,119 @@
+defmodule ChromEx.ExportTest do
+  use ExUnit.Case, async: false
+
+  alias ChromEx.Collection
+
+  setup do
+    suffix = :rand.uniform(100000)
+    name = "test_export_#{suffix}"
+    copy = "test_import_#{suffix}"
+    dir = Path.join(System.tmp_dir!(), "chromex_export_#{suffix}")
+    File.mkdir_p!(dir)
+
+    {:ok, collection} = Collection.create(name, metadata: %{"source" => "export test"})
+
+    :ok =
+      Collection.add(collection,
+        ids: for(i <- 1..30, do: "id#{i}"),
+        embeddings: for(i <- 1..30, do: [i * 1.0, 0.5, -1.0]),
+        documents: for(i <- 1..30, do: "document #{i}"),
+        metadatas: for(i <- 1..30, do: metadata(i))
+      )
+
+    on_exit(fn ->
+      File.rm_rf!(dir)
+
+      for name <- [name, copy] do
+        try do
+          Collection.delete(name)
+        rescue
+          _ -> :ok
+        end
+      end
+    end)
+
+    %{collection: collection, copy: copy, dir: dir}
+  end
+
+  defp metadata(i) when rem(i, 3) == 0, do: %{"n" => i, "tag" => "fizz"}
+  defp metadata(i), do: %{"n" => i}
+
+  defp contents(collection) do
+    {:ok, results} =
+      Collection.get_documents(collection, include: ["documents", "metadatas", "embeddings"])
+
+    [results["ids"], results["documents"], results["metadatas"], results["embeddings"]]
+    |> Enum.zip()
+    |> Enum.sort()
+  end
+
+  test "round-trips a collection through JSONL",
+       %{collection: collection, copy: copy, dir: dir} do
+    path = Path.join(dir, "export.jsonl")
+
+    assert {:ok, 30} = Collection.export(collection, path, page_size: 7)
+    assert {:ok, imported} = Collection.import(copy, path, batch_size: 8)
+
+    assert imported.metadata == %{"source" => "export test"}
+    assert imported.configuration == collection.configuration
+    assert contents(imported) == contents(collection)
+
+    [header | records] = path |> File.read!() |> String.split("\n", trim: true)
+    assert %{"chromex_export" => 1, "name" => name} = Jason.decode!(header)
+    assert name == collection.name
+    assert length(records) == 30
+  end
+
+  test "exports only the records matching a filter", %{collection: collection, dir: dir} do
+    path = Path.join(dir, "fizz.jsonl")
+
+    assert {:ok, 10} = Collection.export(collection, path, where: %{"tag" => "fizz"})
+  end
+
+  test "refuses to import into an existing collection",
+       %{collection: collection, dir: dir} do
+    path = Path.join(dir, "export.jsonl")
+    {:ok, 30} = Collection.export(collection, path)
+
+    assert {:error, {:already_exists, :collection, _name}} =
+             Collection.import(collection.name, path)
+
+    assert {:ok, existing} = Collection.get(collection.name)
+    assert contents(existing) == contents(collection)
+  end
+
+  test "rejects files that are not exports and leaves no collection behind",
+       %{collection: collection, copy: copy, dir: dir} do
+    path = Path.join(dir, "export.jsonl")
+    {:ok, 30} = Collection.export(collection, path)
+
+    bad = Path.join(dir, "bad.jsonl")
+    File.write!(bad, ~s({"id": "x"}\n))
+    assert {:error, {:invalid_export, ^bad, _message}} = Collection.import(copy, bad)
+
+    corrupt = Path.join(dir, "corrupt.jsonl")
+    File.write!(corrupt, File.read!(path) <> "not json\n")
+
+    assert {:error, {:invalid_export, ^corrupt, "line 32 is not a record"}} =
+             Collection.import(copy, corrupt)
+
+    assert {:error, {:not_found, :collection, ^copy}} = Collection.get(copy)
+  end
+
+  test "round-trips through Parquet and Arrow IPC when the arrow feature is built",
+       %{collection: collection, copy: copy, dir: dir} do
+    for file <- ["export.parquet", "export.arrow"] do
+      path = Path.join(dir, file)
+
+      if ChromEx.Native.native_arrow_available() do
+        assert {:ok, 30} = Collection.export(collection, path, page_size: 7)
+        assert {:ok, imported} = Collection.import(copy, path)
+        assert contents(imported) == contents(collection)
+        :ok = Collection.delete(copy)
+      else
+        assert {:error, {:validation, _message}} = Collection.export(collection, path)
+        refute File.exists?(path)
+      end
+    end
+  end
+end
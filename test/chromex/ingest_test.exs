@@ -0,0 +1,117 @@
+defmodule ChromEx.IngestTest do
+  use ExUnit.Case, async: false
+
+  setup do
+    collection_name = "test_ingest_#{:rand.uniform(100000)}"
+    {:ok, collection} = ChromEx.Collection.create(collection_name)
+
+    on_exit(fn ->
+      try do
+        ChromEx.Collection.delete(collection_name)
+      rescue
+        _ -> :ok
+      end
+    end)
+
+    %{collection: collection}
+  end
+
+  defp records(range) do
+    Stream.map(range, fn i ->
+      %{id: "id#{i}", embedding: [i * 1.0, 1.0], metadata: %{"n" => i}}
+    end)
+  end
+
+  test "writes a stream in batches and reports progress", %{collection: collection} do
+    test = self()
+
+    assert {:ok, summary} =
+             ChromEx.Collection.ingest(collection, records(1..250),
+               batch_size: 50,
+               max_concurrency: 3,
+               on_progress: &send(test, {:progress, &1})
+             )
+
+    assert %{written: 250, failed: 0, failures: [], batches: 5, checkpoint: "id250"} = summary
+    assert {:ok, 250} = ChromEx.Collection.count(collection)
+
+    checkpoints =
+      for _ <- 1..5 do
+        receive do
+          {:progress, %{checkpoint: checkpoint}} -> checkpoint
+        end
+      end
+
+    assert checkpoints == ["id50", "id100", "id150", "id200", "id250"]
+  end
+
+  test "resumes after a checkpoint", %{collection: collection} do
+    {:ok, %{written: 40}} =
+      ChromEx.Collection.ingest(collection, records(1..100), batch_size: 20, resume_from: "id60")
+
+    assert {:ok, %{"ids" => []}} = ChromEx.Collection.get_documents(collection, ids: ["id60"])
+    assert {:ok, %{"ids" => ["id61"]}} =
+             ChromEx.Collection.get_documents(collection, ids: ["id61"])
+  end
+
+  test "reports failed batches and keeps going", %{collection: collection} do
+    bad = %{id: "bad", embedding: [1.0, 2.0, 3.0]}
+    records = Stream.concat([records(1..10), [bad], records(11..20)])
+
+    {:ok, summary} =
+      ChromEx.Collection.ingest(collection, records, batch_size: 10, max_concurrency: 1)
+
+    # The batch holding the record of the wrong dimension fails as a whole,
+    # and the checkpoint stops before it
+    assert %{written: 11, failed: 10, batches: 3, checkpoint: "id10"} = summary
+    assert [%{ids: ["bad" | _], reason: _}] = summary.failures
+  end
+
+  test "resuming from the checkpoint retries a failed batch", %{collection: collection} do
+    bad = %{id: "id11", embedding: [1.0, 2.0, 3.0]}
+    records = Stream.concat([records(1..10), [bad], records(12..30)])
+
+    {:ok, %{checkpoint: "id10"} = summary} =
+      ChromEx.Collection.ingest(collection, records,
+        batch_size: 10,
+        max_concurrency: 1,
+        halt_on_error: true
+      )
+
+    assert %{written: 10, failed: 10} = summary
+
+    fixed = Stream.concat([records(1..10), records(11..30)])
+
+    assert {:ok, %{written: 20, failed: 0, checkpoint: "id30"}} =
+             ChromEx.Collection.ingest(collection, fixed, batch_size: 10, resume_from: "id10")
+
+    assert {:ok, 30} = ChromEx.Collection.count(collection)
+  end
+
+  test "rejects a checkpoint that is not in the records", %{collection: collection} do
+    assert {:error, {:validation, message}} =
+             ChromEx.Collection.ingest(collection, records(1..10), resume_from: "id99")
+
+    assert message =~ "id99"
+    assert {:ok, 0} = ChromEx.Collection.count(collection)
+  end
+
+  test "emits telemetry for each batch", %{collection: collection} do
+    test = self()
+    handler = "ingest-test-#{inspect(test)}"
+
+    :telemetry.attach_many(
+      handler,
+      [[:chromex, :ingest, :batch], [:chromex, :ingest, :stop]],
+      fn event, measurements, metadata, _ -> send(test, {event, measurements, metadata}) end,
+      nil
+    )
+
+    on_exit(fn -> :telemetry.detach(handler) end)
+
+    {:ok, _} = ChromEx.Collection.ingest(collection, records(1..4), batch_size: 2)
+
+    assert_receive {[:chromex, :ingest, :batch], %{count: 2}, %{first_id: "id1", result: :ok}}
+    assert_receive {[:chromex, :ingest, :stop], %{duration: _}, %{written: 4}}
+  end
+end
@@ -0,0 +1,28 @@
+defmodule ChromEx.NativeTest do
+  use ExUnit.Case, async: true
+
+  alias ChromEx.Native
+
+  describe "call/2" do
+    test "returns the reply sent to the reply target" do
+      reply = fn {relay, ref} -> send(relay, {ref, {:ok, 1}}) && :ok end
+
+      assert {:ok, 1} = Native.call(reply)
+    end
+
+    test "leaves no late reply in the caller's mailbox" do
+      late = fn {relay, ref} ->
+        Process.send_after(relay, {ref, {:ok, :late}}, 50)
+        :ok
+      end
+
+      assert {:error, :timeout} = Native.call(late, timeout: 10)
+      Process.sleep(100)
+      refute_received {_ref, {:ok, :late}}
+    end
+
+    test "returns errors of the NIF call itself" do
+      assert {:error, :closed} = Native.call(fn _target -> {:error, :closed} end)
+    end
+  end
+end
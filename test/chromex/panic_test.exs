@@ -0,0 +1,58 @@
+defmodule ChromEx.PanicTest do
+  use ExUnit.Case, async: false
+
+  alias ChromEx.{Client, Collection, Native}
+
+  setup do
+    persist_path = Path.join(System.tmp_dir!(), "chromex_panic_#{:rand.uniform(100000)}")
+    on_exit(fn -> File.rm_rf(persist_path) end)
+
+    start_supervised!({Client, name: __MODULE__.Store, persist_path: persist_path})
+    {:ok, collection} = Collection.create("panicking", client: __MODULE__.Store)
+    :ok = Collection.add(collection, ids: ["a"], embeddings: [[1.0, 0.0]])
+
+    %{client: __MODULE__.Store, collection: collection}
+  end
+
+  defp panic(client, at) do
+    resource = Client.get_resource(client)
+    Native.call(&Native.test_panic(resource, &1, at))
+  end
+
+  defp embed(client, model) do
+    resource = Client.get_resource(client)
+    Native.call(&Native.embed(resource, &1, model, ["hello"], :document))
+  end
+
+  test "returns a panic while preparing a call and keeps the store usable",
+       %{client: client, collection: collection} do
+    assert {:error, {:panic, "test panic in the call"}} = panic(client, :call)
+    assert {:ok, 1} = Collection.count(collection)
+  end
+
+  test "replies with a panic in the spawned task and keeps the store usable",
+       %{client: client, collection: collection} do
+    assert {:error, {:panic, "test panic in the task"}} = panic(client, :task)
+    assert {:ok, 1} = Collection.count(collection)
+  end
+
+  test "restart_native/1 recovers an embedding model a panic left unusable",
+       %{client: client, collection: collection} do
+    if Native.native_embeddings_available() do
+      model = ChromEx.Embeddings.Model.default()
+      :ok = Client.ensure_embedder(client, model)
+
+      assert {:error, {:panic, "test panic while holding a lock"}} = panic(client, :lock)
+      assert {:error, {:panic, message}} = embed(client, model)
+      assert message =~ "unusable after an earlier panic"
+
+      assert :ok = Client.restart_native(client)
+      :ok = Client.ensure_embedder(client, model)
+      assert {:ok, [_embedding]} = embed(client, model)
+    else
+      assert {:error, {:validation, _message}} = panic(client, :lock)
+    end
+
+    assert {:ok, 1} = Collection.count(collection)
+  end
+end
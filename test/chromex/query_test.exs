@@ -152,4 +152,57 @@ defmodule ChromEx.QueryTest do
       assert length(hd(results["ids"])) == 3
     end
   end
+
+  describe "where_document filters" do
+    test "query with $contains", %{collection: collection} do
+      assert {:ok, results} = ChromEx.Collection.query(collection,
+        query_texts: ["animals"],
+        where_document: %{"$contains" => "loyal"},
+        n_results: 10
+      )
+
+      assert hd(results["ids"]) == ["doc2"]
+    end
+
+    test "query combines where and where_document", %{collection: collection} do
+      assert {:ok, results} = ChromEx.Collection.query(collection,
+        query_texts: ["animals"],
+        where: %{"year" => 2024},
+        where_document: %{"$not_contains" => "Cats"},
+        n_results: 10
+      )
+
+      assert hd(results["ids"]) == ["doc3"]
+    end
+
+    test "get_documents with $regex", %{collection: collection} do
+      assert {:ok, docs} = ChromEx.Collection.get_documents(collection,
+        where_document: %{"$regex" => "^(Cats|Birds)"}
+      )
+
+      assert Enum.sort(docs["ids"]) == ["doc1", "doc3"]
+    end
+
+    test "get_documents with $not_contains", %{collection: collection} do
+      assert {:ok, docs} = ChromEx.Collection.get_documents(collection,
+        where_document: %{"$not_contains" => "are"}
+      )
+
+      assert docs["ids"] == ["doc3"]
+    end
+
+    test "delete_documents with $contains", %{collection: collection} do
+      assert :ok = ChromEx.Collection.delete_documents(collection,
+        where_document: %{"$contains" => "fly"}
+      )
+
+      assert {:ok, 2} = ChromEx.Collection.count(collection)
+    end
+
+    test "rejects unknown document operators", %{collection: collection} do
+      assert {:error, {:validation, _}} = ChromEx.Collection.get_documents(collection,
+        where_document: %{"$startswith" => "Cats"}
+      )
+    end
+  end
 end
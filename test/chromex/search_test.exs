@@ -0,0 +1,123 @@
+defmodule ChromEx.SearchTest do
+  use ExUnit.Case, async: false
+
+  alias ChromEx.Search
+
+  setup do
+    collection_name = "test_search_#{:rand.uniform(100000)}"
+    {:ok, collection} = ChromEx.Collection.create(collection_name)
+
+    :ok =
+      ChromEx.Collection.add(collection,
+        ids: ["a", "b", "c"],
+        embeddings: [[1.0, 0.0], [0.0, 1.0], [0.7, 0.7]],
+        documents: ["alpha", "beta", "gamma"],
+        metadatas: [%{year: 2024}, %{year: 2023}, %{year: 2024}]
+      )
+
+    on_exit(fn ->
+      try do
+        ChromEx.Collection.delete(collection_name)
+      rescue
+        _ -> :ok
+      end
+    end)
+
+    %{collection: collection}
+  end
+
+  describe "to_payload/1" do
+    test "builds the filter, rank, limit and select sections" do
+      payload =
+        Search.new()
+        |> Search.where(%{"year" => 2024})
+        |> Search.where_document(%{"$contains" => "a"})
+        |> Search.rank(Search.knn([1.0, 0.0], limit: 10))
+        |> Search.limit(5, offset: 2)
+        |> Search.select([:"#document", "#score"])
+        |> Search.to_payload()
+
+      assert payload == %{
+               "filter" => %{
+                 "$and" => [%{"year" => 2024}, %{"#document" => %{"$contains" => "a"}}]
+               },
+               "rank" => %{
+                 "$knn" => %{
+                   "query" => [1.0, 0.0],
+                   "key" => "#embedding",
+                   "limit" => 10,
+                   "return_rank" => false
+                 }
+               },
+               "limit" => %{"offset" => 2, "limit" => 5},
+               "select" => %{"keys" => ["#document", "#score"]}
+             }
+    end
+
+    test "weighted_sum multiplies each expression by its weight" do
+      knn = Search.knn([1.0, 0.0])
+
+      assert Search.weighted_sum([{knn, 0.5}]) ==
+               %{"$sum" => [%{"$mul" => [knn, %{"$val" => 0.5}]}]}
+    end
+  end
+
+  describe "search/3" do
+    test "ranks by KNN and returns selected keys", %{collection: collection} do
+      search =
+        Search.new()
+        |> Search.rank(Search.knn([1.0, 0.0]))
+        |> Search.limit(2)
+        |> Search.select(["#document", "#score"])
+
+      assert {:ok, results} = ChromEx.Collection.search(collection, search)
+      assert [["a", "c"]] = results["ids"]
+      assert [["alpha", "gamma"]] = results["documents"]
+      assert [[score, _]] = results["scores"]
+      assert is_float(score)
+      assert [keys] = results["select"]
+      assert Enum.sort(keys) == ["#document", "#score"]
+    end
+
+    test "returns metadata as native maps", %{collection: collection} do
+      search =
+        Search.new()
+        |> Search.rank(Search.knn([1.0, 0.0]))
+        |> Search.limit(2)
+        |> Search.select(["#metadata"])
+
+      assert {:ok, %{"metadatas" => [[%{"year" => 2024}, %{"year" => 2024}]]}} =
+               ChromEx.Collection.search(collection, search)
+    end
+
+    test "applies filters and composed rank expressions", %{collection: collection} do
+      search =
+        Search.new()
+        |> Search.where(%{"year" => 2024})
+        |> Search.rank(
+          Search.weighted_sum([
+            {Search.knn([0.0, 1.0]), 0.8},
+            {Search.knn([1.0, 0.0]), 0.2}
+          ])
+        )
+        |> Search.limit(10)
+
+      assert {:ok, %{"ids" => [ids]}} = ChromEx.Collection.search(collection, search)
+      assert ids == ["c", "a"]
+    end
+
+    test "runs several searches at once", %{collection: collection} do
+      first = Search.new() |> Search.rank(Search.knn([1.0, 0.0])) |> Search.limit(1)
+      second = Search.new() |> Search.rank(Search.knn([0.0, 1.0])) |> Search.limit(1)
+
+      assert {:ok, %{"ids" => [["a"], ["b"]]}} =
+               ChromEx.Collection.search(collection, [first, second])
+    end
+
+    test "rejects malformed rank expressions", %{collection: collection} do
+      search = Search.new() |> Search.rank(%{"$unknown" => 1})
+
+      assert {:error, {:validation, _}} = ChromEx.Collection.search(collection, search)
+    end
+  end
+end
@@ -0,0 +1,72 @@
+defmodule ChromEx.SparseVectorTest do
+  use ExUnit.Case, async: false
+
+  alias ChromEx.SparseVector
+
+  setup do
+    collection_name = "test_sparse_#{:rand.uniform(100000)}"
+
+    {:ok, collection} =
+      ChromEx.Collection.create(collection_name, sparse_vector_index: "sparse_embedding")
+
+    :ok =
+      ChromEx.Collection.add(collection,
+        ids: ["password", "billing", "shipping"],
+        embeddings: [[1.0, 0.0], [0.0, 1.0], [0.7, 0.7]],
+        documents: ["Reset your password", "Billing overview", "Shipping times"],
+        sparse_embeddings: [
+          SparseVector.from_map(%{1 => 2.0, 7 => 0.5}),
+          SparseVector.from_map(%{3 => 1.5}),
+          SparseVector.from_map(%{5 => 1.0})
+        ]
+      )
+
+    on_exit(fn ->
+      try do
+        ChromEx.Collection.delete(collection_name)
+      rescue
+        _ -> :ok
+      end
+    end)
+
+    %{collection: collection}
+  end
+
+  test "new/2 sorts entries by index" do
+    assert %SparseVector{indices: [1, 5], values: [2.0, 1.0]} = SparseVector.new([5, 1], [1, 2])
+  end
+
+  test "stores sparse vectors in metadata and reads them back", %{collection: collection} do
+    {:ok, docs} =
+      ChromEx.Collection.get_documents(collection, ids: ["billing"], include: ["metadatas"])
+
+    assert [%{"sparse_embedding" => %SparseVector{indices: [3], values: [1.5]}}] =
+             docs["metadatas"]
+  end
+
+  test "hybrid_query fuses dense and sparse rankings", %{collection: collection} do
+    assert {:ok, results} =
+             ChromEx.Collection.hybrid_query(collection,
+               query_embedding: [0.0, 1.0],
+               sparse_query: SparseVector.from_map(%{1 => 1.0}),
+               n_results: 3
+             )
+
+    assert length(results["ids"]) == 3
+    assert Enum.take(results["ids"], 2) |> Enum.sort() == ["billing", "password"]
+    assert results["scores"] == Enum.sort(results["scores"])
+  end
+
+  test "hybrid_query with weighted fusion favours the heavier ranking",
+       %{collection: collection} do
+    assert {:ok, %{"ids" => [first | _]}} =
+             ChromEx.Collection.hybrid_query(collection,
+               query_embedding: [0.0, 1.0],
+               sparse_query: SparseVector.from_map(%{1 => 1.0}),
+               fusion: {:weighted, 1.0, 0.0},
+               n_results: 1
+             )
+
+    assert first == "billing"
+  end
+end
@@ -0,0 +1,111 @@
+defmodule ChromEx.StreamTest do
+  use ExUnit.Case, async: false
+
+  setup do
+    collection_name = "test_stream_#{:rand.uniform(100000)}"
+    {:ok, collection} = ChromEx.Collection.create(collection_name)
+
+    ids = for i <- 1..25, do: "id#{i}"
+
+    :ok =
+      ChromEx.Collection.add(collection,
+        ids: ids,
+        embeddings: for(i <- 1..25, do: [i * 1.0, 1.0]),
+        documents: for(i <- 1..25, do: "document #{i}"),
+        metadatas: for(i <- 1..25, do: %{"n" => i, "even" => rem(i, 2) == 0})
+      )
+
+    on_exit(fn ->
+      try do
+        ChromEx.Collection.delete(collection_name)
+      rescue
+        _ -> :ok
+      end
+    end)
+
+    %{collection: collection, ids: ids}
+  end
+
+  test "pages through every record in id order", %{collection: collection, ids: ids} do
+    records = collection |> ChromEx.Collection.stream(page_size: 4) |> Enum.to_list()
+
+    assert Enum.map(records, & &1.id) == Enum.sort(ids)
+    assert %{id: "id3", document: "document 3", metadata: %{"n" => 3}} = Enum.at(records, 2)
+    refute Map.has_key?(hd(records), :embedding)
+  end
+
+  # Paging reads ids straight from Chroma's SQLite schema (see get_page in
+  # the native crate), so this fails if a Chroma upgrade changes it
+  test "streams records just written, as count/1 and get_documents/2 see them",
+       %{collection: collection} do
+    :ok =
+      ChromEx.Collection.add(collection,
+        ids: for(i <- 26..40, do: "id#{i}"),
+        embeddings: for(i <- 26..40, do: [i * 1.0, 1.0])
+      )
+
+    records = collection |> ChromEx.Collection.stream(page_size: 7) |> Enum.to_list()
+    {:ok, %{"ids" => ids}} = ChromEx.Collection.get_documents(collection, include: [])
+
+    assert length(records) == ChromEx.Collection.count!(collection)
+    assert Enum.map(records, & &1.id) == Enum.sort(ids)
+  end
+
+  test "is lazy", %{collection: collection} do
+    assert ["id1", "id10"] =
+             collection
+             |> ChromEx.Collection.stream(page_size: 2)
+             |> Enum.take(2)
+             |> Enum.map(& &1.id)
+  end
+
+  test "honors where filters and include", %{collection: collection} do
+    records =
+      collection
+      |> ChromEx.Collection.stream(
+        page_size: 3,
+        where: %{"even" => true},
+        include: ["embeddings"]
+      )
+      |> Enum.to_list()
+
+    assert Enum.map(records, & &1.id) == Enum.sort(for(i <- 2..24//2, do: "id#{i}"))
+    assert [%{id: "id10", embedding: [10.0, 1.0]} = record | _] = records
+    refute Map.has_key?(record, :document)
+  end
+
+  test "neither skips nor repeats records deleted while streaming",
+       %{collection: collection, ids: ids} do
+    records =
+      collection
+      |> ChromEx.Collection.stream(page_size: 5)
+      |> Stream.each(fn
+        %{id: "id5"} -> :ok = ChromEx.Collection.delete_documents(collection, ids: ["id1", "id9"])
+        _record -> :ok
+      end)
+      |> Enum.map(& &1.id)
+
+    assert records == Enum.sort(ids) -- ["id9"]
+  end
+
+  test "streams records added after the cursor, not before it",
+       %{collection: collection, ids: ids} do
+    records =
+      collection
+      |> ChromEx.Collection.stream(page_size: 5)
+      |> Stream.each(fn
+        %{id: "id13"} ->
+          :ok =
+            ChromEx.Collection.add(collection,
+              ids: ["id0", "id99"],
+              embeddings: [[0.0, 1.0], [99.0, 1.0]]
+            )
+
+        _record ->
+          :ok
+      end)
+      |> Enum.map(& &1.id)
+
+    assert records == Enum.sort(ids) ++ ["id99"]
+  end
+end
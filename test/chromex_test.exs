@@ -18,5 +18,15 @@ defmodule ChromExTest do
       assert is_integer(size)
       assert size > 0
     end
+
+    test "hnsw_cache_size/0 reports the configured cache size" do
+      expected = Application.get_env(:chromex, :hnsw_cache_size, 1000)
+      assert ChromEx.Client.hnsw_cache_size() == expected
+    end
+
+    test "init rejects a zero cache size" do
+      assert {:error, {:validation, _}} = ChromEx.Native.init(false, nil, 0, 1000)
+      assert {:error, {:validation, _}} = ChromEx.Native.init(false, nil, 1000, 0)
+    end
   end
 end